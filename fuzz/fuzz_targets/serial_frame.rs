@@ -0,0 +1,16 @@
+#![no_main]
+use drgdfu::{decode_command, decode_request, decode_response, decode_status, FRAME_SIZE};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Decode both short input and input padded to a full frame, like read from a port
+    let mut frame = [0; FRAME_SIZE];
+    let len = core::cmp::min(data.len(), FRAME_SIZE);
+    frame[..len].copy_from_slice(&data[..len]);
+    for input in [data, &frame[..]] {
+        let _ = decode_status(input);
+        let _ = decode_command(input);
+        let _ = decode_response(input);
+        let _ = decode_request(input);
+    }
+});
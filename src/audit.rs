@@ -0,0 +1,86 @@
+use serde::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Outcome of an update attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Succeeded,
+    Failed,
+}
+
+/// An update attempt recorded in the audit log.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditRecord {
+    /// Time the attempt finished, in RFC 3339 format.
+    pub timestamp: String,
+    pub device: String,
+    pub operator: String,
+    /// Version the device reported before the update.
+    pub previous_version: Option<String>,
+    /// Version the device was updated to, if known before the transfer.
+    pub target_version: Option<String>,
+    /// Version the device reported last.
+    pub final_version: Option<String>,
+    pub outcome: AuditOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// An append-only log of update attempts, with one JSON object per line.
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    device: String,
+    operator: String,
+}
+
+impl AuditLog {
+    /// Append to the log at the given path, creating it if needed. The operator defaults to
+    /// the user running the process.
+    pub fn new(path: &Path) -> Self {
+        let operator = std::env::var("USER")
+            .or_else(|_| std::env::var("USERNAME"))
+            .unwrap_or_else(|_| "unknown".to_string());
+        Self {
+            path: path.to_path_buf(),
+            device: String::new(),
+            operator,
+        }
+    }
+
+    /// Record the given operator instead of the user running the process.
+    pub fn with_operator(mut self, operator: &str) -> Self {
+        self.operator = operator.to_string();
+        self
+    }
+
+    /// Identify the updated device with the given description, such as its address or port.
+    pub fn with_device(mut self, device: &str) -> Self {
+        self.device = device.to_string();
+        self
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    pub fn operator(&self) -> &str {
+        &self.operator
+    }
+
+    /// Append the record to the log.
+    pub fn append(&self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        // Written at once, so that concurrent updates do not interleave their records
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
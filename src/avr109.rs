@@ -0,0 +1,191 @@
+use crate::Failure;
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_io::asynch::{Read, Write};
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use tokio::time::{timeout, Duration};
+
+/// Block size used if the bootloader does not report one, the flash page size of the
+/// ATmega32U4.
+const DEFAULT_BLOCK_SIZE: usize = 128;
+
+/// An AVR device running a bootloader implementing the AVR109 protocol, such as the Caterina
+/// bootloader of the Arduino Leonardo and Micro.
+///
+/// The bootloader has no notion of firmware versions, so the flash is erased and written
+/// whenever an update is started, and the application is started afterwards. Caterina only
+/// stays in the bootloader for a few seconds, which `touch_1200` can be used for.
+pub struct Avr109Board<T>
+where
+    T: Read + Write,
+{
+    transport: T,
+    block_size: Option<usize>,
+    version: Vec<u8>,
+}
+
+impl<T> Avr109Board<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            block_size: None,
+            version: Vec::new(),
+        }
+    }
+
+    /// Identify the bootloader and read the block size it supports.
+    async fn init(&mut self) -> anyhow::Result<usize> {
+        if let Some(block_size) = self.block_size {
+            return Ok(block_size);
+        }
+        self.send(b"S").await?;
+        let mut id = [0; 7];
+        self.read(&mut id).await?;
+        log::info!("Found bootloader {}", String::from_utf8_lossy(&id));
+
+        self.send(b"b").await?;
+        let mut response = [0; 3];
+        self.read(&mut response).await?;
+        let block_size = match response {
+            [b'Y', high, low] => u16::from_be_bytes([high, low]) as usize,
+            _ => DEFAULT_BLOCK_SIZE,
+        };
+        self.block_size.replace(block_size);
+        Ok(block_size)
+    }
+
+    async fn command(&mut self, command: &[u8], wait: Duration) -> anyhow::Result<()> {
+        self.send(command).await?;
+        let mut response = [0];
+        timeout(wait, self.transport.read_exact(&mut response))
+            .await
+            .map_err(|_| {
+                anyhow!("timeout waiting for bootloader").context(Failure::ConnectTimeout)
+            })?
+            .map_err(|e| anyhow!("error reading from bootloader: {:?}", e))?;
+        if response[0] == b'\r' {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "command {:?} failed with response {:#04x}",
+                command[0] as char,
+                response[0]
+            ))
+        }
+    }
+
+    async fn write_flash(&mut self, offset: u32, data: &[u8]) -> anyhow::Result<()> {
+        let block_size = self.init().await?;
+        // Flash is addressed in words
+        let address = (offset / 2) as u16;
+        let mut command = vec![b'A'];
+        command.extend_from_slice(&address.to_be_bytes());
+        self.command(&command, Duration::from_secs(1)).await?;
+
+        // The address is incremented with each block written
+        for chunk in data.chunks(block_size) {
+            let mut block = chunk.to_vec();
+            block.resize((chunk.len() + 1) / 2 * 2, 0xFF);
+            let mut command = vec![b'B'];
+            command.extend_from_slice(&(block.len() as u16).to_be_bytes());
+            command.push(b'F');
+            command.extend_from_slice(&block);
+            self.command(&command, Duration::from_secs(1)).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.transport
+            .write_all(data)
+            .await
+            .map_err(|e| anyhow!("error writing to bootloader: {:?}", e))?;
+        self.transport
+            .flush()
+            .await
+            .map_err(|e| anyhow!("error writing to bootloader: {:?}", e))
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<()> {
+        timeout(Duration::from_secs(1), self.transport.read_exact(buf))
+            .await
+            .map_err(|_| {
+                anyhow!("timeout waiting for bootloader").context(Failure::ConnectTimeout)
+            })?
+            .map_err(|e| anyhow!("error reading from bootloader: {:?}", e))
+    }
+}
+
+impl<T> FirmwareDevice for Avr109Board<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    const MTU: usize = 1024;
+    type Version = Vec<u8>;
+    type Error = anyhow::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            if self.version.is_empty() {
+                self.init().await?;
+            }
+            Ok(FirmwareStatus {
+                current_version: self.version.clone(),
+                next_offset: 0,
+                next_version: None,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, _: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            self.init().await?;
+            log::info!("Erasing flash");
+            self.command(b"P", Duration::from_secs(1)).await?;
+            self.command(b"e", Duration::from_secs(10)).await
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move { self.write_flash(offset, data).await }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            log::info!("Starting application");
+            self.command(b"L", Duration::from_secs(1)).await?;
+            self.command(b"E", Duration::from_secs(1)).await?;
+            self.version = version.to_vec();
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move { Ok(()) }
+    }
+}
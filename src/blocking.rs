@@ -0,0 +1,54 @@
+//! Synchronous functions updating devices, for applications and test scripts which do not
+//! run an async runtime.
+//!
+//! Each call runs the update on a runtime of its own until the device is in sync with the
+//! source, and must therefore not be made from within an async runtime:
+//!
+//! ```no_run
+//! # fn example() -> anyhow::Result<()> {
+//! use drgdfu::{blocking, FirmwareFileMeta, UpdateSource};
+//!
+//! let metadata = FirmwareFileMeta::from_file(&"firmware.json".into())?;
+//! let data = std::fs::read("firmware.bin")?;
+//! let source = UpdateSource::InMemory { metadata, data };
+//! blocking::update_over_serial("/dev/ttyUSB0".as_ref(), source)?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::{SerialBoard, UpdateOutcome, UpdateRunner, UpdateSource};
+use core::future::Future;
+use std::path::Path;
+
+/// Update the device on the serial port, reopening the port when the device resets.
+pub fn update_over_serial(port: &Path, source: UpdateSource) -> anyhow::Result<UpdateOutcome> {
+    block_on(async move {
+        let device = SerialBoard::new(port)?;
+        UpdateRunner::new(source, device).run().await
+    })
+}
+
+/// Update the BLE GATT device with the address or id, using the first Bluetooth adapter.
+#[cfg(feature = "ble")]
+pub fn update_over_gatt(device: &str, source: UpdateSource) -> anyhow::Result<UpdateOutcome> {
+    use btleplug::api::Manager as _;
+    use btleplug::platform::Manager;
+    block_on(async move {
+        let adapter = Manager::new()
+            .await?
+            .adapters()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("no adapter found"))?;
+        let device = crate::GattBoard::new(device, adapter);
+        UpdateRunner::new(source, device).run().await
+    })
+}
+
+/// Run the future to completion on a new runtime.
+fn block_on<T, F: Future<Output = anyhow::Result<T>>>(future: F) -> anyhow::Result<T> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(future)
+}
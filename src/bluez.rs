@@ -0,0 +1,447 @@
+use crate::gatt::matches_pattern;
+use crate::{DfuError, Failure, GattProtocol, GattTransport, RetryPolicy, FIRMWARE_SERVICE_UUID};
+use bluer::agent::{Agent, AgentHandle, ReqError, RequestConfirmation, RequestPasskey};
+use bluer::gatt::remote::Characteristic;
+use bluer::{Adapter, AdapterEvent, Address, Device, Session};
+use core::future::Future;
+use embedded_update::*;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
+
+/// Time to wait for the services of a device to be resolved without a connect timeout.
+const SERVICES_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A BlueZ adapter used by `BluezBoard`, talking to BlueZ over D-Bus directly instead of
+/// through btleplug.
+#[derive(Clone)]
+pub struct BluezAdapter {
+    session: Session,
+    adapter: Adapter,
+    /// Registered pairing agent, unregistered once all boards are dropped.
+    _agent: Option<Arc<AgentHandle>>,
+}
+
+impl BluezAdapter {
+    /// Open the adapter with the given name, such as `hci0`, or the default adapter.
+    pub async fn open(name: Option<&str>) -> anyhow::Result<Self> {
+        let session = Session::new().await?;
+        let adapter = match name {
+            Some(name) => session.adapter(name)?,
+            None => session.default_adapter().await?,
+        };
+        Ok(Self {
+            session,
+            adapter,
+            _agent: None,
+        })
+    }
+
+    /// Power on the adapter if it is powered off.
+    pub async fn power_on(&self) -> anyhow::Result<()> {
+        if !self.adapter.is_powered().await? {
+            log::info!("Powering on adapter {}", self.adapter.name());
+            self.adapter.set_powered(true).await?;
+        }
+        Ok(())
+    }
+
+    /// Register a pairing agent for the duration of the update, which asks for passkeys on
+    /// the console and accepts pairing requests without a passkey.
+    pub async fn with_pairing_agent(mut self) -> anyhow::Result<Self> {
+        let agent = Agent {
+            request_default: true,
+            request_passkey: Some(Box::new(|req: RequestPasskey| {
+                Box::pin(async move {
+                    let question = format!("Passkey for {}: ", req.device);
+                    let answer = tokio::task::spawn_blocking(move || {
+                        use std::io::Write;
+                        print!("{}", question);
+                        std::io::stdout().flush()?;
+                        let mut line = String::new();
+                        std::io::stdin().read_line(&mut line)?;
+                        Ok::<_, std::io::Error>(line)
+                    })
+                    .await;
+                    match answer {
+                        Ok(Ok(line)) => line.trim().parse().map_err(|_| ReqError::Rejected),
+                        _ => Err(ReqError::Canceled),
+                    }
+                })
+            })),
+            request_confirmation: Some(Box::new(|req: RequestConfirmation| {
+                Box::pin(async move {
+                    log::info!(
+                        "Pairing with {} using passkey {:06}",
+                        req.device,
+                        req.passkey
+                    );
+                    Ok(())
+                })
+            })),
+            ..Default::default()
+        };
+        self._agent
+            .replace(Arc::new(self.session.register_agent(agent).await?));
+        Ok(self)
+    }
+}
+
+/// The device to connect to.
+#[derive(Debug, Clone)]
+enum Target {
+    Address(Address),
+    Name(String),
+}
+
+impl core::fmt::Display for Target {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Address(address) => address.fmt(f),
+            Self::Name(name) => name.fmt(f),
+        }
+    }
+}
+
+/// The connection to the device, giving the firmware service protocol access to its
+/// characteristics. It connects when a characteristic is first accessed.
+struct BluezConnection {
+    adapter: BluezAdapter,
+    target: Target,
+    device: Option<Device>,
+    characteristics: HashMap<uuid::Uuid, Characteristic>,
+    pair: bool,
+    connect_timeout: Option<Duration>,
+    scan_timeout: Option<Duration>,
+    retry: RetryPolicy,
+}
+
+impl BluezConnection {
+    /// Disconnect from the device if connected.
+    async fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.characteristics.clear();
+        if let Some(device) = self.device.take() {
+            device.disconnect().await?;
+        }
+        Ok(())
+    }
+
+    async fn matches(&self, address: Address) -> anyhow::Result<bool> {
+        match &self.target {
+            Target::Address(target) => Ok(address == *target),
+            Target::Name(pattern) => Ok(self
+                .adapter
+                .adapter
+                .device(address)?
+                .name()
+                .await?
+                .map(|name| matches_pattern(pattern.as_bytes(), name.as_bytes()))
+                .unwrap_or(false)),
+        }
+    }
+
+    /// Find the device among the devices known to BlueZ, such as paired ones, or else by
+    /// discovering devices.
+    async fn find(&self) -> anyhow::Result<Device> {
+        for address in self.adapter.adapter.device_addresses().await? {
+            if self.matches(address).await? {
+                return Ok(self.adapter.adapter.device(address)?);
+            }
+        }
+
+        log::info!("Discovering {}", self.target);
+        let discover = async {
+            let mut events = Box::pin(self.adapter.adapter.discover_devices().await?);
+            while let Some(event) = events.next().await {
+                if let AdapterEvent::DeviceAdded(address) = event {
+                    if self.matches(address).await? {
+                        return Ok(self.adapter.adapter.device(address)?);
+                    }
+                }
+            }
+            Err(anyhow::anyhow!("discovery of {} ended", self.target))
+        };
+        match self.scan_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, discover).await.map_err(|_| {
+                anyhow::anyhow!("device {} not found within {:?}", self.target, timeout)
+                    .context(Failure::DeviceNotFound)
+            })?,
+            None => discover.await,
+        }
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        if self.device.is_some() {
+            return Ok(());
+        }
+        let device = self.find().await?;
+        if let Target::Name(_) = self.target {
+            log::info!("Found {} with address {}", self.target, device.address());
+        }
+
+        let connecting = Instant::now();
+        let mut attempts = 0;
+        while !device.is_connected().await? {
+            attempts += 1;
+            log::info!("Connecting...");
+            match self.connect_attempt(&device, connecting).await {
+                Ok(()) => break,
+                Err(err) => {
+                    log::error!("Connect error: {}", &err);
+                    if let Some(timeout) = self.connect_timeout {
+                        if connecting.elapsed() >= timeout {
+                            return Err(err
+                                .context(format!(
+                                    "unable to connect to {} within {:?}",
+                                    self.target, timeout
+                                ))
+                                .context(Failure::ConnectTimeout));
+                        }
+                    }
+                    if !self.retry.retry(attempts) {
+                        return Err(err.context(format!(
+                            "unable to connect to {} after {} attempts",
+                            self.target, attempts
+                        )));
+                    }
+                    self.retry.wait(attempts).await;
+                }
+            }
+        }
+        log::info!("Connected!");
+
+        if self.pair && !device.is_paired().await? {
+            log::info!("Pairing with {}", self.target);
+            device.pair().await?;
+        }
+
+        // Services are resolved once connected, unless BlueZ has them cached
+        let timeout = match self.connect_timeout {
+            Some(timeout) => timeout.saturating_sub(connecting.elapsed()),
+            None => SERVICES_TIMEOUT,
+        };
+        let resolved = async {
+            while !device.is_services_resolved().await? {
+                sleep(Duration::from_millis(100)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        tokio::time::timeout(timeout, resolved)
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!("services of {} not resolved in time", self.target)
+                    .context(Failure::ConnectTimeout)
+            })??;
+        self.characteristics.clear();
+        for service in device.services().await? {
+            if service.uuid().await? == uuid(FIRMWARE_SERVICE_UUID) {
+                for c in service.characteristics().await? {
+                    let id = uuid::Uuid::from_u128(c.uuid().await?.as_u128());
+                    self.characteristics.insert(id, c);
+                }
+            }
+        }
+        if self.characteristics.is_empty() {
+            return Err(anyhow::anyhow!(
+                "device {} has no firmware service",
+                self.target
+            ));
+        }
+        self.device.replace(device);
+        Ok(())
+    }
+
+    async fn connect_attempt(&self, device: &Device, connecting: Instant) -> anyhow::Result<()> {
+        if let Some(timeout) = self.connect_timeout {
+            let remaining = timeout.saturating_sub(connecting.elapsed());
+            tokio::time::timeout(remaining, device.connect())
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("connect timed out after {:?}", timeout)
+                        .context(Failure::ConnectTimeout)
+                })??;
+        } else {
+            device.connect().await?;
+        }
+        Ok(())
+    }
+
+    async fn characteristic(&mut self, id: uuid::Uuid) -> anyhow::Result<Option<&Characteristic>> {
+        self.connect().await?;
+        Ok(self.characteristics.get(&id))
+    }
+}
+
+fn uuid(id: uuid::Uuid) -> bluer::Uuid {
+    bluer::Uuid::from_u128(id.as_u128())
+}
+
+impl GattTransport for BluezConnection {
+    type ReadFuture<'m> = impl Future<Output = anyhow::Result<Option<Vec<u8>>>> + 'm
+    where
+        Self: 'm;
+
+    fn read_characteristic(
+        &mut self,
+        _: uuid::Uuid,
+        characteristic: uuid::Uuid,
+    ) -> Self::ReadFuture<'_> {
+        async move {
+            match self.characteristic(characteristic).await? {
+                Some(c) => Ok(Some(c.read().await?)),
+                None => Ok(None),
+            }
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = anyhow::Result<()>> + 'm
+    where
+        Self: 'm;
+
+    fn write_characteristic<'m>(
+        &'m mut self,
+        _: uuid::Uuid,
+        characteristic: uuid::Uuid,
+        value: &'m [u8],
+    ) -> Self::WriteFuture<'m> {
+        async move {
+            match self.characteristic(characteristic).await? {
+                Some(c) => Ok(c.write(value).await?),
+                None => Err(anyhow::anyhow!("unable to locate characteristic")),
+            }
+        }
+    }
+}
+
+/// A device updated over the BLE GATT firmware service using BlueZ, which supports pairing
+/// and reports connection state changes reliably. The firmware service is driven by
+/// `GattProtocol`, with BlueZ managing the connection.
+pub struct BluezBoard {
+    protocol: GattProtocol<BluezConnection>,
+}
+
+impl BluezBoard {
+    pub fn new(device: &str, adapter: BluezAdapter) -> anyhow::Result<Self> {
+        let address = device
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid device address '{}'", device))?;
+        Ok(Self::with_target(Target::Address(address), adapter))
+    }
+
+    /// Connect to the first device with a name matching the pattern, where `*` matches any
+    /// sequence of characters and `?` matches a single character.
+    pub fn new_by_name(pattern: &str, adapter: BluezAdapter) -> Self {
+        Self::with_target(Target::Name(pattern.to_string()), adapter)
+    }
+
+    fn with_target(target: Target, adapter: BluezAdapter) -> Self {
+        Self {
+            protocol: GattProtocol::new(BluezConnection {
+                adapter,
+                target,
+                device: None,
+                characteristics: HashMap::new(),
+                pair: false,
+                connect_timeout: None,
+                scan_timeout: None,
+                retry: RetryPolicy::fixed(Duration::from_secs(2)),
+            }),
+        }
+    }
+
+    fn connection(&mut self) -> &mut BluezConnection {
+        self.protocol.transport()
+    }
+
+    /// Pair with the device after connecting if it is not paired yet.
+    pub fn with_pairing(mut self) -> Self {
+        self.connection().pair = true;
+        self
+    }
+
+    /// Give up connecting if the device is not connected within the timeout, which bounds
+    /// all connection attempts together rather than each of them.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connection().connect_timeout.replace(timeout);
+        self
+    }
+
+    /// Fail if the device is not found within the timeout.
+    pub fn with_scan_timeout(mut self, timeout: Duration) -> Self {
+        self.connection().scan_timeout.replace(timeout);
+        self
+    }
+
+    /// Fail after the given number of failed connection attempts.
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        let connection = self.connection();
+        connection.retry = connection.retry.with_max_attempts(retries + 1);
+        self
+    }
+
+    /// Write at most `chunk_size` bytes of firmware at a time, instead of the size requested by
+    /// the device. Sizes not dividing the blocks of firmware are rounded down to a power of two.
+    pub fn with_chunk_size(self, chunk_size: u8) -> Self {
+        Self {
+            protocol: self.protocol.with_chunk_size(chunk_size),
+        }
+    }
+
+    /// Disconnect from the device if connected.
+    pub async fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.connection().disconnect().await
+    }
+}
+
+impl FirmwareDevice for BluezBoard {
+    const MTU: usize = 4096;
+    type Version = Vec<u8>;
+    type Error = DfuError;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        self.protocol.status()
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        self.protocol.start(version)
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        self.protocol.write(offset, data)
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], checksum: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            self.protocol.update(version, checksum).await?;
+            // The device resets to swap, the connection is lost
+            sleep(Duration::from_secs(10)).await;
+            let _ = self.disconnect().await;
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        self.protocol.synced()
+    }
+}
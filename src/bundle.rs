@@ -0,0 +1,151 @@
+use crate::mcuboot::parse_public_key;
+use crate::{sha256, ChecksumAlgorithm, Failure, FirmwareFileMeta, SigningKey};
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read, Write};
+use zip::result::ZipError;
+use zip::write::{FileOptions, ZipWriter};
+use zip::ZipArchive;
+
+const METADATA: &str = "metadata.json";
+const FIRMWARE: &str = "firmware.bin";
+const SIGNATURE: &str = "bundle.sig";
+const RELEASE_NOTES: &str = "RELEASE_NOTES.md";
+
+/// A self-contained firmware bundle (`.drgfw`), holding a firmware image together with its
+/// metadata, and optionally a signature and release notes.
+///
+/// The bundle is a zip file with the entries `metadata.json`, `firmware.bin`, `bundle.sig`
+/// and `RELEASE_NOTES.md`. The signature covers both the metadata and the firmware.
+pub struct FirmwareBundle {
+    pub metadata: FirmwareFileMeta,
+    pub firmware: Vec<u8>,
+    pub signature: Option<Vec<u8>>,
+    pub release_notes: Option<String>,
+}
+
+impl FirmwareBundle {
+    /// Create a bundle for the firmware, setting the checksum of the metadata.
+    pub fn new(version: &str, firmware: Vec<u8>) -> Self {
+        Self {
+            metadata: FirmwareFileMeta {
+                version: version.to_string(),
+                size: firmware.len(),
+                checksum: sha256(&firmware),
+                checksum_algorithm: ChecksumAlgorithm::default(),
+                images: Vec::new(),
+                slot_size: None,
+                encryption: None,
+            },
+            firmware,
+            signature: None,
+            release_notes: None,
+        }
+    }
+
+    /// Record the checksum of the firmware with the algorithm instead of SHA-256.
+    pub fn with_checksum_algorithm(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.metadata.checksum = algorithm.hex(&self.firmware);
+        self.metadata.checksum_algorithm = algorithm;
+        self
+    }
+
+    pub fn with_release_notes(mut self, release_notes: String) -> Self {
+        self.release_notes.replace(release_notes);
+        self
+    }
+
+    /// Sign the metadata and the firmware with the key, after which neither may change.
+    pub fn with_signing_key(mut self, key: &SigningKey) -> anyhow::Result<Self> {
+        self.signature.replace(key.sign_data(&self.signed_data()?)?);
+        Ok(self)
+    }
+
+    /// The data covered by the signature: the digest of the compact JSON of the metadata
+    /// followed by the digest of the firmware, so that neither can be replaced on its own.
+    fn signed_data(&self) -> anyhow::Result<Vec<u8>> {
+        let mut data = Sha256::digest(serde_json::to_vec(&self.metadata)?).to_vec();
+        data.extend_from_slice(&Sha256::digest(&self.firmware));
+        Ok(data)
+    }
+
+    /// Read a bundle, verifying the firmware against the checksum in the metadata.
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let mut archive = ZipArchive::new(Cursor::new(data))?;
+        let metadata: FirmwareFileMeta = serde_json::from_slice(
+            &read_entry(&mut archive, METADATA)?
+                .ok_or_else(|| anyhow!("bundle has no {}", METADATA))?,
+        )?;
+        let firmware = read_entry(&mut archive, FIRMWARE)?
+            .ok_or_else(|| anyhow!("bundle has no {}", FIRMWARE))?;
+        let signature = read_entry(&mut archive, SIGNATURE)?;
+        let release_notes = read_entry(&mut archive, RELEASE_NOTES)?
+            .map(|notes| String::from_utf8_lossy(&notes).to_string());
+
+        let checksum = metadata.checksum_algorithm.hex(&firmware);
+        if metadata.size != firmware.len()
+            || (!metadata.checksum.is_empty() && !metadata.checksum.eq_ignore_ascii_case(&checksum))
+        {
+            return Err(anyhow!(
+                "bundle firmware does not match its metadata, expected {} bytes with checksum {} but was {} bytes with checksum {}",
+                metadata.size,
+                metadata.checksum,
+                firmware.len(),
+                checksum
+            )
+            .context(Failure::Verification));
+        }
+
+        Ok(Self {
+            metadata,
+            firmware,
+            signature,
+            release_notes,
+        })
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default();
+        zip.start_file(METADATA, options)?;
+        zip.write_all(serde_json::to_string_pretty(&self.metadata)?.as_bytes())?;
+        zip.start_file(FIRMWARE, options)?;
+        zip.write_all(&self.firmware)?;
+        if let Some(signature) = &self.signature {
+            zip.start_file(SIGNATURE, options)?;
+            zip.write_all(signature)?;
+        }
+        if let Some(release_notes) = &self.release_notes {
+            zip.start_file(RELEASE_NOTES, options)?;
+            zip.write_all(release_notes.as_bytes())?;
+        }
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Verify the signature of the metadata and the firmware with a PEM encoded ECDSA P-256 or
+    /// Ed25519 public key.
+    pub fn verify(&self, public_key: &[u8]) -> anyhow::Result<()> {
+        let signature = self
+            .signature
+            .as_ref()
+            .ok_or_else(|| anyhow!("bundle is not signed"))?;
+        parse_public_key(public_key)?
+            .verify(&self.signed_data()?, signature)
+            .map_err(|_| anyhow!("invalid bundle signature").context(Failure::Verification))
+    }
+}
+
+fn read_entry(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    match archive.by_name(name) {
+        Ok(mut entry) => {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            Ok(Some(data))
+        }
+        Err(ZipError::FileNotFound) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{sha256, ChecksumAlgorithm};
+use embedded_update::Command;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// A cache of firmware fetched from Drogue IoT Cloud, so that updating several devices to the
+/// same version downloads the firmware once.
+///
+/// Blocks are appended to the cache of their version as they are received, and the firmware
+/// is complete once the checksum sent with the swap command matches the cached data.
+/// Incomplete firmware is used as far as it goes, and continued by the next update. Blocks
+/// received again are compared with the cached data, which is discarded if the firmware was
+/// republished with the same version.
+#[derive(Debug, Clone)]
+pub struct FirmwareCache {
+    dir: PathBuf,
+    scope: String,
+}
+
+impl FirmwareCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            scope: String::new(),
+        }
+    }
+
+    /// Keep the cached firmware apart from the firmware of other scopes, such as other
+    /// applications publishing firmware with the same versions.
+    pub fn with_scope(mut self, scope: &str) -> Self {
+        self.scope = scope.to_string();
+        self
+    }
+
+    /// The default cache directory, `$XDG_CACHE_HOME/drgdfu` or `~/.cache/drgdfu`.
+    pub fn default_dir() -> Option<PathBuf> {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .map(|dir| dir.join("drgdfu"))
+    }
+
+    fn path(&self, version: &[u8], extension: &str) -> PathBuf {
+        let mut key = self.scope.as_bytes().to_vec();
+        key.push(0);
+        key.extend_from_slice(version);
+        self.dir.join(format!("{}.{}", sha256(&key), extension))
+    }
+
+    /// Read up to `len` bytes of the version at the offset into `buf`, returning false if they
+    /// are not cached.
+    pub fn read(
+        &self,
+        version: &[u8],
+        offset: u32,
+        len: usize,
+        buf: &mut Vec<u8>,
+    ) -> std::io::Result<bool> {
+        let mut file = match File::open(self.path(version, "bin")) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        lock(&file, false)?;
+        let size = file.metadata()?.len();
+        if offset as u64 >= size {
+            return Ok(false);
+        }
+        buf.resize(core::cmp::min(len as u64, size - offset as u64) as usize, 0);
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut buf[..])?;
+        Ok(true)
+    }
+
+    /// The checksum of the version, if the firmware has been cached completely.
+    pub fn checksum(&self, version: &[u8]) -> std::io::Result<Option<Vec<u8>>> {
+        match std::fs::read(self.path(version, "checksum")) {
+            Ok(checksum) => Ok(Some(checksum)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The size of the version, if the firmware has been cached completely.
+    pub fn size(&self, version: &[u8]) -> std::io::Result<Option<usize>> {
+        if self.checksum(version)?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(
+            std::fs::metadata(self.path(version, "bin"))?.len() as usize
+        ))
+    }
+
+    /// Add the firmware block or checksum of a command received from the cloud to the cache.
+    pub fn store(&self, command: &Command<'_>) -> std::io::Result<()> {
+        match command {
+            Command::Write {
+                version,
+                offset,
+                data,
+                ..
+            } => {
+                let version: &[u8] = version.as_ref();
+                let data: &[u8] = data.as_ref();
+                let offset = *offset as u64;
+                std::fs::create_dir_all(&self.dir)?;
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .read(true)
+                    .append(true)
+                    .open(self.path(version, "bin"))?;
+                // Updates running at the same time append to the same file
+                lock(&file, true)?;
+                let len = file.metadata()?.len();
+                if offset < len {
+                    let mut cached =
+                        vec![0; core::cmp::min(data.len() as u64, len - offset) as usize];
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.read_exact(&mut cached)?;
+                    if cached[..] == data[..cached.len()] {
+                        return Ok(());
+                    }
+                    log::warn!(
+                        "Discarding cached firmware {} not matching the firmware in the cloud",
+                        String::from_utf8_lossy(version)
+                    );
+                    file.set_len(0)?;
+                    match std::fs::remove_file(self.path(version, "checksum")) {
+                        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                        _ => {}
+                    }
+                    if offset == 0 {
+                        file.write_all(data)?;
+                    }
+                } else if offset == len {
+                    // Only extend the cached data, blocks following a gap are skipped
+                    file.write_all(data)?;
+                }
+            }
+            Command::Swap {
+                version, checksum, ..
+            } => {
+                let version: &[u8] = version.as_ref();
+                let checksum: &[u8] = checksum.as_ref();
+                let mut file = match OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open(self.path(version, "bin"))
+                {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                lock(&file, true)?;
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)?;
+                // The cloud does not tell the algorithm of the checksum it sends
+                if ChecksumAlgorithm::ALL
+                    .iter()
+                    .any(|algorithm| algorithm.digest(&data)[..] == *checksum)
+                {
+                    std::fs::write(self.path(version, "checksum"), checksum)?;
+                } else {
+                    log::warn!(
+                        "Discarding cached firmware {} not matching its checksum",
+                        String::from_utf8_lossy(version)
+                    );
+                    file.set_len(0)?;
+                }
+            }
+            Command::Sync { .. } | Command::Wait { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Lock the file until it is closed, waiting for the locks of other processes to be released.
+/// Files are not locked on other platforms than Unix.
+#[cfg(unix)]
+fn lock(file: &File, exclusive: bool) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let operation = if exclusive {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_SH
+    };
+    // SAFETY: the descriptor stays open for the duration of the call
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_: &File, _: bool) -> std::io::Result<()> {
+    Ok(())
+}
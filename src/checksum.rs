@@ -0,0 +1,115 @@
+use core::future::Future;
+use embedded_update::{Command, Status, UpdateService};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+/// Algorithm of firmware checksums, which must match the digest the bootloader of the device
+/// verifies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Sha512,
+    /// CRC-32 as used by zlib and Ethernet, in big endian byte order.
+    Crc32,
+    Blake3,
+}
+
+impl Default for ChecksumAlgorithm {
+    fn default() -> Self {
+        Self::Sha256
+    }
+}
+
+impl ChecksumAlgorithm {
+    pub const ALL: [Self; 4] = [Self::Sha256, Self::Sha512, Self::Crc32, Self::Blake3];
+
+    /// Compute the checksum of the data.
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+            Self::Sha512 => Sha512::digest(data).to_vec(),
+            Self::Crc32 => crc32fast::hash(data).to_be_bytes().to_vec(),
+            Self::Blake3 => blake3::hash(data).as_bytes().to_vec(),
+        }
+    }
+
+    /// Compute the hex encoded checksum of the data, as recorded in firmware metadata.
+    pub fn hex(&self, data: &[u8]) -> String {
+        hex(&self.digest(data))
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Hex encode the data, as checksums are shown.
+pub(crate) fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl core::str::FromStr for ChecksumAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(Self::Sha256),
+            "sha512" => Ok(Self::Sha512),
+            "crc32" => Ok(Self::Crc32),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(anyhow::anyhow!("unknown checksum algorithm '{}'", s)),
+        }
+    }
+}
+
+impl core::fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Sha256 => write!(f, "sha256"),
+            Self::Sha512 => write!(f, "sha512"),
+            Self::Crc32 => write!(f, "crc32"),
+            Self::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+/// An update service swapping the firmware of the wrapped service with a checksum of its own,
+/// for services which always send the SHA-256 digest of the firmware.
+pub struct ChecksumService<S> {
+    service: S,
+    version: Vec<u8>,
+    checksum: Vec<u8>,
+}
+
+impl<S> ChecksumService<S> {
+    /// Swap the version with the checksum computed for the firmware.
+    pub fn new(service: S, version: &[u8], algorithm: ChecksumAlgorithm, firmware: &[u8]) -> Self {
+        Self {
+            service,
+            version: version.to_vec(),
+            checksum: algorithm.digest(firmware),
+        }
+    }
+}
+
+impl<S> UpdateService for ChecksumService<S>
+where
+    S: UpdateService,
+{
+    type Error = S::Error;
+
+    type RequestFuture<'m> = impl Future<Output = Result<Command<'m>, Self::Error>> + 'm where Self: 'm;
+    fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Self::RequestFuture<'m> {
+        async move {
+            match self.service.request(status).await? {
+                Command::Swap { correlation_id, .. } => Ok(Command::new_swap(
+                    &self.version,
+                    &self.checksum,
+                    correlation_id,
+                )),
+                command => Ok(command),
+            }
+        }
+    }
+}
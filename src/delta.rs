@@ -0,0 +1,210 @@
+use crate::ChecksumAlgorithm;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+const DELTA_MAGIC: &[u8; 4] = b"DRGD";
+const DELTA_VERSION: u8 = 1;
+
+const OP_COPY: u8 = 1;
+const OP_INSERT: u8 = 2;
+
+/// Size of the blocks of the base firmware looked up in the target firmware. Matches are
+/// extended beyond the block, so that shorter blocks only find more, shorter matches.
+const BLOCK_SIZE: usize = 32;
+
+/// Metadata of a delta, describing the firmware it applies to and the firmware it produces.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeltaMeta {
+    /// Version of the firmware the delta applies to, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_version: Option<String>,
+    pub base_size: usize,
+    pub base_checksum: String,
+    /// Version of the firmware the delta produces, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub size: usize,
+    pub checksum: String,
+    #[serde(default, skip_serializing_if = "ChecksumAlgorithm::is_default")]
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// Size of the delta itself.
+    pub delta_size: usize,
+}
+
+impl DeltaMeta {
+    /// Describe the delta from the base to the target firmware, with checksums computed with
+    /// the algorithm.
+    pub fn new(base: &[u8], target: &[u8], delta: &Delta, algorithm: ChecksumAlgorithm) -> Self {
+        Self {
+            base_version: None,
+            base_size: base.len(),
+            base_checksum: algorithm.hex(base),
+            version: None,
+            size: target.len(),
+            checksum: algorithm.hex(target),
+            checksum_algorithm: algorithm,
+            delta_size: delta.as_bytes().len(),
+        }
+    }
+}
+
+/// A binary delta between two firmware images, for devices which rebuild the new firmware
+/// from the one they run, so that only the differences are transferred.
+///
+/// The delta starts with the magic `DRGD`, a format version byte and the sizes of the base
+/// and target firmware as 32 bit little endian values. It is followed by operations building
+/// the target firmware in order, each starting with an opcode:
+///
+/// * `1`: copy the number of bytes given by a 32 bit length from the base firmware at a 32
+///   bit offset, as `1 <offset> <length>`
+/// * `2`: insert the bytes following a 32 bit length, as `2 <length> <bytes>`
+pub struct Delta {
+    data: Vec<u8>,
+}
+
+enum Op {
+    Copy { offset: usize, len: usize },
+    Insert { start: usize, end: usize },
+}
+
+impl Delta {
+    /// Compute the delta from the base to the target firmware.
+    pub fn new(base: &[u8], target: &[u8]) -> anyhow::Result<Self> {
+        if u32::try_from(base.len()).is_err() || u32::try_from(target.len()).is_err() {
+            return Err(anyhow!("firmware larger than 4 GiB"));
+        }
+
+        let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+        for (i, block) in base.chunks_exact(BLOCK_SIZE).enumerate() {
+            blocks.entry(block).or_insert(i * BLOCK_SIZE);
+        }
+
+        let mut ops = Vec::new();
+        let mut literal = 0;
+        let mut i = 0;
+        while i + BLOCK_SIZE <= target.len() {
+            let offset = match blocks.get(&target[i..i + BLOCK_SIZE]) {
+                Some(offset) => *offset,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            // Extend the match backwards into the pending literal, and forwards
+            let mut start = i;
+            let mut base_start = offset;
+            while start > literal && base_start > 0 && target[start - 1] == base[base_start - 1] {
+                start -= 1;
+                base_start -= 1;
+            }
+            let mut end = i + BLOCK_SIZE;
+            let mut base_end = offset + BLOCK_SIZE;
+            while end < target.len() && base_end < base.len() && target[end] == base[base_end] {
+                end += 1;
+                base_end += 1;
+            }
+            if start > literal {
+                ops.push(Op::Insert {
+                    start: literal,
+                    end: start,
+                });
+            }
+            ops.push(Op::Copy {
+                offset: base_start,
+                len: end - start,
+            });
+            literal = end;
+            i = end;
+        }
+        if literal < target.len() {
+            ops.push(Op::Insert {
+                start: literal,
+                end: target.len(),
+            });
+        }
+
+        let mut data = DELTA_MAGIC.to_vec();
+        data.push(DELTA_VERSION);
+        data.extend_from_slice(&(base.len() as u32).to_le_bytes());
+        data.extend_from_slice(&(target.len() as u32).to_le_bytes());
+        for op in ops {
+            match op {
+                Op::Copy { offset, len } => {
+                    data.push(OP_COPY);
+                    data.extend_from_slice(&(offset as u32).to_le_bytes());
+                    data.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                Op::Insert { start, end } => {
+                    data.push(OP_INSERT);
+                    data.extend_from_slice(&((end - start) as u32).to_le_bytes());
+                    data.extend_from_slice(&target[start..end]);
+                }
+            }
+        }
+        Ok(Self { data })
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> anyhow::Result<Self> {
+        if data.len() < 13 || &data[..4] != DELTA_MAGIC {
+            return Err(anyhow!("not a firmware delta"));
+        }
+        if data[4] != DELTA_VERSION {
+            return Err(anyhow!("unsupported delta format version {}", data[4]));
+        }
+        Ok(Self { data })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Rebuild the target firmware from the base firmware.
+    pub fn apply(&self, base: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let invalid = || anyhow!("invalid firmware delta");
+        let mut reader = &self.data[5..];
+        let read_u32 = |reader: &mut &[u8]| -> anyhow::Result<usize> {
+            let (value, rest) = reader.split_at(reader.len().min(4));
+            let value: [u8; 4] = value.try_into().map_err(|_| invalid())?;
+            *reader = rest;
+            Ok(u32::from_le_bytes(value) as usize)
+        };
+
+        let base_size = read_u32(&mut reader)?;
+        let size = read_u32(&mut reader)?;
+        if base_size != base.len() {
+            return Err(anyhow!(
+                "delta applies to firmware of {} bytes, but base firmware has {} bytes",
+                base_size,
+                base.len()
+            ));
+        }
+
+        let mut target = Vec::with_capacity(size);
+        while let Some((op, rest)) = reader.split_first() {
+            reader = rest;
+            match *op {
+                OP_COPY => {
+                    let offset = read_u32(&mut reader)?;
+                    let len = read_u32(&mut reader)?;
+                    let data = base.get(offset..offset + len).ok_or_else(invalid)?;
+                    target.extend_from_slice(data);
+                }
+                OP_INSERT => {
+                    let len = read_u32(&mut reader)?;
+                    if reader.len() < len {
+                        return Err(invalid());
+                    }
+                    let (data, rest) = reader.split_at(len);
+                    target.extend_from_slice(data);
+                    reader = rest;
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        if target.len() != size {
+            return Err(invalid());
+        }
+        Ok(target)
+    }
+}
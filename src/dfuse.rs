@@ -0,0 +1,190 @@
+use crate::{FirmwareError, MAX_IMAGE_SIZE};
+
+const PREFIX_SIZE: usize = 11;
+const TARGET_PREFIX_SIZE: usize = 274;
+const ELEMENT_HEADER_SIZE: usize = 8;
+const SUFFIX_SIZE: usize = 16;
+
+/// Returns true if the data looks like a DfuSe file, as created by ST tools for the USB DFU
+/// bootloader of STM32 devices.
+pub fn is_dfuse(data: &[u8]) -> bool {
+    data.len() >= PREFIX_SIZE + SUFFIX_SIZE && data.starts_with(b"DfuSe")
+}
+
+/// Convert a DfuSe file to a binary image starting at its lowest element address.
+///
+/// Only files with a single target are supported, as targets describe different memories of
+/// the device. Gaps between elements are filled with 0xFF, the value of erased flash. Elements
+/// spanning more than `MAX_IMAGE_SIZE` bytes are rejected.
+pub fn dfuse_to_binary(data: &[u8]) -> Result<Vec<u8>, FirmwareError> {
+    if !is_dfuse(data) {
+        return Err(invalid("missing DfuSe prefix"));
+    }
+    let (body, suffix) = data.split_at(data.len() - SUFFIX_SIZE);
+    if &suffix[8..11] != b"UFD" {
+        return Err(invalid("missing DFU suffix"));
+    }
+    let expected = u32::from_le_bytes([suffix[12], suffix[13], suffix[14], suffix[15]]);
+    if dfu_crc(&data[..data.len() - 4]) != expected {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    let size = u32_at(body, 6)? as usize;
+    if size != body.len() {
+        return Err(invalid("image size mismatch"));
+    }
+    match body[10] {
+        1 => {}
+        0 => return Err(invalid("file contains no targets")),
+        n => {
+            return Err(invalid(&format!(
+                "file contains {} targets, only one is supported",
+                n
+            )))
+        }
+    }
+
+    let target = &body[PREFIX_SIZE..];
+    if target.len() < TARGET_PREFIX_SIZE || !target.starts_with(b"Target") {
+        return Err(invalid("missing target prefix"));
+    }
+    let target_size = u32_at(target, 266)? as usize;
+    let elements = u32_at(target, 270)?;
+    let mut pos = TARGET_PREFIX_SIZE;
+    let end = TARGET_PREFIX_SIZE + target_size;
+    if end > target.len() {
+        return Err(invalid("target size exceeds file"));
+    }
+
+    let mut blocks: Vec<(u32, &[u8])> = Vec::new();
+    for _ in 0..elements {
+        let address = u32_at(target, pos)?;
+        let len = u32_at(target, pos + 4)? as usize;
+        pos += ELEMENT_HEADER_SIZE;
+        if pos + len > end {
+            return Err(invalid("element size exceeds target"));
+        }
+        blocks.push((address, &target[pos..pos + len]));
+        pos += len;
+    }
+
+    let start = match blocks.iter().map(|(a, _)| *a).min() {
+        Some(start) => start,
+        None => return Err(invalid("target contains no elements")),
+    };
+    let span = blocks
+        .iter()
+        .map(|(address, data)| (address - start) as usize + data.len())
+        .max()
+        .unwrap_or(0);
+    if span > MAX_IMAGE_SIZE {
+        return Err(invalid(&format!(
+            "elements span {} bytes, more than the maximum image size of {} bytes",
+            span, MAX_IMAGE_SIZE
+        )));
+    }
+    let mut image = Vec::with_capacity(span);
+    for (address, data) in blocks {
+        let offset = (address - start) as usize;
+        if image.len() < offset + data.len() {
+            image.resize(offset + data.len(), 0xFF);
+        }
+        image[offset..offset + data.len()].copy_from_slice(data);
+    }
+    Ok(image)
+}
+
+/// CRC-32 of the DFU suffix, which unlike the usual CRC-32 is not inverted at the end.
+fn dfu_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0xFFFF_FFFF, |crc, b| {
+        (0..8).fold(crc ^ *b as u32, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Result<u32, FirmwareError> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("unexpected end of file"))
+}
+
+fn invalid(reason: &str) -> FirmwareError {
+    FirmwareError::Image(format!("invalid DfuSe file: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a DfuSe file with a single target holding the elements.
+    fn dfuse(elements: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut target = Vec::new();
+        for (address, data) in elements {
+            target.extend_from_slice(&address.to_le_bytes());
+            target.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            target.extend_from_slice(data);
+        }
+
+        let mut file = b"DfuSe\x01".to_vec();
+        let size = PREFIX_SIZE + TARGET_PREFIX_SIZE + target.len();
+        file.extend_from_slice(&(size as u32).to_le_bytes());
+        file.push(1);
+        file.extend_from_slice(b"Target");
+        file.resize(PREFIX_SIZE + 266, 0);
+        file.extend_from_slice(&(target.len() as u32).to_le_bytes());
+        file.extend_from_slice(&(elements.len() as u32).to_le_bytes());
+        file.extend_from_slice(&target);
+        file.extend_from_slice(&[0xFF, 0xFF, 0x11, 0xDF, 0x83, 0x04, 0x1A, 0x01]);
+        file.extend_from_slice(b"UFD\x10");
+        let crc = dfu_crc(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn convert_elements() {
+        let file = dfuse(&[(0x0800_0004, &[3, 4]), (0x0800_0000, &[1, 2])]);
+        assert!(is_dfuse(&file));
+        assert_eq!(
+            dfuse_to_binary(&file).unwrap(),
+            vec![1, 2, 0xFF, 0xFF, 3, 4]
+        );
+    }
+
+    #[test]
+    fn reject_truncated() {
+        let file = dfuse(&[(0x0800_0000, &[1, 2, 3, 4])]);
+        for len in [file.len() - 1, file.len() - SUFFIX_SIZE, PREFIX_SIZE + 20] {
+            assert!(
+                dfuse_to_binary(&file[..len]).is_err(),
+                "accepted {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn reject_crc_mismatch() {
+        let mut file = dfuse(&[(0x0800_0000, &[1, 2, 3, 4])]);
+        let pos = file.len() - SUFFIX_SIZE - 1;
+        file[pos] ^= 0xFF;
+        assert!(matches!(
+            dfuse_to_binary(&file),
+            Err(FirmwareError::Image(e)) if e.contains("checksum mismatch")
+        ));
+    }
+
+    #[test]
+    fn reject_oversized_span() {
+        let file = dfuse(&[(0, &[1]), (0xFFFF_0000, &[2])]);
+        assert!(matches!(
+            dfuse_to_binary(&file),
+            Err(FirmwareError::Image(e)) if e.contains("maximum image size")
+        ));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::Failure;
+use anyhow::anyhow;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_128_GCM, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+/// Cipher of encrypted firmware, decided by the size of the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EncryptionAlgorithm {
+    Aes128Gcm,
+    Aes256Gcm,
+}
+
+impl core::fmt::Display for EncryptionAlgorithm {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Aes128Gcm => write!(f, "aes-128-gcm"),
+            Self::Aes256Gcm => write!(f, "aes-256-gcm"),
+        }
+    }
+}
+
+/// Encryption of firmware which is transferred as ciphertext and decrypted by the device.
+/// The ciphertext ends with the 16 byte authentication tag.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptionMeta {
+    pub algorithm: EncryptionAlgorithm,
+    /// Hex encoded 96 bit nonce the firmware was encrypted with.
+    pub nonce: String,
+    /// Size of the decrypted firmware.
+    pub plaintext_size: usize,
+}
+
+/// An AES key shared with the devices decrypting firmware.
+pub struct EncryptionKey {
+    algorithm: EncryptionAlgorithm,
+    key: Vec<u8>,
+}
+
+impl EncryptionKey {
+    /// Read a 128 or 256 bit key, either as raw bytes or hex encoded.
+    pub fn from_bytes(data: &[u8]) -> anyhow::Result<Self> {
+        let key = match core::str::from_utf8(data)
+            .ok()
+            .and_then(|t| from_hex(t.trim()))
+        {
+            Some(key) if key.len() == 16 || key.len() == 32 => key,
+            _ => data.to_vec(),
+        };
+        let algorithm = match key.len() {
+            16 => EncryptionAlgorithm::Aes128Gcm,
+            32 => EncryptionAlgorithm::Aes256Gcm,
+            len => return Err(anyhow!("unsupported encryption key of {} bytes", len)),
+        };
+        Ok(Self { algorithm, key })
+    }
+
+    fn key(&self) -> anyhow::Result<LessSafeKey> {
+        let algorithm = match self.algorithm {
+            EncryptionAlgorithm::Aes128Gcm => &AES_128_GCM,
+            EncryptionAlgorithm::Aes256Gcm => &AES_256_GCM,
+        };
+        let key = UnboundKey::new(algorithm, &self.key).map_err(|_| anyhow!("invalid key"))?;
+        Ok(LessSafeKey::new(key))
+    }
+
+    /// Encrypt the firmware with a random nonce, returning the ciphertext and the metadata
+    /// the device decrypts it with.
+    pub fn encrypt(&self, firmware: &[u8]) -> anyhow::Result<(EncryptionMeta, Vec<u8>)> {
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow!("error generating nonce"))?;
+        let mut data = firmware.to_vec();
+        self.key()?
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .map_err(|_| anyhow!("error encrypting firmware"))?;
+        let meta = EncryptionMeta {
+            algorithm: self.algorithm,
+            nonce: nonce.iter().map(|b| format!("{:02x}", b)).collect(),
+            plaintext_size: firmware.len(),
+        };
+        Ok((meta, data))
+    }
+
+    /// Decrypt the firmware, failing with a verification error if it was not encrypted with
+    /// this key or has been modified.
+    pub fn decrypt(&self, meta: &EncryptionMeta, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if meta.algorithm != self.algorithm {
+            return Err(anyhow!(
+                "firmware is encrypted with {}, but the key is for {}",
+                meta.algorithm,
+                self.algorithm
+            ));
+        }
+        let nonce: [u8; NONCE_LEN] = from_hex(&meta.nonce)
+            .and_then(|n| n.try_into().ok())
+            .ok_or_else(|| anyhow!("invalid nonce '{}'", meta.nonce))?;
+        let mut data = data.to_vec();
+        let len = self
+            .key()?
+            .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut data)
+            .map_err(|_| anyhow!("error decrypting firmware").context(Failure::Verification))?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
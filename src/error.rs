@@ -0,0 +1,119 @@
+use crate::Failure;
+
+/// Error of updating a device over BLE GATT or a serial port, or of requesting updates from
+/// the cloud, classified so that library consumers can act on the class of the failure, for
+/// instance by retrying after timeouts but not after the device rejected the firmware.
+///
+/// The variants wrap the underlying error, which is shown when the error is displayed.
+#[derive(Debug)]
+pub enum DfuError {
+    /// The device or the cloud was not found, or connecting to it failed.
+    Connect(anyhow::Error),
+    /// The device or the cloud did not answer in time.
+    Timeout(anyhow::Error),
+    /// The device or the cloud sent an unexpected or malformed response, or refused a
+    /// request.
+    Protocol(anyhow::Error),
+    /// The device rejected the firmware, such as when it did not match its checksum.
+    DeviceRejected(anyhow::Error),
+    /// Reading from or writing to the transport failed.
+    Io(std::io::Error),
+    /// The credentials were refused, or refreshing the access token failed.
+    Auth(anyhow::Error),
+}
+
+impl DfuError {
+    /// The class of the failure, as reported in the exit code of the tool.
+    pub fn failure(&self) -> Option<Failure> {
+        match self {
+            Self::Connect(e) | Self::Protocol(e) | Self::Auth(e) => Failure::of(e),
+            Self::Timeout(e) => Failure::of(e).or(Some(Failure::ConnectTimeout)),
+            Self::DeviceRejected(e) => Failure::of(e).or(Some(Failure::Verification)),
+            Self::Io(_) => None,
+        }
+    }
+}
+
+impl core::fmt::Display for DfuError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Connect(e)
+            | Self::Timeout(e)
+            | Self::Protocol(e)
+            | Self::DeviceRejected(e)
+            | Self::Auth(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DfuError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Connect(e)
+            | Self::Timeout(e)
+            | Self::Protocol(e)
+            | Self::DeviceRejected(e)
+            | Self::Auth(e) => e.source(),
+            Self::Io(e) => e.source(),
+        }
+    }
+}
+
+/// Classify an error by the failure added as its context, or by its underlying error.
+impl From<anyhow::Error> for DfuError {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<DfuError>() {
+            Ok(e) => return e,
+            Err(error) => error,
+        };
+        match Failure::of(&error) {
+            Some(Failure::DeviceNotFound) => return Self::Connect(error),
+            Some(Failure::ConnectTimeout) => return Self::Timeout(error),
+            Some(Failure::Verification) => return Self::DeviceRejected(error),
+            _ => {}
+        }
+        match error.downcast::<std::io::Error>() {
+            Ok(e) => Self::Io(e),
+            Err(error) => Self::Protocol(error),
+        }
+    }
+}
+
+impl From<std::io::Error> for DfuError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+#[cfg(feature = "host")]
+impl From<reqwest::Error> for DfuError {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_timeout() {
+            Self::Timeout(error.into())
+        } else if error.is_connect() {
+            Self::Connect(error.into())
+        } else {
+            Self::Protocol(error.into())
+        }
+    }
+}
+
+/// Error returned when the firmware written by a device does not match the firmware sent.
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl core::fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "device reports checksum {} for the written firmware, expected {}",
+            self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
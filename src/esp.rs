@@ -0,0 +1,284 @@
+use crate::Failure;
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_io::asynch::{Read, Write};
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use tokio::time::{timeout, Duration};
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+const CMD_FLASH_BEGIN: u8 = 0x02;
+const CMD_FLASH_DATA: u8 = 0x03;
+const CMD_FLASH_END: u8 = 0x04;
+const CMD_SYNC: u8 = 0x08;
+
+const CHECKSUM_SEED: u8 = 0xEF;
+
+/// Size of the data packets accepted by the ROM loader.
+const FLASH_BLOCK_SIZE: usize = 0x400;
+/// Size of a flash sector, the granularity in which flash is erased.
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+
+/// Flash offset of the application partition in the default partition table of ESP-IDF.
+pub const ESP_APP_ADDRESS: u32 = 0x10000;
+
+/// An ESP32 or ESP8266 running its ROM serial loader, updated using the protocol of esptool.
+///
+/// The loader has no notion of firmware versions, so the firmware is written whenever an
+/// update is started, and the device is rebooted afterwards. The device must already be in
+/// the loader, for instance by resetting it with `EnterBootloader::DtrRts`.
+pub struct EspBoard<T>
+where
+    T: Read + Write,
+{
+    transport: T,
+    address: u32,
+    version: Vec<u8>,
+    synced: bool,
+}
+
+impl<T> EspBoard<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            address: ESP_APP_ADDRESS,
+            version: Vec::new(),
+            synced: false,
+        }
+    }
+
+    /// Write the firmware to the given flash offset instead of the application partition.
+    pub fn with_address(mut self, address: u32) -> Self {
+        self.address = address;
+        self
+    }
+
+    async fn sync(&mut self) -> anyhow::Result<()> {
+        if self.synced {
+            return Ok(());
+        }
+        let mut data = vec![0x07, 0x07, 0x12, 0x20];
+        data.extend_from_slice(&[0x55; 32]);
+        for _ in 0..10 {
+            self.send(CMD_SYNC, &data, 0).await?;
+            if self
+                .response(CMD_SYNC, Duration::from_millis(100))
+                .await
+                .is_ok()
+            {
+                // The loader responds to a sync several times, skip the remaining responses
+                while self.read_frame(Duration::from_millis(100)).await.is_ok() {}
+                self.synced = true;
+                return Ok(());
+            }
+        }
+        Err(anyhow!("unable to sync with the ESP loader"))
+    }
+
+    async fn command(
+        &mut self,
+        command: u8,
+        data: &[u8],
+        checksum: u32,
+        wait: Duration,
+    ) -> anyhow::Result<()> {
+        self.sync().await?;
+        self.send(command, data, checksum).await?;
+        self.response(command, wait).await
+    }
+
+    async fn send(&mut self, command: u8, data: &[u8], checksum: u32) -> anyhow::Result<()> {
+        let mut packet = vec![0x00, command];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(data);
+
+        let mut frame = Vec::with_capacity(packet.len() + 8);
+        frame.push(SLIP_END);
+        for b in packet {
+            match b {
+                SLIP_END => frame.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+                SLIP_ESC => frame.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+                b => frame.push(b),
+            }
+        }
+        frame.push(SLIP_END);
+        self.transport
+            .write_all(&frame)
+            .await
+            .map_err(|e| anyhow!("error writing to loader: {:?}", e))?;
+        self.transport
+            .flush()
+            .await
+            .map_err(|e| anyhow!("error writing to loader: {:?}", e))
+    }
+
+    /// Wait for the response to a command, skipping responses to other commands.
+    async fn response(&mut self, command: u8, wait: Duration) -> anyhow::Result<()> {
+        loop {
+            let frame = self.read_frame(wait).await?;
+            if frame.len() < 10 || frame[0] != 0x01 || frame[1] != command {
+                continue;
+            }
+            // The data ends with the status, which is 2 bytes on the ESP8266 and 4 on the ESP32
+            let data = &frame[8..];
+            return match data {
+                [0, ..] => Ok(()),
+                [_, error, ..] => Err(anyhow!(
+                    "command {:#04x} failed with error {:#04x}",
+                    command,
+                    error
+                )),
+                _ => Err(anyhow!("invalid response to command {:#04x}", command)),
+            };
+        }
+    }
+
+    async fn read_frame(&mut self, wait: Duration) -> anyhow::Result<Vec<u8>> {
+        timeout(wait, async {
+            let mut frame = Vec::new();
+            let mut started = false;
+            let mut escaped = false;
+            loop {
+                let mut b = [0];
+                self.transport
+                    .read_exact(&mut b)
+                    .await
+                    .map_err(|e| anyhow!("error reading from loader: {:?}", e))?;
+                match (b[0], escaped) {
+                    (SLIP_END, _) if !started => started = true,
+                    (SLIP_END, _) if frame.is_empty() => {}
+                    (SLIP_END, _) => return Ok(frame),
+                    _ if !started => {}
+                    (SLIP_ESC, false) => escaped = true,
+                    (SLIP_ESC_END, true) => {
+                        frame.push(SLIP_END);
+                        escaped = false;
+                    }
+                    (SLIP_ESC_ESC, true) => {
+                        frame.push(SLIP_ESC);
+                        escaped = false;
+                    }
+                    (b, _) => {
+                        frame.push(b);
+                        escaped = false;
+                    }
+                }
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("timeout waiting for loader").context(Failure::ConnectTimeout))?
+    }
+
+    async fn flash(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        let blocks = (data.len() + FLASH_BLOCK_SIZE - 1) / FLASH_BLOCK_SIZE;
+        let erase_size =
+            (data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE * FLASH_SECTOR_SIZE;
+
+        let mut begin = Vec::new();
+        begin.extend_from_slice(&(erase_size as u32).to_le_bytes());
+        begin.extend_from_slice(&(blocks as u32).to_le_bytes());
+        begin.extend_from_slice(&(FLASH_BLOCK_SIZE as u32).to_le_bytes());
+        begin.extend_from_slice(&address.to_le_bytes());
+        // Erasing is done when beginning, which takes longer for larger regions
+        self.command(CMD_FLASH_BEGIN, &begin, 0, Duration::from_secs(10))
+            .await?;
+
+        for (seq, chunk) in data.chunks(FLASH_BLOCK_SIZE).enumerate() {
+            let mut block = chunk.to_vec();
+            block.resize(FLASH_BLOCK_SIZE, 0xFF);
+            let checksum = block.iter().fold(CHECKSUM_SEED, |c, b| c ^ b);
+
+            let mut packet = Vec::with_capacity(16 + block.len());
+            packet.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            packet.extend_from_slice(&(seq as u32).to_le_bytes());
+            packet.extend_from_slice(&[0; 8]);
+            packet.extend_from_slice(&block);
+            self.command(
+                CMD_FLASH_DATA,
+                &packet,
+                checksum as u32,
+                Duration::from_secs(3),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> FirmwareDevice for EspBoard<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    // Whole sectors, so that each write erases only the sectors it writes
+    const MTU: usize = FLASH_SECTOR_SIZE;
+    type Version = Vec<u8>;
+    type Error = anyhow::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            self.sync().await?;
+            Ok(FirmwareStatus {
+                current_version: self.version.clone(),
+                next_offset: 0,
+                next_version: None,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, _: &'m [u8]) -> Self::StartFuture<'m> {
+        async move { Ok(()) }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move { self.flash(self.address + offset, data).await }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            log::info!("Rebooting device");
+            // A zero flag reboots the device into the new firmware
+            self.command(
+                CMD_FLASH_END,
+                &0u32.to_le_bytes(),
+                0,
+                Duration::from_secs(3),
+            )
+            .await?;
+            self.version = version.to_vec();
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move { Ok(()) }
+    }
+}
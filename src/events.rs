@@ -0,0 +1,112 @@
+use crate::UpdateHooks;
+use serde::Serialize;
+use std::io::Write;
+
+/// A lifecycle event of an update, as written by `EventWriter`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum UpdateEvent {
+    /// The status of the device has been read, with the version it runs.
+    Connected { version: String },
+    /// The device has started receiving a firmware.
+    Started { version: String },
+    /// A block of firmware has been written to the device.
+    ChunkWritten { offset: u32, len: usize },
+    /// The device has been told to swap to the written firmware.
+    Swapped { version: String },
+    /// The device is in sync with the firmware source.
+    Synced { version: String },
+    /// The update failed.
+    Error { message: String },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    device: &'a str,
+    #[serde(flatten)]
+    event: &'a UpdateEvent,
+}
+
+/// Writes the lifecycle events of updates as newline delimited JSON, with one object per
+/// event, for tools wrapping drgdfu.
+pub struct EventWriter<W: Write> {
+    device: String,
+    out: W,
+}
+
+impl EventWriter<std::io::Stdout> {
+    /// Write the events of updates of the device to stdout.
+    pub fn stdout(device: &str) -> Self {
+        Self::new(device, std::io::stdout())
+    }
+}
+
+impl<W: Write> EventWriter<W> {
+    pub fn new(device: &str, out: W) -> Self {
+        Self {
+            device: device.to_string(),
+            out,
+        }
+    }
+
+    pub fn write(&mut self, event: &UpdateEvent) {
+        let record = Record {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            device: &self.device,
+            event,
+        };
+        match serde_json::to_string(&record) {
+            // Write whole lines, as the events of several devices may share the output
+            Ok(line) => {
+                if let Err(e) = self.out.write_all(format!("{}\n", line).as_bytes()) {
+                    log::warn!("Error writing event: {:?}", e);
+                }
+                let _ = self.out.flush();
+            }
+            Err(e) => log::warn!("Error encoding event: {:?}", e),
+        }
+    }
+}
+
+fn version(version: &[u8]) -> String {
+    String::from_utf8_lossy(version).to_string()
+}
+
+impl<W: Write> UpdateHooks for EventWriter<W> {
+    fn connected(&mut self, v: &[u8]) {
+        self.write(&UpdateEvent::Connected {
+            version: version(v),
+        });
+    }
+
+    fn started(&mut self, v: &[u8]) {
+        self.write(&UpdateEvent::Started {
+            version: version(v),
+        });
+    }
+
+    fn written(&mut self, offset: u32, len: usize) {
+        self.write(&UpdateEvent::ChunkWritten { offset, len });
+    }
+
+    fn swapped(&mut self, v: &[u8]) {
+        self.write(&UpdateEvent::Swapped {
+            version: version(v),
+        });
+    }
+
+    fn synced(&mut self, v: &[u8]) {
+        self.write(&UpdateEvent::Synced {
+            version: version(v),
+        });
+    }
+
+    fn finished(&mut self, result: &anyhow::Result<()>) {
+        if let Err(e) = result {
+            self.write(&UpdateEvent::Error {
+                message: format!("{:#}", e),
+            });
+        }
+    }
+}
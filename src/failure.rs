@@ -0,0 +1,73 @@
+#[cfg(feature = "host")]
+use crate::FirmwareError;
+use crate::{ChecksumMismatch, DfuError};
+
+/// Exit code of the tool when the devices already run the firmware, and none was swapped.
+pub const EXIT_UP_TO_DATE: u8 = 3;
+
+/// Class of a failed operation, reported as the exit code of the tool so that scripts can act
+/// on the failure without parsing the error message.
+///
+/// Errors are classified by adding the class as context, as in
+/// `Err(anyhow!("...").context(Failure::DeviceNotFound))`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The device, or the port or drive it is attached to, was not found.
+    DeviceNotFound,
+    /// Connecting to the device, or waiting for it to answer, timed out.
+    ConnectTimeout,
+    /// Transferring the firmware to the device failed.
+    Transfer,
+    /// The firmware did not match its checksum or signature, or the firmware read back from
+    /// the device.
+    Verification,
+    /// The device did not come back healthy after it was updated.
+    Unhealthy,
+    /// The tool was interrupted, or the update was cancelled.
+    Cancelled,
+}
+
+impl Failure {
+    /// The class of the error, if it has been classified.
+    pub fn of(error: &anyhow::Error) -> Option<Self> {
+        if let Some(failure) = error.downcast_ref::<Failure>() {
+            return Some(*failure);
+        }
+        if let Some(e) = error.downcast_ref::<DfuError>() {
+            return e.failure();
+        }
+        if error.is::<ChecksumMismatch>() {
+            return Some(Self::Verification);
+        }
+        #[cfg(feature = "host")]
+        if let Some(FirmwareError::Checksum { .. }) = error.downcast_ref::<FirmwareError>() {
+            return Some(Self::Verification);
+        }
+        None
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::DeviceNotFound => 4,
+            Self::ConnectTimeout => 5,
+            Self::Transfer => 6,
+            Self::Verification => 7,
+            Self::Unhealthy => 8,
+            // As for processes killed by SIGINT
+            Self::Cancelled => 130,
+        }
+    }
+}
+
+impl core::fmt::Display for Failure {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::DeviceNotFound => write!(f, "device not found"),
+            Self::ConnectTimeout => write!(f, "timed out connecting to device"),
+            Self::Transfer => write!(f, "firmware transfer failed"),
+            Self::Verification => write!(f, "firmware verification failed"),
+            Self::Unhealthy => write!(f, "device unhealthy after update"),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
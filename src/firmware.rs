@@ -1,41 +1,507 @@
+use crate::mcuboot::parse_public_key;
+use crate::{
+    dfuse_to_binary, is_dfuse, is_srec, srec_to_binary, AccessToken, AuditOutcome,
+    ChecksumAlgorithm, DeviceLogin, DfuError, EncryptionMeta, Failure, FirmwareCache, SigningKey,
+};
 use anyhow::anyhow;
 use core::future::Future;
 use embedded_update::*;
 use serde::{Deserialize, Serialize};
-use std::fs::File;
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FirmwareFileMeta {
     pub version: String,
     pub size: usize,
     pub checksum: String,
+    /// Algorithm of the checksums of the firmware and its images, SHA-256 unless recorded.
+    #[serde(default, skip_serializing_if = "ChecksumAlgorithm::is_default")]
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// Images contained in the firmware for targets updating several images together, such
+    /// as the application and network cores of the nRF5340.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub images: Vec<ImageMeta>,
+    /// Size of the firmware slot of the target device, used to reject firmware which does not
+    /// fit before transferring it, unless the device reports its slot size itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot_size: Option<usize>,
+    /// Encryption of firmware transferred as ciphertext, for devices decrypting it on-chip.
+    /// The size and checksum are of the ciphertext.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionMeta>,
 }
 
+/// An image within a multi-image firmware, which is transferred as a separate update.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImageMeta {
+    pub name: String,
+    pub version: String,
+    /// Offset of the image within the firmware.
+    pub offset: usize,
+    pub size: usize,
+    pub checksum: String,
+}
+
+#[derive(Clone)]
 pub struct DrogueFirmwareService {
     pub url: String,
-    pub user: String,
-    pub password: String,
+    pub credentials: Credentials,
     pub timeout: std::time::Duration,
     pub client: reqwest::Client,
+    pub encoding: PayloadEncoding,
     pub last_response: Vec<u8>,
+    /// Device to act on behalf of, when authenticated as a gateway.
+    device: Option<String>,
+    last_command: Option<JsonCommand>,
+    cache: Option<FirmwareCache>,
+    /// Firmware block read from the cache for the last command.
+    cached: Vec<u8>,
+}
+
+/// Block size used for cached firmware if the device does not report its MTU.
+const DEFAULT_BLOCK_SIZE: usize = 128;
+
+/// Encoding of the status and command payloads exchanged with Drogue IoT Cloud.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadEncoding {
+    #[default]
+    Cbor,
+    Json,
+}
+
+impl PayloadEncoding {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Cbor => "application/cbor",
+            Self::Json => "application/json",
+        }
+    }
+
+    /// The encoding of a payload with the given content type, if supported.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        // Ignore parameters such as the charset
+        match content_type.split(';').next().unwrap_or_default().trim() {
+            "application/cbor" => Some(Self::Cbor),
+            "application/json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+impl core::str::FromStr for PayloadEncoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cbor" => Ok(Self::Cbor),
+            "json" => Ok(Self::Json),
+            _ => Err(anyhow!("unknown payload encoding '{}'", s)),
+        }
+    }
+}
+
+/// A command decoded from JSON, which encodes bytes as arrays and can therefore not be
+/// borrowed from the payload like with CBOR.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) enum JsonCommand {
+    Wait {
+        correlation_id: Option<u32>,
+        poll: Option<u32>,
+    },
+    Sync {
+        version: Vec<u8>,
+        poll: Option<u32>,
+        correlation_id: Option<u32>,
+    },
+    Write {
+        version: Vec<u8>,
+        offset: u32,
+        data: Vec<u8>,
+        correlation_id: Option<u32>,
+    },
+    Swap {
+        version: Vec<u8>,
+        checksum: Vec<u8>,
+        correlation_id: Option<u32>,
+    },
+}
+
+impl JsonCommand {
+    fn as_command(&self) -> Command<'_> {
+        match self {
+            Self::Wait {
+                correlation_id,
+                poll,
+            } => Command::new_wait(*poll, *correlation_id),
+            Self::Sync {
+                version,
+                poll,
+                correlation_id,
+            } => Command::new_sync(version, *poll, *correlation_id),
+            Self::Write {
+                version,
+                offset,
+                data,
+                correlation_id,
+            } => Command::new_write(version, *offset, data, *correlation_id),
+            Self::Swap {
+                version,
+                checksum,
+                correlation_id,
+            } => Command::new_swap(version, checksum, *correlation_id),
+        }
+    }
+}
+
+/// Channel the outcome of updates is published to as telemetry of the device.
+pub const REPORT_CHANNEL: &str = "dfu-report";
+
+/// Outcome of an update, published to Drogue IoT Cloud by `DrogueFirmwareService::report`.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpdateReport {
+    pub outcome: AuditOutcome,
+    /// Version the device reported last.
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: u64,
+}
+
+/// Channel the progress of updates is published to as telemetry of the device.
+pub const PROGRESS_CHANNEL: &str = "dfu-progress";
+
+/// Phase of an update in progress.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdatePhase {
+    /// The device has started receiving the firmware.
+    Started,
+    /// Firmware is being written to the device.
+    Writing,
+    /// The device has been told to swap to the written firmware.
+    Swapped,
+    /// The device is in sync with the cloud.
+    Synced,
+}
+
+/// Progress of an update, published to Drogue IoT Cloud by
+/// `DrogueFirmwareService::report_progress` while the update runs.
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressReport {
+    pub phase: UpdatePhase,
+    /// Version being written to the device.
+    pub version: Option<String>,
+    /// Number of bytes written to the device.
+    pub offset: u32,
+    /// Size of the firmware, only known for firmware in the cache.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<u8>,
+}
+
+/// Credentials used to authenticate with Drogue IoT Cloud.
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    /// Device credentials, with the user given as `device@application`.
+    Password { user: String, password: String },
+    /// X.509 device credentials, using the client certificate of the HTTP client.
+    Certificate,
+    /// Access token of a user, acting on behalf of a device. The token is refreshed before
+    /// it expires if the login it was obtained with is given.
+    Token {
+        token: AccessToken,
+        application: String,
+        device: String,
+        login: Option<DeviceLogin>,
+    },
 }
 
 impl DrogueFirmwareService {
     pub fn new(url: &str, user: &str, password: &str, timeout: std::time::Duration) -> Self {
+        Self::with_credentials(
+            url,
+            Credentials::Password {
+                user: user.to_string(),
+                password: password.to_string(),
+            },
+            timeout,
+        )
+    }
+
+    pub fn with_credentials(
+        url: &str,
+        credentials: Credentials,
+        timeout: std::time::Duration,
+    ) -> Self {
         Self {
             url: url.to_string(),
-            user: user.to_string(),
-            password: password.to_string(),
+            credentials,
             timeout,
             client: reqwest::Client::new(),
+            encoding: PayloadEncoding::default(),
             last_response: Vec::new(),
+            device: None,
+            last_command: None,
+            cache: None,
+            cached: Vec::new(),
+        }
+    }
+
+    /// Use the given encoding for status payloads, and to request commands in.
+    pub fn with_encoding(mut self, encoding: PayloadEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Cache the firmware fetched from the cloud, and serve the blocks already cached from it
+    /// instead of fetching them again.
+    pub fn with_cache(mut self, cache: FirmwareCache) -> Self {
+        self.cache = Some(cache.with_scope(&self.cache_scope()));
+        self
+    }
+
+    /// The scope of the cached firmware, as applications may publish different firmware with
+    /// the same version. The application is not known with certificates, whose cached firmware
+    /// is only kept apart by the cloud.
+    fn cache_scope(&self) -> String {
+        let application = match &self.credentials {
+            Credentials::Password { user, .. } => user.split_once('@').map(|(_, a)| a),
+            Credentials::Token { application, .. } => Some(application.as_str()),
+            Credentials::Certificate => None,
+        };
+        format!("{} {}", self.url, application.unwrap_or_default())
+    }
+
+    /// Act on behalf of the device, when authenticated as a gateway the device may be reached
+    /// through.
+    pub fn with_device(mut self, device: &str) -> Self {
+        self.device = Some(device.to_string());
+        self
+    }
+
+    /// Use the given HTTP client, for instance to configure client certificates.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Publish the outcome of an update as telemetry of the device on the report channel, so
+    /// that the rollout state is visible in the cloud before the device reports itself.
+    pub async fn report(&mut self, report: &UpdateReport) -> Result<(), DfuError> {
+        self.publish(REPORT_CHANNEL, report).await
+    }
+
+    /// Publish the progress of an update as telemetry of the device on the progress channel,
+    /// for rollout dashboards showing live progress per device.
+    pub async fn report_progress(&mut self, progress: &ProgressReport) -> Result<(), DfuError> {
+        self.publish(PROGRESS_CHANNEL, progress).await
+    }
+
+    /// The size of the firmware of the version, if it has been cached completely.
+    pub fn firmware_size(&self, version: &[u8]) -> Option<usize> {
+        self.cache.as_ref()?.size(version).ok().flatten()
+    }
+
+    /// Publish the value as JSON telemetry of the device on the channel.
+    async fn publish<T: Serialize>(&mut self, channel: &str, value: &T) -> Result<(), DfuError> {
+        self.refresh_token(false).await.map_err(DfuError::Auth)?;
+        let response = self
+            .post(channel, Vec::new())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(value).map_err(|e| DfuError::Protocol(e.into()))?)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(status_error(
+                status,
+                anyhow!(
+                    "Error publishing to {} channel: {}: {}",
+                    channel,
+                    status,
+                    response.text().await.unwrap_or_default()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Refresh the access token if it expires soon, or unconditionally if `force` is set,
+    /// returning true if the token was refreshed.
+    async fn refresh_token(&mut self, force: bool) -> Result<bool, anyhow::Error> {
+        if let Credentials::Token {
+            token,
+            login: Some(login),
+            ..
+        } = &mut self.credentials
+        {
+            // Leave some time for the request using the token to complete
+            if token.refresh_token.is_some()
+                && (force || token.expires_within(std::time::Duration::from_secs(60)))
+            {
+                *token = login.refresh(token).await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Create an authenticated request publishing to the channel.
+    fn post(&self, channel: &str, mut query: Vec<(String, String)>) -> reqwest::RequestBuilder {
+        let url = format!("{}/v1/{}", self.url, channel);
+        let mut device = self.device.clone();
+        let request = match &self.credentials {
+            Credentials::Password { user, password } => {
+                self.client.post(url).basic_auth(user, Some(password))
+            }
+            Credentials::Certificate => self.client.post(url),
+            Credentials::Token {
+                token,
+                application,
+                device: token_device,
+                ..
+            } => {
+                query.push(("application".to_string(), application.clone()));
+                device.get_or_insert_with(|| token_device.clone());
+                self.client.post(url).bearer_auth(&token.access_token)
+            }
+        };
+        if let Some(device) = device {
+            query.push(("as".to_string(), device));
+        }
+        request.query(&query[..])
+    }
+
+    /// Create an authenticated request publishing the status payload to the DFU channel.
+    fn post_status(
+        &self,
+        query: Vec<(String, String)>,
+        payload: Vec<u8>,
+    ) -> reqwest::RequestBuilder {
+        self.post("dfu", query)
+            .header(reqwest::header::CONTENT_TYPE, self.encoding.content_type())
+            .header(reqwest::header::ACCEPT, self.encoding.content_type())
+            .body(payload)
+    }
+
+    /// Look up the block following the status of a device in the middle of an update in the
+    /// cache, reading it into `cached`.
+    fn lookup(&mut self, status: &Status<'_>) -> bool {
+        let (cache, update) = match (&self.cache, &status.update) {
+            (Some(cache), Some(update)) => (cache, update),
+            _ => return false,
+        };
+        let version: &[u8] = update.version.as_ref();
+        // Leave devices running the version to the cloud, which tells them they are in sync
+        if version == status.version.as_ref() {
+            return false;
+        }
+
+        let len = status.mtu.map(|m| m as usize).unwrap_or(DEFAULT_BLOCK_SIZE);
+        cache
+            .read(version, update.offset, len, &mut self.cached)
+            .unwrap_or_else(|e| {
+                log::warn!("Error reading firmware cache: {:?}", e);
+                false
+            })
+    }
+
+    /// Publish the status to the cloud, waiting up to `timeout` for a command, which is kept
+    /// in `last_response`. Returns the encoding of the command, or `None` if there is none.
+    async fn send_status(
+        &mut self,
+        status: &Status<'_>,
+        timeout: Option<std::time::Duration>,
+    ) -> Result<Option<PayloadEncoding>, DfuError> {
+        let payload = match self.encoding {
+            PayloadEncoding::Cbor => {
+                serde_cbor::to_vec(status).map_err(|e| DfuError::Protocol(e.into()))?
+            }
+            PayloadEncoding::Json => {
+                serde_json::to_vec(status).map_err(|e| DfuError::Protocol(e.into()))?
+            }
+        };
+        let mut query: Vec<(String, String)> = Vec::new();
+        if let Some(timeout) = timeout {
+            query.push(("ct".to_string(), format!("{}", timeout.as_secs())));
+        }
+
+        self.refresh_token(false).await.map_err(DfuError::Auth)?;
+        let mut result = self
+            .post_status(query.clone(), payload.clone())
+            .send()
+            .await;
+        // The token may have been revoked or expired early
+        if matches!(&result, Ok(r) if r.status() == reqwest::StatusCode::UNAUTHORIZED)
+            && self.refresh_token(true).await.map_err(DfuError::Auth)?
+        {
+            result = self.post_status(query, payload).send().await;
+        }
+
+        match result {
+            Ok(r) if !r.status().is_success() => {
+                let status = r.status();
+                Err(status_error(
+                    status,
+                    anyhow!(
+                        "Error reporting status to cloud: {}: {}",
+                        status,
+                        r.text().await.unwrap_or_default()
+                    ),
+                ))
+            }
+            Ok(r) => {
+                // Trust the content type of the response over the one asked for
+                let encoding = r
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(PayloadEncoding::from_content_type)
+                    .unwrap_or(self.encoding);
+                let payload = r
+                    .bytes()
+                    .await
+                    .map_err(|_| DfuError::Protocol(anyhow!("Error retrieving payload")))?;
+                log::trace!("Received command: {:?}", payload);
+                self.last_response.clear();
+                self.last_response.extend(payload);
+                Ok((!self.last_response.is_empty()).then_some(encoding))
+            }
+            Err(e) => Err(e.into()),
         }
     }
 }
 
+/// Decode a command received from the cloud, borrowing its bytes from the payload with CBOR
+/// or from the decoded JSON command.
+pub(crate) fn decode_command<'m>(
+    encoding: PayloadEncoding,
+    payload: &'m mut [u8],
+    json: &'m mut Option<JsonCommand>,
+) -> Result<Command<'m>, DfuError> {
+    match encoding {
+        PayloadEncoding::Cbor => serde_cbor::de::from_mut_slice::<Command<'m>>(payload)
+            .map_err(|_| DfuError::Protocol(anyhow!("Error parsing command"))),
+        PayloadEncoding::Json => match serde_json::from_slice(payload) {
+            Ok(cmd) => Ok(json.insert(cmd).as_command()),
+            Err(_) => Err(DfuError::Protocol(anyhow!("Error parsing command"))),
+        },
+    }
+}
+
+/// Classify an error response of the cloud, which refuses requests with invalid credentials.
+fn status_error(status: reqwest::StatusCode, error: anyhow::Error) -> DfuError {
+    match status {
+        reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => DfuError::Auth(error),
+        _ => DfuError::Protocol(error),
+    }
+}
+
 impl embedded_update::UpdateService for DrogueFirmwareService {
-    type Error = anyhow::Error;
+    type Error = DfuError;
 
     type RequestFuture<'m> = impl Future<Output = Result<Command<'m>, Self::Error>> + 'm
     where
@@ -43,51 +509,32 @@ impl embedded_update::UpdateService for DrogueFirmwareService {
 
     fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Self::RequestFuture<'m> {
         async move {
-            let payload = serde_cbor::to_vec(status)?;
-            let mut query: Vec<(String, String)> = Vec::new();
-            query.push(("ct".to_string(), format!("{}", self.timeout.as_secs())));
-            /* TODO: act on behalf of device
-            if let Some(name) = name {
-                query.push(("as".to_string(), name.to_string()));
+            // Cached blocks are served without waiting for the cloud, which still sees the
+            // progress, and whose commands take precedence over the cache
+            let cached = self.lookup(status);
+            let timeout = (!cached).then_some(self.timeout);
+            let encoding = self.send_status(status, timeout).await?;
+            if let (Some(update), None, true) = (&status.update, encoding, cached) {
+                log::trace!("Writing cached block at offset {}", update.offset);
+                return Ok(Command::new_write(
+                    update.version.as_ref(),
+                    update.offset,
+                    &self.cached,
+                    status.correlation_id,
+                ));
             }
-            */
-
-            let url = format!("{}/v1/dfu", self.url);
-            let result = self
-                .client
-                .post(url)
-                .basic_auth(&self.user, Some(&self.password))
-                .query(&query[..])
-                .body(payload)
-                .send()
-                .await;
-
-            match result {
-                Ok(r) if !r.status().is_success() => Err(anyhow!(
-                    "Error reporting status to cloud: {}: {}",
-                    r.status(),
-                    r.text().await.unwrap_or_default()
-                )),
-                Ok(r) => {
-                    if let Ok(payload) = r.bytes().await {
-                        log::trace!("Received command: {:?}", payload);
-                        {
-                            self.last_response.clear();
-                            self.last_response.extend(payload);
-                        }
-                        if let Ok(cmd) = serde_cbor::de::from_mut_slice::<Command<'m>>(
-                            &mut self.last_response[..],
-                        ) {
-                            Ok(cmd)
-                        } else {
-                            Err(anyhow!("Error parsing command"))
-                        }
-                    } else {
-                        Err(anyhow!("Error retrieving payload"))
-                    }
+
+            let command = decode_command(
+                encoding.unwrap_or(self.encoding),
+                &mut self.last_response,
+                &mut self.last_command,
+            )?;
+            if let Some(cache) = &self.cache {
+                if let Err(e) = cache.store(&command) {
+                    log::warn!("Error writing firmware cache: {:?}", e);
                 }
-                Err(e) => return Err(e.into()),
             }
+            Ok(command)
         }
     }
 }
@@ -96,24 +543,262 @@ impl embedded_update::UpdateService for DrogueFirmwareService {
 pub enum FirmwareError {
     Io(std::io::Error),
     Parse(serde_json::Error),
+    Http(reqwest::Error),
+    Checksum { expected: String, actual: String },
+    Image(String),
 }
 
 impl FirmwareFileMeta {
     pub fn new(version: &str, path: &PathBuf) -> Result<Self, FirmwareError> {
-        let f = File::open(path)?;
-        let metadata = f.metadata()?;
-        let len = metadata.len();
+        let data = decode_image(std::fs::read(path)?)?;
         Ok(Self {
             version: version.to_string(),
-            size: len as usize,
+            size: data.len(),
             checksum: String::new(),
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            images: Vec::new(),
+            slot_size: None,
+            encryption: None,
         })
     }
+
+    /// Create metadata for a firmware combining several images, returning the metadata and the
+    /// combined firmware.
+    pub fn with_images(
+        version: &str,
+        images: Vec<(String, String, Vec<u8>)>,
+        algorithm: ChecksumAlgorithm,
+    ) -> (Self, Vec<u8>) {
+        let mut data = Vec::new();
+        let mut metadata = Vec::new();
+        for (name, version, image) in images {
+            metadata.push(ImageMeta {
+                name,
+                version,
+                offset: data.len(),
+                size: image.len(),
+                checksum: algorithm.hex(&image),
+            });
+            data.extend_from_slice(&image);
+        }
+        (
+            Self {
+                version: version.to_string(),
+                size: data.len(),
+                checksum: algorithm.hex(&data),
+                checksum_algorithm: algorithm,
+                images: metadata,
+                slot_size: None,
+                encryption: None,
+            },
+            data,
+        )
+    }
     pub fn from_file(path: &PathBuf) -> Result<Self, FirmwareError> {
         let data = std::fs::read_to_string(path)?;
         let metadata = serde_json::from_str(&data)?;
         Ok(metadata)
     }
+
+    /// Read metadata from a file, verifying it with a PEM encoded ECDSA P-256 or Ed25519
+    /// public key against the detached signature in the file with the `.sig` extension
+    /// appended, such as `metadata.json.sig`.
+    pub fn from_signed_file(path: &PathBuf, public_key: &[u8]) -> anyhow::Result<Self> {
+        let data = std::fs::read(path)?;
+        let signature = std::fs::read(signature_path(path))
+            .map_err(|e| anyhow!("error reading signature of {}: {}", path.display(), e))?;
+        parse_public_key(public_key)?
+            .verify(&data, &signature)
+            .map_err(|_| anyhow!("invalid metadata signature").context(Failure::Verification))?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Write the metadata to a file along with its detached signature, created with the key.
+    /// The checksum should be set for the signature to cover the firmware.
+    pub fn to_signed_file(&self, path: &PathBuf, key: &SigningKey) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        let signature = key.sign_data(&data)?;
+        std::fs::write(path, &data)?;
+        std::fs::write(signature_path(path), signature)?;
+        Ok(())
+    }
+
+    pub async fn from_url(client: &reqwest::Client, url: &str) -> Result<Self, FirmwareError> {
+        let data = download(client, url).await?;
+        let metadata = serde_json::from_slice(&data)?;
+        Ok(metadata)
+    }
+}
+
+/// Download a firmware image with the client, verifying it against the expected checksum if
+/// provided.
+///
+/// The image is downloaded to a partial file in the cache directory of the user first, so that
+/// an interrupted download is resumed with a range request by the next download of the same
+/// URL. While another update downloads the same URL, the image is downloaded into memory.
+pub async fn download_firmware(
+    client: &reqwest::Client,
+    url: &str,
+    checksum: Option<&str>,
+    algorithm: ChecksumAlgorithm,
+) -> Result<Vec<u8>, FirmwareError> {
+    let data = match download_dir()? {
+        Some(dir) => {
+            let partial = dir.join(format!("{}.part", sha256(url.as_bytes())));
+            let lock = std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(partial.with_extension("lock"))?;
+            if try_lock(&lock)? {
+                download_resumable(client, url, &partial).await?
+            } else {
+                log::info!("{} is downloaded by another update, not resuming", url);
+                download(client, url).await?
+            }
+        }
+        None => download(client, url).await?,
+    };
+    if let Some(expected) = checksum {
+        let actual = algorithm.hex(&data);
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(FirmwareError::Checksum {
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+    Ok(data)
+}
+
+/// Largest binary image spanned by the records of an S-record or DfuSe file, far beyond the
+/// flash of any device updated, so that a stray address is rejected instead of allocating
+/// gigabytes of padding.
+pub const MAX_IMAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Convert a firmware image in a supported format, such as Motorola S-records or DfuSe files,
+/// to a binary.
+/// Binary images are returned as is.
+pub fn decode_image(data: Vec<u8>) -> Result<Vec<u8>, FirmwareError> {
+    if is_srec(&data) {
+        srec_to_binary(&data)
+    } else if is_dfuse(&data) {
+        dfuse_to_binary(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Path of the detached signature of a file.
+fn signature_path(path: &PathBuf) -> PathBuf {
+    let mut path = path.clone().into_os_string();
+    path.push(".sig");
+    path.into()
+}
+
+/// Compute the hex encoded SHA-256 checksum of the data.
+pub fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+async fn download(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, FirmwareError> {
+    log::debug!("Downloading {}", url);
+    let response = client.get(url).send().await?.error_for_status()?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Directory of partial downloads, created accessible to the user only.
+fn download_dir() -> std::io::Result<Option<PathBuf>> {
+    let dir = match FirmwareCache::default_dir() {
+        Some(dir) => dir.join("downloads"),
+        None => return Ok(None),
+    };
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&dir)?;
+    Ok(Some(dir))
+}
+
+/// Lock the file exclusively until it is closed, returning false if it is locked already.
+/// Files are not locked on other platforms than Unix.
+#[cfg(unix)]
+fn try_lock(file: &std::fs::File) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    // SAFETY: the descriptor stays open for the duration of the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    match std::io::Error::last_os_error() {
+        e if e.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+        e => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_: &std::fs::File) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+/// Download to the partial file, continuing after the data it already holds. The ETag or
+/// modification time of the download is stored next to the partial file, so that a partial
+/// file of an older version is not continued with a newer one.
+async fn download_resumable(
+    client: &reqwest::Client,
+    url: &str,
+    partial: &Path,
+) -> Result<Vec<u8>, FirmwareError> {
+    use std::io::Write;
+    let validator_path = partial.with_extension("validator");
+    let offset = std::fs::metadata(partial).map(|m| m.len()).unwrap_or(0);
+    let validator = std::fs::read_to_string(&validator_path).ok();
+
+    let mut request = client.get(url);
+    if let (true, Some(validator)) = (offset > 0, &validator) {
+        log::info!("Resuming download of {} at offset {}", url, offset);
+        request = request
+            .header(reqwest::header::RANGE, format!("bytes={}-", offset))
+            .header(reqwest::header::IF_RANGE, validator.as_str());
+    } else {
+        log::debug!("Downloading {}", url);
+    }
+    let mut response = request.send().await?;
+    if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // The partial file is not a prefix of the current download, start over
+        std::fs::remove_file(partial)?;
+        response = client.get(url).send().await?;
+    }
+    let mut response = response.error_for_status()?;
+
+    let mut file = if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        std::fs::OpenOptions::new().append(true).open(partial)?
+    } else {
+        // The server sent the whole download, because it changed or ranges are not supported
+        let validator = response
+            .headers()
+            .get(reqwest::header::ETAG)
+            .or_else(|| response.headers().get(reqwest::header::LAST_MODIFIED))
+            .and_then(|v| v.to_str().ok());
+        match validator {
+            Some(validator) => std::fs::write(&validator_path, validator)?,
+            None => {
+                let _ = std::fs::remove_file(&validator_path);
+            }
+        }
+        std::fs::File::create(partial)?
+    };
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk)?;
+    }
+    drop(file);
+
+    let data = std::fs::read(partial)?;
+    std::fs::remove_file(partial)?;
+    let _ = std::fs::remove_file(&validator_path);
+    Ok(data)
 }
 
 impl core::fmt::Display for FirmwareError {
@@ -121,6 +806,13 @@ impl core::fmt::Display for FirmwareError {
         match self {
             Self::Io(e) => e.fmt(f),
             Self::Parse(e) => e.fmt(f),
+            Self::Http(e) => e.fmt(f),
+            Self::Checksum { expected, actual } => write!(
+                f,
+                "checksum mismatch, expected {} but was {}",
+                expected, actual
+            ),
+            Self::Image(e) => e.fmt(f),
         }
     }
 }
@@ -137,4 +829,10 @@ impl From<serde_json::Error> for FirmwareError {
     }
 }
 
+impl From<reqwest::Error> for FirmwareError {
+    fn from(error: reqwest::Error) -> Self {
+        FirmwareError::Http(error)
+    }
+}
+
 impl serde::ser::StdError for FirmwareError {}
@@ -0,0 +1,86 @@
+use crate::{Device, FirmwareSource};
+use futures::stream::{self, StreamExt};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// A list of devices to update, as read from a fleet file.
+///
+/// ```yaml
+/// devices:
+///   - transport: ble-gatt
+///     device: "F7:5D:2C:3A:0E:11"
+///     labels:
+///       site: lab
+///   - transport: serial
+///     port: /dev/ttyACM0
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Fleet {
+    devices: Vec<FleetDevice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FleetDevice {
+    #[serde(flatten)]
+    device: Device,
+
+    /// Labels identifying the device in the report.
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+impl FleetDevice {
+    fn describe(&self) -> String {
+        let mut description = self.device.target();
+        if !self.labels.is_empty() {
+            let labels: Vec<String> = self
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            description = format!("{} [{}]", description, labels.join(", "));
+        }
+        description
+    }
+}
+
+impl Fleet {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_yaml::from_reader(file)?)
+    }
+
+    /// Update all devices from the source, with at most `concurrency` updates running at a time.
+    pub async fn update(self, source: FirmwareSource, concurrency: usize) -> anyhow::Result<()> {
+        let total = self.devices.len();
+        let results: Vec<(String, anyhow::Result<()>)> = stream::iter(self.devices)
+            .map(|d| {
+                let source = source.clone();
+                async move {
+                    let description = d.describe();
+                    let result = d.device.upload(source).await;
+                    (description, result)
+                }
+            })
+            .buffered(core::cmp::max(concurrency, 1))
+            .collect()
+            .await;
+
+        println!("Summary:");
+        let mut failed = 0;
+        for (description, result) in results.iter() {
+            match result {
+                Ok(_) => println!("{}: updated", description),
+                Err(e) => {
+                    failed += 1;
+                    println!("{}: failed: {}", description, e);
+                }
+            }
+        }
+        if failed > 0 {
+            return Err(anyhow::anyhow!("{} of {} updates failed", failed, total));
+        }
+        Ok(())
+    }
+}
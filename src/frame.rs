@@ -0,0 +1,242 @@
+use crate::{SerialRequest, SerialResponse, FRAME_SIZE};
+use embedded_update::{Command, Status};
+
+/// Maximum length of a firmware version supported by the serial protocol.
+pub const MAX_VERSION_LEN: usize = 16;
+
+/// Errors returned when decoding a serial protocol frame.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The frame is not exactly `FRAME_SIZE` bytes.
+    Length { len: usize },
+    /// The frame does not contain a valid message.
+    Malformed(postcard::Error),
+    /// A version is longer than `MAX_VERSION_LEN` bytes.
+    VersionTooLong { len: usize },
+    /// Data at the given offset extends past the end of the address space.
+    OutOfRange { offset: u32, len: usize },
+    /// The device reported a block size of zero.
+    InvalidMtu,
+}
+
+impl core::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Length { len } => write!(
+                f,
+                "frame of {} bytes does not match frame size of {} bytes",
+                len, FRAME_SIZE
+            ),
+            Self::Malformed(e) => write!(f, "malformed frame: {}", e),
+            Self::VersionTooLong { len } => write!(
+                f,
+                "version of {} bytes exceeds {} bytes",
+                len, MAX_VERSION_LEN
+            ),
+            Self::OutOfRange { offset, len } => write!(
+                f,
+                "{} bytes at offset {} exceed the address space",
+                len, offset
+            ),
+            Self::InvalidMtu => write!(f, "block size of zero"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+fn check_length(frame: &[u8]) -> Result<(), FrameError> {
+    if frame.len() != FRAME_SIZE {
+        return Err(FrameError::Length { len: frame.len() });
+    }
+    Ok(())
+}
+
+fn check_version(version: &[u8]) -> Result<(), FrameError> {
+    if version.len() > MAX_VERSION_LEN {
+        return Err(FrameError::VersionTooLong { len: version.len() });
+    }
+    Ok(())
+}
+
+fn check_range(offset: u32, len: usize) -> Result<(), FrameError> {
+    u32::try_from(len)
+        .ok()
+        .and_then(|l| offset.checked_add(l))
+        .map(|_| ())
+        .ok_or(FrameError::OutOfRange { offset, len })
+}
+
+/// Decode a status frame sent by a device.
+///
+/// Never panics, whatever the contents of the frame, so that a misbehaving device can not
+/// crash the host.
+pub fn decode_status(frame: &[u8]) -> Result<Status<'_>, FrameError> {
+    check_length(frame)?;
+    let status: Status = postcard::from_bytes(frame).map_err(FrameError::Malformed)?;
+    check_version(&status.version)?;
+    if status.mtu == Some(0) {
+        return Err(FrameError::InvalidMtu);
+    }
+    if let Some(update) = &status.update {
+        check_version(&update.version)?;
+    }
+    Ok(status)
+}
+
+/// Decode a command frame sent by the host.
+pub fn decode_command(frame: &[u8]) -> Result<Command<'_>, FrameError> {
+    check_length(frame)?;
+    let command: Command = postcard::from_bytes(frame).map_err(FrameError::Malformed)?;
+    match &command {
+        Command::Write {
+            version,
+            offset,
+            data,
+            ..
+        } => {
+            check_version(version)?;
+            check_range(*offset, data.len())?;
+        }
+        Command::Swap { version, .. } | Command::Sync { version, .. } => check_version(version)?,
+        Command::Wait { .. } => {}
+    }
+    Ok(command)
+}
+
+/// Decode a frame sent by a device in response to a `SerialRequest`.
+pub fn decode_response(frame: &[u8]) -> Result<SerialResponse<'_>, FrameError> {
+    check_length(frame)?;
+    let response: SerialResponse = postcard::from_bytes(frame).map_err(FrameError::Malformed)?;
+    match &response {
+        SerialResponse::Data { offset, data } => check_range(*offset, data.len())?,
+        SerialResponse::Capabilities { mtu: 0, .. } => return Err(FrameError::InvalidMtu),
+        _ => {}
+    }
+    Ok(response)
+}
+
+/// Decode a frame sent by the host as a `SerialRequest`. Update commands may decode as a
+/// request too, so devices should check that the request carries `HANDSHAKE_MAGIC`.
+pub fn decode_request(frame: &[u8]) -> Result<SerialRequest, FrameError> {
+    check_length(frame)?;
+    postcard::from_bytes(frame).map_err(FrameError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HANDSHAKE_MAGIC;
+
+    /// Encode the message into a frame, as sent over the serial port.
+    fn frame<T: serde::Serialize>(message: &T) -> Vec<u8> {
+        let mut frame = vec![0; FRAME_SIZE];
+        postcard::to_slice(message, &mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn round_trip() {
+        let status = frame(&Status::first(b"1.0", None, None));
+        assert_eq!(&decode_status(&status).unwrap().version[..], b"1.0");
+
+        let command = frame(&Command::new_write(b"1.1", 512, &[1, 2, 3], None));
+        assert!(matches!(
+            decode_command(&command),
+            Ok(Command::Write { offset: 512, data, .. }) if data[..] == [1, 2, 3]
+        ));
+
+        let request = frame(&SerialRequest::Read {
+            magic: HANDSHAKE_MAGIC,
+            offset: 64,
+            len: 32,
+        });
+        assert!(matches!(
+            decode_request(&request),
+            Ok(SerialRequest::Read {
+                magic: HANDSHAKE_MAGIC,
+                offset: 64,
+                len: 32
+            })
+        ));
+
+        let response = frame(&SerialResponse::SlotSize { size: 4096 });
+        assert!(matches!(
+            decode_response(&response),
+            Ok(SerialResponse::SlotSize { size: 4096 })
+        ));
+    }
+
+    #[test]
+    fn reject_wrong_length() {
+        assert!(matches!(
+            decode_status(&[0; 10]),
+            Err(FrameError::Length { len: 10 })
+        ));
+        let status = frame(&Status::first(b"1.0", None, None));
+        assert!(matches!(
+            decode_status(&status[..FRAME_SIZE - 1]),
+            Err(FrameError::Length { len }) if len == FRAME_SIZE - 1
+        ));
+    }
+
+    #[test]
+    fn reject_malformed() {
+        let garbage = [0xFF; FRAME_SIZE];
+        assert!(matches!(
+            decode_status(&garbage),
+            Err(FrameError::Malformed(_))
+        ));
+        assert!(matches!(
+            decode_response(&garbage),
+            Err(FrameError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn reject_version_too_long() {
+        let version = [b'1'; MAX_VERSION_LEN + 1];
+        assert!(matches!(
+            decode_status(&frame(&Status::first(&version, None, None))),
+            Err(FrameError::VersionTooLong { len }) if len == MAX_VERSION_LEN + 1
+        ));
+        assert!(matches!(
+            decode_command(&frame(&Command::new_write(&version, 0, &[1], None))),
+            Err(FrameError::VersionTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_out_of_range() {
+        let command = frame(&Command::new_write(b"1.0", u32::MAX, &[1, 2], None));
+        assert!(matches!(
+            decode_command(&command),
+            Err(FrameError::OutOfRange {
+                offset: u32::MAX,
+                len: 2
+            })
+        ));
+        let response = frame(&SerialResponse::Data {
+            offset: u32::MAX - 1,
+            data: &[1, 2],
+        });
+        assert!(matches!(
+            decode_response(&response),
+            Err(FrameError::OutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_zero_mtu() {
+        let response = frame(&SerialResponse::Capabilities {
+            magic: HANDSHAKE_MAGIC,
+            protocol: 1,
+            mtu: 0,
+            features: 0,
+        });
+        assert!(matches!(
+            decode_response(&response),
+            Err(FrameError::InvalidMtu)
+        ));
+    }
+}
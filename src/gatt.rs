@@ -1,82 +1,651 @@
-use btleplug::api::{BDAddr, Central, Characteristic, Peripheral as _, WriteType};
+use crate::gatt_protocol::{self, aligned_chunk_size, CONTROL_BOOTED, CONTROL_SWAP};
+use crate::{
+    metrics, ChecksumMismatch, Compression, DfuError, Failure, GattTransport, RetryPolicy,
+    CONTROL_CHAR_UUID, FIRMWARE_CHAR_UUID, FIRMWARE_SERVICE_UUID, MTU_CHAR_UUID,
+    NEXT_VERSION_CHAR_UUID, OFFSET_CHAR_UUID, SLOT_SIZE_CHAR_UUID, VERSION_CHAR_UUID,
+};
+use btleplug::api::{
+    BDAddr, Central, CharPropFlags, Characteristic, Peripheral as _, PeripheralProperties,
+    ScanFilter, ValueNotification, WriteType,
+};
 use btleplug::platform::{Adapter, Peripheral};
 use core::future::Future;
+use core::ops::RangeInclusive;
+use core::pin::Pin;
 use embedded_update::*;
-use tokio::time::{sleep, Duration};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, Duration, Instant};
+
+type Notifications = Pin<Box<dyn Stream<Item = ValueNotification> + Send>>;
 
 pub struct GattBoard {
     adapter: Adapter,
-    address: BDAddr,
+    target: Target,
     board: Option<Peripheral>,
     updated: bool,
     mtu: Option<u8>,
+    chunk_size: Option<u8>,
+    compression: Option<Compression>,
+    compressed: bool,
+    // Offset notifications, or None if the device only supports polling
+    offsets: Option<Option<Notifications>>,
+    write_without_response: bool,
+    firmware_write_type: Option<WriteType>,
+    pipeline: usize,
+    adaptive: bool,
+    adaptive_chunk: Option<AdaptiveChunk>,
+    connect_timeout: Option<Duration>,
+    scan_timeout: Option<Duration>,
+    retry: RetryPolicy,
+    connected: bool,
+    rssi_threshold: i16,
+    last_rssi: Option<i16>,
+    discovery: Option<Discovery>,
+    searching: bool,
+    max_recoveries: u32,
+    recoveries: u32,
+    phy: Option<Phy>,
+    phy_selected: bool,
+    /// Index of the adapter whose PHYs were selected, to be restored once done.
+    phy_adapter: Option<String>,
+    /// Version whose written firmware did not match its checksum, to be written again from
+    /// the start.
+    rejected: Option<Vec<u8>>,
+}
+
+/// LE physical layer preferred for connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Phy {
+    /// LE 1M, supported by all devices.
+    #[serde(rename = "1m")]
+    Le1M,
+    /// LE 2M, doubling the data rate for faster transfers.
+    #[serde(rename = "2m")]
+    Le2M,
+    /// LE Coded, for longer range at a lower data rate.
+    #[serde(rename = "coded")]
+    Coded,
+}
+
+impl Phy {
+    /// PHYs selected with btmgmt for this preference. LE 1M remains selected, as it is
+    /// required for advertising.
+    #[cfg(target_os = "linux")]
+    fn btmgmt_phys(&self) -> &'static [&'static str] {
+        match self {
+            Self::Le1M => &["LE1MTX", "LE1MRX"],
+            Self::Le2M => &["LE1MTX", "LE1MRX", "LE2MTX", "LE2MRX"],
+            Self::Coded => &["LE1MTX", "LE1MRX", "LECODEDTX", "LECODEDRX"],
+        }
+    }
+}
+
+impl core::str::FromStr for Phy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1m" => Ok(Self::Le1M),
+            "2m" => Ok(Self::Le2M),
+            "coded" => Ok(Self::Coded),
+            _ => Err(anyhow::anyhow!("unknown PHY '{}'", s)),
+        }
+    }
+}
+
+/// A DFU capable device found during a scan.
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    pub address: BDAddr,
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+}
+
+/// Information identifying a device, read from the Device Information Service and the
+/// firmware service. Values not exposed by the device are none.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub address: String,
+    pub name: Option<String>,
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub firmware_revision: Option<String>,
+    pub software_revision: Option<String>,
+    pub current_version: Option<String>,
+    pub next_version: Option<String>,
+    pub offset: Option<u32>,
+    pub mtu: Option<u8>,
+    pub slot_size: Option<u32>,
 }
 
-const FIRMWARE_SERVICE_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001000b0cd11ec871fd45ddf138840);
+/// ATT MTU used by devices not negotiating a larger one.
+pub(crate) const DEFAULT_ATT_MTU: u16 = 23;
+/// Opcode and handle preceding the value in ATT write requests.
+pub(crate) const ATT_WRITE_OVERHEAD: u16 = 3;
+
+/// Signal strength below which transfers are likely to be slow or interrupted.
+const DEFAULT_RSSI_THRESHOLD: i16 = -80;
+
+/// Number of times a transfer is continued after the device disconnected during an update.
+const DEFAULT_MAX_RECOVERIES: u32 = 3;
+
+/// Number of times the services of a device are discovered before giving up on finding the
+/// firmware service. WinRT may complete the discovery before the services of a device just
+/// connected to are known.
+#[cfg(target_os = "windows")]
+const SERVICE_DISCOVERY_ATTEMPTS: u32 = 5;
+#[cfg(not(target_os = "windows"))]
+const SERVICE_DISCOVERY_ATTEMPTS: u32 = 1;
+
+const DEVICE_INFO_SERVICE_UUID: uuid::Uuid = btleplug::api::bleuuid::uuid_from_u16(0x180A);
+const MANUFACTURER_CHAR_UUID: uuid::Uuid = btleplug::api::bleuuid::uuid_from_u16(0x2A29);
+const MODEL_CHAR_UUID: uuid::Uuid = btleplug::api::bleuuid::uuid_from_u16(0x2A24);
+const SERIAL_NUMBER_CHAR_UUID: uuid::Uuid = btleplug::api::bleuuid::uuid_from_u16(0x2A25);
+const HARDWARE_REVISION_CHAR_UUID: uuid::Uuid = btleplug::api::bleuuid::uuid_from_u16(0x2A27);
+const FIRMWARE_REVISION_CHAR_UUID: uuid::Uuid = btleplug::api::bleuuid::uuid_from_u16(0x2A26);
+const SOFTWARE_REVISION_CHAR_UUID: uuid::Uuid = btleplug::api::bleuuid::uuid_from_u16(0x2A28);
 
-const VERSION_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001001b0cd11ec871fd45ddf138840);
-const MTU_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001002b0cd11ec871fd45ddf138840);
-const CONTROL_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001003b0cd11ec871fd45ddf138840);
-const NEXT_VERSION_CHAR_UUID: uuid::Uuid =
-    uuid::Uuid::from_u128(0x00001004b0cd11ec871fd45ddf138840);
-const OFFSET_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001005b0cd11ec871fd45ddf138840);
-const FIRMWARE_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x00001006b0cd11ec871fd45ddf138840);
+/// Number of chunks written before the throughput of a chunk size is evaluated.
+const ADAPTIVE_SAMPLE_CHUNKS: usize = 32;
+
+/// Chunk size adjusted to the fastest one the link sustains.
+///
+/// Chunk sizes are tried from small to large, moving on while the throughput improves and
+/// settling on the best size otherwise. Failed writes step back to a smaller size. All sizes
+/// are powers of two, so that they divide the blocks of firmware.
+struct AdaptiveChunk {
+    sizes: Vec<usize>,
+    current: usize,
+    best: Option<(usize, f64)>,
+    settled: bool,
+    sample_start: Option<Instant>,
+    sample_bytes: usize,
+    sample_chunks: usize,
+}
+
+impl AdaptiveChunk {
+    fn new(max: usize) -> Self {
+        // Start with a size fitting the default ATT MTU, doubling up to the maximum
+        let max = aligned_chunk_size(max);
+        let mut sizes = Vec::new();
+        let mut size = core::cmp::min(
+            aligned_chunk_size((DEFAULT_ATT_MTU - ATT_WRITE_OVERHEAD) as usize),
+            max,
+        );
+        while size <= max {
+            sizes.push(size);
+            size *= 2;
+        }
+        Self {
+            sizes,
+            current: 0,
+            best: None,
+            settled: false,
+            sample_start: None,
+            sample_bytes: 0,
+            sample_chunks: 0,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.sizes[self.current]
+    }
+
+    /// Record a chunk written successfully, which started being written at `started`.
+    fn written(&mut self, len: usize, started: Instant) {
+        let start = *self.sample_start.get_or_insert(started);
+        self.sample_bytes += len;
+        self.sample_chunks += 1;
+        if self.settled || self.sample_chunks < ADAPTIVE_SAMPLE_CHUNKS {
+            return;
+        }
+
+        let throughput = self.sample_bytes as f64 / start.elapsed().as_secs_f64();
+        log::debug!("Chunk size {}: {:.0} bytes/s", self.size(), throughput);
+        match self.best {
+            Some((best, best_throughput)) if throughput <= best_throughput => {
+                self.current = best;
+                self.settled = true;
+            }
+            _ => {
+                self.best = Some((self.current, throughput));
+                if self.current + 1 < self.sizes.len() {
+                    self.current += 1;
+                } else {
+                    self.settled = true;
+                }
+            }
+        }
+        if self.settled {
+            log::info!("Using chunk size {}", self.size());
+        }
+        self.reset_sample();
+    }
+
+    fn failed(&mut self) {
+        if self.current > 0 {
+            self.current -= 1;
+            log::info!("Write failed, reducing chunk size to {}", self.size());
+        }
+        self.settled = true;
+        self.reset_sample();
+    }
+
+    fn reset_sample(&mut self) {
+        self.sample_start = None;
+        self.sample_bytes = 0;
+        self.sample_chunks = 0;
+    }
+}
+
+/// The peripheral to connect to.
+#[derive(Debug, Clone)]
+enum Target {
+    Address(BDAddr),
+    /// Identifier of the peripheral assigned by the platform, which is not the address of
+    /// the device on Mac OS X, nor on Windows for devices using random addresses.
+    Id(String),
+    Name(String),
+}
+
+impl Target {
+    fn matches(&self, device: &Peripheral, p: &PeripheralProperties) -> bool {
+        self.matches_properties(&device.id().to_string(), p)
+    }
+
+    /// Returns true if the peripheral with the platform identifier and properties is the
+    /// target.
+    fn matches_properties(&self, device_id: &str, p: &PeripheralProperties) -> bool {
+        match self {
+            Self::Address(address) => p.address == *address,
+            Self::Id(id) => device_id.eq_ignore_ascii_case(id),
+            Self::Name(pattern) => p
+                .local_name
+                .as_ref()
+                .map(|name| matches_pattern(pattern.as_bytes(), name.as_bytes()))
+                .unwrap_or(false),
+        }
+    }
+}
+
+impl core::fmt::Display for Target {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Address(address) => address.fmt(f),
+            Self::Id(id) => id.fmt(f),
+            Self::Name(name) => name.fmt(f),
+        }
+    }
+}
 
 impl GattBoard {
+    /// Connect to the peripheral with the given address, written with `:` or `-` delimiters or
+    /// without any as shown by Windows, or else with the given platform identifier.
     pub fn new(device: &str, adapter: Adapter) -> Self {
+        match parse_address(device) {
+            Some(address) => Self::with_target(Target::Address(address), adapter),
+            None => Self::new_by_id(device, adapter),
+        }
+    }
+
+    /// Connect to the peripheral with the given platform identifier, such as the UUID
+    /// identifying peripherals on Mac OS X.
+    pub fn new_by_id(id: &str, adapter: Adapter) -> Self {
+        Self::with_target(Target::Id(id.to_string()), adapter)
+    }
+
+    /// Connect to the first peripheral with an advertised name matching the pattern, where `*`
+    /// matches any sequence of characters and `?` matches a single character.
+    pub fn new_by_name(pattern: &str, adapter: Adapter) -> Self {
+        Self::with_target(Target::Name(pattern.to_string()), adapter)
+    }
+
+    fn with_target(target: Target, adapter: Adapter) -> Self {
         Self {
-            address: BDAddr::from_str_delim(device).unwrap(),
+            target,
             adapter,
             board: None,
             updated: false,
             mtu: None,
+            chunk_size: None,
+            compression: None,
+            compressed: false,
+            offsets: None,
+            write_without_response: false,
+            firmware_write_type: None,
+            pipeline: 1,
+            adaptive: false,
+            adaptive_chunk: None,
+            connect_timeout: None,
+            scan_timeout: None,
+            retry: RetryPolicy::fixed(Duration::from_secs(2)),
+            connected: false,
+            rssi_threshold: DEFAULT_RSSI_THRESHOLD,
+            last_rssi: None,
+            discovery: None,
+            searching: false,
+            max_recoveries: DEFAULT_MAX_RECOVERIES,
+            recoveries: 0,
+            phy: None,
+            phy_selected: false,
+            phy_adapter: None,
+            rejected: None,
         }
     }
 
-    async fn read_firmware_offset(&mut self) -> anyhow::Result<u32> {
-        let data = self
-            .read_char(FIRMWARE_SERVICE_UUID, OFFSET_CHAR_UUID)
+    /// Prefer the given PHY for connections, if the device supports it. On Linux, this selects
+    /// the PHYs of the whole adapter using `btmgmt`, which requires the `CAP_NET_ADMIN`
+    /// capability, until the board is dropped. Other platforms do not support selecting the
+    /// PHY.
+    pub fn with_phy(mut self, phy: Phy) -> Self {
+        self.phy.replace(phy);
+        self
+    }
+
+    /// Reconnect and continue the transfer from the offset reported by the device at most
+    /// `recoveries` times per update when the device disconnects during the transfer.
+    pub fn with_max_recoveries(mut self, recoveries: u32) -> Self {
+        self.max_recoveries = recoveries;
+        self
+    }
+
+    /// Discover devices while looking for the device, scanning only until it is found so
+    /// that the scan does not contend with the transfer for the radio.
+    pub fn with_discovery(mut self, discovery: Discovery) -> Self {
+        self.discovery.replace(discovery);
+        self
+    }
+
+    /// Give up connecting if the device is not connected within the timeout, which bounds
+    /// all connection attempts together rather than each of them.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout.replace(timeout);
+        self
+    }
+
+    /// Fail if the device is not found within the timeout.
+    pub fn with_scan_timeout(mut self, timeout: Duration) -> Self {
+        self.scan_timeout.replace(timeout);
+        self
+    }
+
+    /// Fail after the given number of failed connection attempts.
+    pub fn with_max_retries(mut self, retries: u32) -> Self {
+        self.retry = self.retry.with_max_attempts(retries + 1);
+        self
+    }
+
+    /// Retry failed connection attempts and service discovery according to the policy, whose
+    /// first delay is also the interval of looking for the device.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Warn when the signal strength drops below the given RSSI in dBm during a transfer.
+    pub fn with_rssi_threshold(mut self, rssi: i16) -> Self {
+        self.rssi_threshold = rssi;
+        self
+    }
+
+    /// Write at most `chunk_size` bytes of firmware at a time, instead of the size requested by
+    /// the device. Sizes not dividing the blocks of firmware are rounded down to a power of two.
+    pub fn with_chunk_size(mut self, chunk_size: u8) -> Self {
+        self.chunk_size.replace(chunk_size);
+        self
+    }
+
+    /// Write firmware chunks without response if the device supports it, only checking the
+    /// offset periodically.
+    pub fn with_write_without_response(mut self) -> Self {
+        self.write_without_response = true;
+        self
+    }
+
+    /// Keep up to `chunks` firmware chunks in flight before waiting for the device to confirm
+    /// them, instead of waiting for each chunk. This speeds up transfers over links with high
+    /// latency, as long as the device keeps up with the writes.
+    pub fn with_pipeline(mut self, chunks: usize) -> Self {
+        self.pipeline = chunks.max(1);
+        self
+    }
+
+    /// Start with small firmware chunks, and grow them up to the device MTU for as long as this
+    /// improves throughput. Chunks are made smaller again when writes fail.
+    pub fn with_adaptive_chunk_size(mut self) -> Self {
+        self.adaptive = true;
+        self
+    }
+
+    /// Find the addresses of known peripherals matching a pattern, where `*` matches any
+    /// sequence of characters and `?` matches a single character.
+    pub async fn discover(adapter: &Adapter, pattern: &str) -> anyhow::Result<Vec<BDAddr>> {
+        let mut found = Vec::new();
+        for device in adapter.peripherals().await? {
+            if let Some(p) = device.properties().await? {
+                if matches_pattern(pattern.as_bytes(), p.address.to_string().as_bytes()) {
+                    found.push(p.address);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Start discovering devices advertising the firmware service, ignoring unrelated
+    /// peripherals.
+    pub async fn start_discovery(adapter: &Adapter) -> anyhow::Result<()> {
+        adapter
+            .start_scan(ScanFilter {
+                services: vec![FIRMWARE_SERVICE_UUID],
+            })
             .await?;
-        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+        Ok(())
     }
 
-    async fn read_firmware_version(&mut self) -> anyhow::Result<Vec<u8>> {
-        let data = self
-            .read_char(FIRMWARE_SERVICE_UUID, VERSION_CHAR_UUID)
+    /// Scan for devices exposing the firmware service for the given duration.
+    pub async fn scan(
+        adapter: &Adapter,
+        duration: Duration,
+    ) -> anyhow::Result<Vec<DiscoveredDevice>> {
+        Self::start_discovery(adapter).await?;
+        sleep(duration).await;
+        adapter.stop_scan().await?;
+
+        let mut found = Vec::new();
+        for device in adapter.peripherals().await? {
+            if let Some(p) = device.properties().await? {
+                // Not all backends apply the scan filter
+                if p.services.contains(&FIRMWARE_SERVICE_UUID) {
+                    found.push(DiscoveredDevice {
+                        address: p.address,
+                        name: p.local_name,
+                        rssi: p.rssi,
+                    });
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    /// Disconnect from the device if connected.
+    pub async fn disconnect(&mut self) -> anyhow::Result<()> {
+        self.offsets = None;
+        if let Some(board) = self.board.take() {
+            board.disconnect().await?;
+        }
+        Ok(())
+    }
+
+    /// Compress firmware using the given algorithm, if the device supports it.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression.replace(compression);
+        self
+    }
+
+    /// Read the size of the firmware slot of the device, or none if the device does not expose
+    /// the slot size characteristic.
+    pub async fn slot_size(&mut self) -> anyhow::Result<Option<u32>> {
+        let (device, c) = self
+            .find_char(FIRMWARE_SERVICE_UUID, SLOT_SIZE_CHAR_UUID)
             .await?;
-        Ok(data)
+        match c {
+            Some(c) => {
+                let data = device.read(&c).await?;
+                if data.len() < 4 {
+                    return Err(anyhow::anyhow!("invalid slot size {:x?}", data));
+                }
+                Ok(Some(u32::from_le_bytes([
+                    data[0], data[1], data[2], data[3],
+                ])))
+            }
+            None => Ok(None),
+        }
     }
 
+    /// Read the Device Information Service and the firmware characteristics of the device.
+    pub async fn device_info(&mut self) -> anyhow::Result<DeviceInfo> {
+        let properties = self.connect().await?.properties().await?;
+        let text = |v: Option<Vec<u8>>| v.map(|v| String::from_utf8_lossy(&v).trim().to_string());
+        let dis = DEVICE_INFO_SERVICE_UUID;
+        let firmware = FIRMWARE_SERVICE_UUID;
+        Ok(DeviceInfo {
+            address: properties
+                .as_ref()
+                .map(|p| p.address.to_string())
+                .unwrap_or_else(|| self.target.to_string()),
+            name: properties.and_then(|p| p.local_name),
+            manufacturer: text(self.read_optional_char(dis, MANUFACTURER_CHAR_UUID).await?),
+            model: text(self.read_optional_char(dis, MODEL_CHAR_UUID).await?),
+            serial_number: text(
+                self.read_optional_char(dis, SERIAL_NUMBER_CHAR_UUID)
+                    .await?,
+            ),
+            hardware_revision: text(
+                self.read_optional_char(dis, HARDWARE_REVISION_CHAR_UUID)
+                    .await?,
+            ),
+            firmware_revision: text(
+                self.read_optional_char(dis, FIRMWARE_REVISION_CHAR_UUID)
+                    .await?,
+            ),
+            software_revision: text(
+                self.read_optional_char(dis, SOFTWARE_REVISION_CHAR_UUID)
+                    .await?,
+            ),
+            current_version: text(self.read_optional_char(firmware, VERSION_CHAR_UUID).await?),
+            next_version: text(
+                self.read_optional_char(firmware, NEXT_VERSION_CHAR_UUID)
+                    .await?,
+            )
+            .filter(|v| !v.is_empty()),
+            offset: self
+                .read_optional_char(firmware, OFFSET_CHAR_UUID)
+                .await?
+                .filter(|v| v.len() >= 4)
+                .map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]])),
+            mtu: self
+                .read_optional_char(firmware, MTU_CHAR_UUID)
+                .await?
+                .and_then(|v| v.first().copied()),
+            slot_size: self.slot_size().await?,
+        })
+    }
+
+    async fn read_firmware_offset(&mut self) -> anyhow::Result<u32> {
+        gatt_protocol::read_offset(self).await
+    }
+
+    async fn read_firmware_version(&mut self) -> anyhow::Result<Vec<u8>> {
+        gatt_protocol::read_version(self).await
+    }
+
+    /// Read the chunk size requested by the device, or derive it from the ATT MTU if the
+    /// device does not expose the MTU characteristic.
     async fn read_mtu(&mut self) -> anyhow::Result<u8> {
-        let data = self.read_char(FIRMWARE_SERVICE_UUID, MTU_CHAR_UUID).await?;
-        Ok(data[0])
+        let (device, c) = self.find_char(FIRMWARE_SERVICE_UUID, MTU_CHAR_UUID).await?;
+        if let Some(c) = c {
+            let data = device.read(&c).await?;
+            return Ok(data[0]);
+        }
+
+        let att_mtu = match self.att_mtu().await {
+            Ok(Some(mtu)) => mtu,
+            Ok(None) => DEFAULT_ATT_MTU,
+            Err(e) => {
+                log::debug!("Unable to read the ATT MTU: {}", e);
+                DEFAULT_ATT_MTU
+            }
+        };
+        let mtu = core::cmp::min(att_mtu.saturating_sub(ATT_WRITE_OVERHEAD), u8::MAX as u16);
+        let mtu = aligned_chunk_size(mtu as usize);
+        log::info!(
+            "Device has no MTU characteristic, using ATT MTU {} for a chunk size of {}",
+            att_mtu,
+            mtu
+        );
+        Ok(mtu as u8)
+    }
+
+    /// The ATT MTU negotiated with the device by BlueZ when connecting.
+    #[cfg(target_os = "linux")]
+    async fn att_mtu(&mut self) -> anyhow::Result<Option<u16>> {
+        let address = match self.connect().await?.properties().await? {
+            Some(p) => p.address,
+            None => return Ok(None),
+        };
+        tokio::task::spawn_blocking(move || bluez_att_mtu(address, FIRMWARE_CHAR_UUID)).await?
+    }
+
+    /// The ATT MTU negotiated with the device, which other platforms do not expose.
+    #[cfg(not(target_os = "linux"))]
+    async fn att_mtu(&mut self) -> anyhow::Result<Option<u16>> {
+        Ok(None)
     }
 
     async fn read_next_firmware_version(&mut self) -> anyhow::Result<Vec<u8>> {
-        let data = self
-            .read_char(FIRMWARE_SERVICE_UUID, NEXT_VERSION_CHAR_UUID)
-            .await?;
-        Ok(data)
+        gatt_protocol::read_next_version(self).await
     }
 
     async fn mark_booted(&mut self) -> anyhow::Result<()> {
-        // Trigger DFU process
-        self.write_char(FIRMWARE_SERVICE_UUID, CONTROL_CHAR_UUID, &[3])
-            .await
+        gatt_protocol::control(self, &[CONTROL_BOOTED]).await
     }
 
     async fn start_firmware_update(&mut self, version: &[u8]) -> Result<(), anyhow::Error> {
-        // Write the version we're updating
-        self.write_char(FIRMWARE_SERVICE_UUID, NEXT_VERSION_CHAR_UUID, version)
-            .await?;
-
-        // Trigger DFU process
-        self.write_char(FIRMWARE_SERVICE_UUID, CONTROL_CHAR_UUID, &[1])
-            .await?;
+        // Write the version we're updating and trigger DFU process
+        gatt_protocol::start_update(self, version).await?;
+        self.recoveries = 0;
+        self.rejected = None;
 
         // Wait until firmware offset is reset
-        while self.read_firmware_offset().await? != 0 {
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        self.wait_for_offset(0).await?;
+
+        // Negotiate compression, devices not supporting it will reject the command
+        self.compressed = false;
+        if let Some(compression) = self.compression {
+            match self
+                .write_char(
+                    FIRMWARE_SERVICE_UUID,
+                    CONTROL_CHAR_UUID,
+                    &[4, compression.id()],
+                )
+                .await
+            {
+                Ok(()) => {
+                    log::info!("Using {:?} compressed transfer", compression);
+                    self.compressed = true;
+                }
+                Err(e) => {
+                    log::info!(
+                        "Compression not supported by device, using raw transfer: {}",
+                        e
+                    );
+                }
+            }
         }
         Ok(())
     }
@@ -88,41 +657,375 @@ impl GattBoard {
     ) -> Result<(), anyhow::Error> {
         // Retrieve desired MTU size
         if self.mtu.is_none() {
-            let mtu = self.read_mtu().await?;
-            self.mtu.replace(mtu);
+            let mut mtu = self.read_mtu().await?;
+            if let Some(chunk_size) = self.chunk_size {
+                if chunk_size > mtu {
+                    log::warn!(
+                        "Chunk size {} exceeds the device MTU, using {}",
+                        chunk_size,
+                        mtu
+                    );
+                }
+                mtu = core::cmp::min(mtu, chunk_size);
+            }
+            if mtu == 0 {
+                return Err(anyhow::anyhow!("invalid chunk size 0"));
+            }
+            let aligned = aligned_chunk_size(mtu as usize) as u8;
+            if aligned != mtu {
+                log::info!(
+                    "Using chunk size {} instead of {}, to divide the firmware blocks",
+                    aligned,
+                    mtu
+                );
+            }
+            self.mtu.replace(aligned);
         }
 
         let mtu = self.mtu.unwrap() as usize;
+        if self.compressed {
+            return self.write_compressed_firmware(offset, firmware, mtu).await;
+        }
+
+        if self.firmware_write_type.is_none() {
+            let write_type = self.detect_firmware_write_type().await?;
+            self.firmware_write_type.replace(write_type);
+        }
+        let write_type = self.firmware_write_type.unwrap();
+
+        if self.adaptive && self.adaptive_chunk.is_none() {
+            self.adaptive_chunk.replace(AdaptiveChunk::new(mtu));
+        }
+
         let mut buf = [0; u8::MAX as usize];
-        for chunk in firmware.chunks(mtu) {
-            buf[0..chunk.len()].copy_from_slice(chunk);
-            if chunk.len() < mtu {
-                buf[chunk.len()..mtu].fill(0);
+        let mut pos = 0;
+        let mut confirmed = offset;
+        while pos < firmware.len() {
+            let size = self
+                .adaptive_chunk
+                .as_ref()
+                .map(|a| chunk_size_at(offset, a.size()))
+                .unwrap_or(mtu);
+            fill_chunk(&mut buf, &firmware[pos..], size);
+            let previous = offset;
+            pos += size;
+            offset += size as u32;
+            let last = pos >= firmware.len();
+
+            let started = Instant::now();
+            let result = async {
+                self.write_char_with(
+                    FIRMWARE_SERVICE_UUID,
+                    FIRMWARE_CHAR_UUID,
+                    &buf[0..size],
+                    write_type,
+                )
+                .await?;
+                log::debug!("Write {} bytes at offset {}", size, previous);
+                if offset / 4096 != previous / 4096 {
+                    self.report_progress(offset).await;
+                }
+
+                // Wait until firmware offset is incremented. With response, wait once the
+                // pipeline is full until the device confirmed the oldest chunk in flight.
+                // Without response, only check periodically. Always check at the end of the
+                // block.
+                let window = (self.pipeline * size) as u32;
+                if last {
+                    self.wait_for_offset(offset).await?;
+                } else if write_type == WriteType::WithResponse {
+                    if offset - confirmed >= window {
+                        let oldest = offset + size as u32 - window;
+                        confirmed = self.wait_for_offset_in(oldest..=offset).await?;
+                    }
+                } else if offset / 4096 != previous / 4096 {
+                    self.wait_for_offset(offset).await?;
+                }
+                Ok::<_, anyhow::Error>(())
+            }
+            .await;
+            if let Some(adaptive) = self.adaptive_chunk.as_mut() {
+                match &result {
+                    Ok(()) => adaptive.written(size, started),
+                    Err(_) => adaptive.failed(),
+                }
             }
-            self.write_char(FIRMWARE_SERVICE_UUID, FIRMWARE_CHAR_UUID, &buf[0..mtu])
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Write firmware, reconnecting and continuing from the offset reported by the device if
+    /// the device disconnects during the transfer, as long as recoveries are left.
+    async fn write_firmware_with_recovery(
+        &mut self,
+        offset: u32,
+        firmware: &[u8],
+    ) -> anyhow::Result<()> {
+        let end = offset + firmware.len() as u32;
+        let mut pos = offset;
+        loop {
+            let error = match self
+                .write_firmware(pos, &firmware[(pos - offset) as usize..])
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            // Compressed transfers can not be continued at an arbitrary offset
+            if self.compressed
+                || self.recoveries >= self.max_recoveries
+                || self.is_connected().await
+            {
+                return Err(error);
+            }
+
+            self.recoveries += 1;
+            log::warn!(
+                "{}: disconnected at offset {}, reconnecting ({} of {}): {}",
+                self.target,
+                pos,
+                self.recoveries,
+                self.max_recoveries,
+                error
+            );
+            self.offsets = None;
+            self.board = None;
+            let reported = self.read_firmware_offset().await?;
+            if reported >= end {
+                return Ok(());
+            } else if reported < offset {
+                return Err(error.context(format!(
+                    "device reported offset {} after reconnecting, expected at least {}",
+                    reported, offset
+                )));
+            }
+            log::info!(
+                "{}: continuing transfer at offset {}",
+                self.target,
+                reported
+            );
+            pos = reported;
+        }
+    }
+
+    async fn is_connected(&self) -> bool {
+        match &self.board {
+            Some(board) => board.is_connected().await.unwrap_or(false),
+            None => false,
+        }
+    }
+
+    async fn detect_firmware_write_type(&mut self) -> anyhow::Result<WriteType> {
+        if self.write_without_response {
+            let (_, c) = self
+                .find_char(FIRMWARE_SERVICE_UUID, FIRMWARE_CHAR_UUID)
                 .await?;
-            log::debug!("Write {} bytes at offset {}", mtu, offset);
-            offset += mtu as u32;
-            if offset % 4096 == 0 {
-                println!("{} bytes written", offset)
+            if let Some(c) = c {
+                if c.properties.contains(CharPropFlags::WRITE_WITHOUT_RESPONSE) {
+                    log::info!("Writing firmware without response");
+                    return Ok(WriteType::WithoutResponse);
+                }
             }
+            log::info!("Write without response not supported by device, writing with response");
+        }
+        Ok(WriteType::WithResponse)
+    }
 
-            // Wait until firmware offset is incremented
-            while self.read_firmware_offset().await? != offset {
-                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    async fn write_compressed_firmware(
+        &mut self,
+        offset: u32,
+        firmware: &[u8],
+        mtu: usize,
+    ) -> Result<(), anyhow::Error> {
+        // Pad the block to the MTU, so that offsets match the raw transfer
+        let mut block = firmware.to_vec();
+        block.resize(((firmware.len() + mtu - 1) / mtu) * mtu, 0);
+        let compressed = self.compression.unwrap().compress(&block)?;
+
+        // Announce the size of the compressed block and the size it expands to
+        let mut header = vec![5];
+        header.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(block.len() as u32).to_le_bytes());
+        self.write_char(FIRMWARE_SERVICE_UUID, CONTROL_CHAR_UUID, &header)
+            .await?;
+
+        for chunk in compressed.chunks(mtu) {
+            self.write_char(FIRMWARE_SERVICE_UUID, FIRMWARE_CHAR_UUID, chunk)
+                .await?;
+        }
+        log::debug!(
+            "Write {} bytes ({} compressed) at offset {}",
+            block.len(),
+            compressed.len(),
+            offset
+        );
+
+        let next = offset + block.len() as u32;
+        if next / 4096 != offset / 4096 {
+            self.report_progress(next).await;
+        }
+
+        // Wait until the device has decompressed and written the block
+        self.wait_for_offset(next).await
+    }
+
+    /// Log the transfer progress along with the signal strength of the connection, warning
+    /// when it drops below the threshold. Progress is logged rather than printed, as the
+    /// output may be a stream of events.
+    async fn report_progress(&mut self, offset: u32) {
+        // Backends only report the RSSI when they have received it recently
+        let rssi = match &self.board {
+            Some(board) => board.properties().await.ok().flatten().and_then(|p| p.rssi),
+            None => None,
+        };
+        match rssi {
+            Some(rssi) => {
+                log::info!(
+                    "{}: {} bytes written (RSSI {} dBm)",
+                    self.target,
+                    offset,
+                    rssi
+                );
+                let was_low = self
+                    .last_rssi
+                    .map(|last| last < self.rssi_threshold)
+                    .unwrap_or(false);
+                if rssi < self.rssi_threshold && !was_low {
+                    log::warn!(
+                        "{}: weak signal ({} dBm), the transfer may be slow or interrupted",
+                        self.target,
+                        rssi
+                    );
+                }
+                self.last_rssi.replace(rssi);
             }
+            None => log::info!("{}: {} bytes written", self.target, offset),
         }
+    }
+
+    /// Wait until the device reports the expected firmware offset, using notifications if
+    /// the device supports it and polling otherwise.
+    async fn wait_for_offset(&mut self, expected: u32) -> anyhow::Result<()> {
+        self.wait_for_offset_in(expected..=expected).await?;
         Ok(())
     }
 
-    async fn swap_firmware(&mut self) -> Result<(), anyhow::Error> {
-        // Write signal that DFU process is done and should be applied
-        log::info!("DFU process done, setting reset");
+    /// Wait until the device reports an offset within the range, returning the offset.
+    async fn wait_for_offset_in(&mut self, expected: RangeInclusive<u32>) -> anyhow::Result<u32> {
+        if self.offsets.is_none() {
+            let offsets = self.subscribe_offset().await?;
+            self.offsets.replace(offsets);
+        }
+
+        if let Some(mut offsets) = self.offsets.as_mut().and_then(|o| o.take()) {
+            let offset = loop {
+                // Notifications may be missed, so check the offset regularly as well
+                match tokio::time::timeout(Duration::from_secs(1), offsets.next()).await {
+                    Ok(Some(n)) if n.uuid == OFFSET_CHAR_UUID && n.value.len() >= 4 => {
+                        let offset =
+                            u32::from_le_bytes([n.value[0], n.value[1], n.value[2], n.value[3]]);
+                        if expected.contains(&offset) {
+                            break offset;
+                        }
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        log::debug!("Offset notifications ended, falling back to polling");
+                        self.offsets = None;
+                        return self.poll_offset(expected).await;
+                    }
+                    Err(_) => match self.read_firmware_offset().await {
+                        Ok(offset) if expected.contains(&offset) => break offset,
+                        Ok(_) => {}
+                        Err(e) => {
+                            // Subscribe again on the next attempt
+                            self.offsets = None;
+                            return Err(e);
+                        }
+                    },
+                }
+            };
+            if let Some(o) = self.offsets.as_mut() {
+                o.replace(offsets);
+            }
+            Ok(offset)
+        } else {
+            self.poll_offset(expected).await
+        }
+    }
 
-        self.write_char(FIRMWARE_SERVICE_UUID, CONTROL_CHAR_UUID, &[2])
+    async fn poll_offset(&mut self, expected: RangeInclusive<u32>) -> anyhow::Result<u32> {
+        loop {
+            let offset = self.read_firmware_offset().await?;
+            if expected.contains(&offset) {
+                return Ok(offset);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn subscribe_offset(&mut self) -> anyhow::Result<Option<Notifications>> {
+        let (device, c) = self
+            .find_char(FIRMWARE_SERVICE_UUID, OFFSET_CHAR_UUID)
             .await?;
+        match c {
+            Some(c) if c.properties.contains(CharPropFlags::NOTIFY) => {
+                device.subscribe(&c).await?;
+                log::debug!("Subscribed to offset notifications");
+                Ok(Some(device.notifications().await?))
+            }
+            _ => {
+                log::debug!("Offset notifications not supported, polling offset");
+                Ok(None)
+            }
+        }
+    }
 
-        Ok(())
+    /// Read back `len` bytes of firmware starting at `offset`.
+    pub async fn read_firmware(&mut self, mut offset: u32, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut firmware = Vec::with_capacity(len);
+        while firmware.len() < len {
+            // Select the read position, the firmware characteristic then returns data from there
+            let mut command = vec![6];
+            command.extend_from_slice(&offset.to_le_bytes());
+            self.write_char(FIRMWARE_SERVICE_UUID, CONTROL_CHAR_UUID, &command)
+                .await?;
+            let data = self
+                .read_char(FIRMWARE_SERVICE_UUID, FIRMWARE_CHAR_UUID)
+                .await?;
+            if data.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "device returned no data at offset {}",
+                    offset
+                ));
+            }
+            log::debug!("Read {} bytes at offset {}", data.len(), offset);
+            offset += data.len() as u32;
+            firmware.extend_from_slice(&data);
+        }
+        firmware.truncate(len);
+        Ok(firmware)
+    }
+
+    /// Compare the checksum the device computes over the written firmware with the checksum
+    /// the firmware is swapped with, so that firmware corrupted in flash is not swapped to.
+    /// Devices without the checksum characteristic are not verified.
+    async fn verify_checksum(&mut self, expected: &[u8]) -> anyhow::Result<()> {
+        let result = gatt_protocol::verify_checksum(self, expected).await;
+        if let Err(e) = &result {
+            if e.is::<ChecksumMismatch>() {
+                let version = self.read_next_firmware_version().await?;
+                self.rejected.replace(version);
+            }
+        }
+        result
+    }
+
+    async fn swap_firmware(&mut self) -> Result<(), anyhow::Error> {
+        // Write signal that DFU process is done and should be applied
+        log::info!("DFU process done, setting reset");
+        gatt_protocol::control(self, &[CONTROL_SWAP]).await
     }
 
     async fn read_char(&mut self, service: uuid::Uuid, c: uuid::Uuid) -> anyhow::Result<Vec<u8>> {
@@ -135,15 +1038,39 @@ impl GattBoard {
         }
     }
 
+    /// Read a characteristic, or none if the device does not expose it.
+    async fn read_optional_char(
+        &mut self,
+        service: uuid::Uuid,
+        c: uuid::Uuid,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let (device, c) = self.find_char(service, c).await?;
+        match c {
+            Some(c) => Ok(Some(device.read(&c).await?)),
+            None => Ok(None),
+        }
+    }
+
     async fn write_char(
         &mut self,
         service: uuid::Uuid,
         c: uuid::Uuid,
         value: &[u8],
+    ) -> anyhow::Result<()> {
+        self.write_char_with(service, c, value, WriteType::WithResponse)
+            .await
+    }
+
+    async fn write_char_with(
+        &mut self,
+        service: uuid::Uuid,
+        c: uuid::Uuid,
+        value: &[u8],
+        write_type: WriteType,
     ) -> anyhow::Result<()> {
         let (device, c) = self.find_char(service, c).await?;
         if let Some(c) = c {
-            device.write(&c, value, WriteType::WithResponse).await?;
+            device.write(&c, value, write_type).await?;
             Ok(())
         } else {
             Err(anyhow::anyhow!("unable to locate characteristic"))
@@ -170,52 +1097,507 @@ impl GattBoard {
 
     async fn connect(&mut self) -> anyhow::Result<&mut Peripheral> {
         if self.board.is_none() {
+            let started = tokio::time::Instant::now();
+            let mut connecting = None;
+            let mut attempts = 0;
+            if let (Some(phy), false) = (self.phy, self.phy_selected) {
+                match self.select_phy(phy).await {
+                    Ok(index) => self.phy_adapter = Some(index),
+                    Err(e) => log::warn!("Unable to select {:?} PHY: {}", phy, e),
+                }
+                self.phy_selected = true;
+            }
+            self.start_searching().await?;
             loop {
                 for device in self.adapter.peripherals().await? {
                     if let Some(p) = device.properties().await? {
-                        if p.address == self.address {
-                            // Make sure we get a fresh start
-                            let _ = device.disconnect().await;
-                            sleep(Duration::from_secs(2)).await;
+                        if self.target.matches(&device, &p) {
+                            if !matches!(self.target, Target::Address(_)) {
+                                log::info!("Found {} with address {}", self.target, p.address);
+                            }
+                            self.stop_searching().await;
+                            // Make sure we get a fresh start. WinRT closes the connection once
+                            // the peripheral is no longer used, and drops its services when
+                            // disconnecting explicitly.
+                            if cfg!(not(target_os = "windows")) {
+                                let _ = device.disconnect().await;
+                                self.retry.wait(1).await;
+                            }
                             match device.is_connected().await {
                                 Ok(false) => {
                                     log::info!("Connecting...");
+                                    let connecting = *connecting.get_or_insert_with(Instant::now);
                                     loop {
-                                        match device.connect().await {
+                                        attempts += 1;
+                                        match self.connect_attempt(&device, connecting).await {
                                             Ok(()) => break,
                                             Err(err) => {
                                                 log::error!("Connect error: {}", &err);
+                                                self.check_retries(attempts, connecting, err)?;
+                                                self.retry.wait(attempts).await;
                                             }
                                         }
                                     }
                                     log::info!("Connected!");
-                                    device.discover_services().await?;
-                                    self.board.replace(device);
-                                    return Ok(self.board.as_mut().unwrap());
+                                    discover_services(&device, &self.retry).await?;
+                                    return Ok(self.connected(device));
                                 }
                                 Ok(true) => {
                                     log::info!("Connected!");
-                                    self.board.replace(device);
-                                    return Ok(self.board.as_mut().unwrap());
+                                    // Windows keeps paired devices connected without the
+                                    // services being discovered
+                                    if device.services().is_empty() {
+                                        discover_services(&device, &self.retry).await?;
+                                    }
+                                    return Ok(self.connected(device));
                                 }
                                 Err(e) => {
                                     log::info!("Error checking connection, retrying: {:?}", e);
+                                    attempts += 1;
+                                    let connecting = *connecting.get_or_insert_with(Instant::now);
+                                    self.check_retries(attempts, connecting, e.into())?;
+                                    self.retry.wait(attempts).await;
                                 }
                             }
                         }
                     }
                 }
-                sleep(Duration::from_secs(2)).await;
+                if let Some(timeout) = self.scan_timeout {
+                    if started.elapsed() >= timeout {
+                        self.stop_searching().await;
+                        return Err(anyhow::anyhow!(
+                            "device {} not found within {:?}",
+                            self.target,
+                            timeout
+                        )
+                        .context(Failure::DeviceNotFound));
+                    }
+                }
+                // WinRT only reports peripherals seen while scanning
+                if cfg!(target_os = "windows") && self.discovery.is_none() {
+                    log::info!("Scanning for {}", self.target);
+                    self.discovery.replace(Discovery::new(self.adapter.clone()));
+                    self.start_searching().await?;
+                }
+                self.retry.wait(1).await;
             }
         }
         Ok(self.board.as_mut().unwrap())
     }
+
+    /// Select the PHYs of the adapter, so that connections use the preferred one if the
+    /// device supports it, returning the index of the adapter. The PHYs previously selected
+    /// are restored by [`restore_phys`] once the last board preferring a PHY is dropped.
+    #[cfg(target_os = "linux")]
+    async fn select_phy(&self, phy: Phy) -> anyhow::Result<String> {
+        // The adapter is described like `hci0 (usb:v1D6Bp0246d0537)`
+        let info = self.adapter.adapter_info().await?;
+        let index = info
+            .split_whitespace()
+            .next()
+            .and_then(|name| name.strip_prefix("hci"))
+            .ok_or_else(|| anyhow::anyhow!("unknown adapter '{}'", info))?
+            .to_string();
+        let output = btmgmt(&index, &[]).await?;
+        let current: Vec<String> = output
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("Selected phys:"))
+            .ok_or_else(|| anyhow::anyhow!("unable to read the selected PHYs"))?
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        let previous = {
+            let mut selected = SELECTED_PHYS.lock().unwrap();
+            let (previous, boards) = selected
+                .entry(index.clone())
+                .or_insert_with(|| (current, 0));
+            *boards += 1;
+            previous.clone()
+        };
+        // BR/EDR PHYs are kept as they were, as some of them can not be deselected
+        let phys: Vec<&str> = previous
+            .iter()
+            .map(String::as_str)
+            .filter(|p| !p.starts_with("LE"))
+            .chain(phy.btmgmt_phys().iter().copied())
+            .collect();
+        log::warn!(
+            "Selecting {:?} PHY for all connections of adapter hci{} until the update is done",
+            phy,
+            index
+        );
+        if let Err(e) = btmgmt(&index, &phys).await {
+            restore_phys(&index);
+            return Err(e);
+        }
+        Ok(index)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn select_phy(&self, _: Phy) -> anyhow::Result<String> {
+        Err(anyhow::anyhow!("not supported on this platform"))
+    }
+
+    /// Start discovering devices while looking for the device, if discovery is enabled.
+    async fn start_searching(&mut self) -> anyhow::Result<()> {
+        if let Some(discovery) = &self.discovery {
+            if !self.searching {
+                discovery.start().await?;
+                self.searching = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop discovering devices once the device has been found, or is no longer looked for.
+    async fn stop_searching(&mut self) {
+        if let Some(discovery) = &self.discovery {
+            if self.searching {
+                self.searching = false;
+                if let Err(e) = discovery.stop().await {
+                    log::warn!("Error stopping discovery: {}", e);
+                }
+            }
+        }
+    }
+
+    fn connected(&mut self, device: Peripheral) -> &mut Peripheral {
+        // Reconnecting after swapping firmware is expected, anything else is counted
+        if self.connected && !self.updated {
+            metrics::BLE_RECONNECTS.inc();
+            if let Some(rssi) = self.last_rssi {
+                log::warn!(
+                    "{}: reconnected, last known signal strength was {} dBm",
+                    self.target,
+                    rssi
+                );
+            }
+        }
+        self.connected = true;
+        self.board.insert(device)
+    }
+
+    async fn connect_attempt(
+        &self,
+        device: &Peripheral,
+        connecting: Instant,
+    ) -> anyhow::Result<()> {
+        if let Some(timeout) = self.connect_timeout {
+            let remaining = timeout.saturating_sub(connecting.elapsed());
+            tokio::time::timeout(remaining, device.connect())
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("connect timed out after {:?}", timeout)
+                        .context(Failure::ConnectTimeout)
+                })??;
+        } else {
+            device.connect().await?;
+        }
+        Ok(())
+    }
+
+    fn check_retries(
+        &self,
+        attempts: u32,
+        connecting: Instant,
+        error: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        if let Some(timeout) = self.connect_timeout {
+            if connecting.elapsed() >= timeout {
+                return Err(error
+                    .context(format!(
+                        "unable to connect to {} within {:?}",
+                        self.target, timeout
+                    ))
+                    .context(Failure::ConnectTimeout));
+            }
+        }
+        if self.retry.retry(attempts) {
+            Ok(())
+        } else {
+            Err(error.context(format!(
+                "unable to connect to {} after {} attempts",
+                self.target, attempts
+            )))
+        }
+    }
+}
+
+impl Drop for GattBoard {
+    fn drop(&mut self) {
+        if let Some(index) = self.phy_adapter.take() {
+            restore_phys(&index);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+lazy_static::lazy_static! {
+    /// PHYs selected on each adapter before a board selected its preferred PHY, and the number
+    /// of boards using the adapter with the preferred PHY.
+    static ref SELECTED_PHYS: std::sync::Mutex<
+        std::collections::HashMap<String, (Vec<String>, usize)>,
+    > = Default::default();
+}
+
+/// Run `btmgmt phy` on the adapter, selecting the given PHYs unless empty, and return its
+/// output.
+#[cfg(target_os = "linux")]
+async fn btmgmt(index: &str, phys: &[&str]) -> anyhow::Result<String> {
+    let output = tokio::process::Command::new("btmgmt")
+        .args(["--index", index, "phy"])
+        .args(phys)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "btmgmt failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Restore the PHYs of the adapter once no board prefers a PHY on it anymore. This runs when
+/// boards are dropped, so `btmgmt` is run without the async runtime.
+#[cfg(target_os = "linux")]
+fn restore_phys(index: &str) {
+    let previous = {
+        let mut selected = SELECTED_PHYS.lock().unwrap();
+        let boards = match selected.get_mut(index) {
+            Some((_, boards)) => boards,
+            None => return,
+        };
+        *boards -= 1;
+        if *boards > 0 {
+            return;
+        }
+        selected.remove(index)
+    };
+    if let Some((previous, _)) = previous {
+        match std::process::Command::new("btmgmt")
+            .args(["--index", index, "phy"])
+            .args(&previous)
+            .output()
+        {
+            Ok(output) if output.status.success() => {
+                log::info!("Restored the PHYs of adapter hci{}", index)
+            }
+            Ok(output) => log::warn!(
+                "Unable to restore the PHYs of adapter hci{}: {}",
+                index,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) => log::warn!("Unable to restore the PHYs of adapter hci{}: {}", index, e),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn restore_phys(_: &str) {}
+
+/// The chunk size to write at `offset`, reduced until the offset is aligned to it, so that
+/// the chunks of a block end at the end of the block when the chunk size grows.
+fn chunk_size_at(offset: u32, size: usize) -> usize {
+    let mut size = size;
+    while size > 1 && offset % size as u32 != 0 {
+        size /= 2;
+    }
+    size
+}
+
+/// Copy the start of the firmware into a chunk of `size` bytes, padding it with zeros at the
+/// end of the firmware as devices expect full chunks.
+fn fill_chunk(buf: &mut [u8], firmware: &[u8], size: usize) {
+    let len = core::cmp::min(size, firmware.len());
+    buf[..len].copy_from_slice(&firmware[..len]);
+    buf[len..size].fill(0);
+}
+
+/// Discover the services of a connected device, retrying according to the policy until the
+/// firmware service is found as long as discovery attempts are left.
+async fn discover_services(device: &Peripheral, retry: &RetryPolicy) -> anyhow::Result<()> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        device.discover_services().await?;
+        if attempts >= SERVICE_DISCOVERY_ATTEMPTS
+            || device
+                .services()
+                .iter()
+                .any(|s| s.uuid == FIRMWARE_SERVICE_UUID)
+        {
+            return Ok(());
+        }
+        log::debug!("Firmware service not discovered yet, retrying");
+        retry.wait(attempts).await;
+    }
+}
+
+/// Parse a device address written with `:` or `-` delimiters, or without any.
+fn parse_address(address: &str) -> Option<BDAddr> {
+    let address = address.replace('-', ":");
+    BDAddr::from_str_delim(&address)
+        .or_else(|_| BDAddr::from_str_no_delim(&address))
+        .ok()
+}
+
+/// Discovery of devices advertising the firmware service on an adapter, shared by the boards
+/// using the adapter. The adapter scans while any of the boards is looking for its device.
+#[derive(Clone)]
+pub struct Discovery {
+    adapter: Adapter,
+    searching: Arc<tokio::sync::Mutex<usize>>,
+}
+
+impl Discovery {
+    pub fn new(adapter: Adapter) -> Self {
+        Self {
+            adapter,
+            searching: Arc::new(tokio::sync::Mutex::new(0)),
+        }
+    }
+
+    pub fn adapter(&self) -> &Adapter {
+        &self.adapter
+    }
+
+    /// Start scanning unless already scanning for another board.
+    pub async fn start(&self) -> anyhow::Result<()> {
+        let mut searching = self.searching.lock().await;
+        if *searching == 0 {
+            GattBoard::start_discovery(&self.adapter).await?;
+        }
+        *searching += 1;
+        Ok(())
+    }
+
+    /// Stop scanning unless still scanning for another board.
+    pub async fn stop(&self) -> anyhow::Result<()> {
+        let mut searching = self.searching.lock().await;
+        *searching = searching.saturating_sub(1);
+        if *searching == 0 {
+            self.adapter.stop_scan().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Distributes concurrent updates across several adapters, with at most a given number of
+/// connections per adapter at a time.
+pub struct AdapterPool {
+    adapters: Vec<(Discovery, Arc<Semaphore>)>,
+}
+
+impl AdapterPool {
+    /// Use the adapters with at most `limit` connections each, or any number if none.
+    pub fn new(adapters: Vec<Adapter>, limit: Option<usize>) -> Self {
+        let limit = limit.unwrap_or(Semaphore::MAX_PERMITS).max(1);
+        Self {
+            adapters: adapters
+                .into_iter()
+                .map(|adapter| (Discovery::new(adapter), Arc::new(Semaphore::new(limit))))
+                .collect(),
+        }
+    }
+
+    /// The adapters, with the discovery of devices on them.
+    pub fn adapters(&self) -> impl Iterator<Item = &Discovery> {
+        self.adapters.iter().map(|(adapter, _)| adapter)
+    }
+
+    /// Wait for a free connection, preferring the adapter with the most free connections.
+    /// The connection is released when the permit is dropped.
+    pub async fn acquire(&self) -> (Discovery, OwnedSemaphorePermit) {
+        let mut free: Vec<_> = self.adapters.iter().collect();
+        free.sort_by_key(|(_, slots)| core::cmp::Reverse(slots.available_permits()));
+        for (adapter, slots) in free {
+            if let Ok(permit) = slots.clone().try_acquire_owned() {
+                return (adapter.clone(), permit);
+            }
+        }
+
+        let waiting = self.adapters.iter().map(|(adapter, slots)| {
+            Box::pin(async move { (adapter.clone(), slots.clone().acquire_owned().await) })
+        });
+        let ((adapter, permit), _, _) = futures::future::select_all(waiting).await;
+        // The semaphores are never closed
+        (adapter, permit.unwrap())
+    }
+}
+
+/// Read the ATT MTU of a characteristic of a connected device from BlueZ.
+#[cfg(target_os = "linux")]
+fn bluez_att_mtu(address: BDAddr, characteristic: uuid::Uuid) -> anyhow::Result<Option<u16>> {
+    use dbus::blocking::stdintf::org_freedesktop_dbus::ObjectManager;
+    use dbus::blocking::Connection;
+
+    let connection = Connection::new_system()?;
+    let proxy = connection.with_proxy("org.bluez", "/", Duration::from_secs(5));
+    let device = format!("/dev_{}/", address.to_string().replace(':', "_"));
+    let uuid = characteristic.to_string();
+    for (path, interfaces) in proxy.get_managed_objects()? {
+        if !path.contains(&device) {
+            continue;
+        }
+        if let Some(properties) = interfaces.get("org.bluez.GattCharacteristic1") {
+            if properties.get("UUID").and_then(|v| v.0.as_str()) == Some(uuid.as_str()) {
+                return Ok(properties
+                    .get("MTU")
+                    .and_then(|v| v.0.as_u64())
+                    .map(|mtu| mtu as u16));
+            }
+        }
+    }
+    Ok(None)
+}
+
+pub(crate) fn matches_pattern(pattern: &[u8], value: &[u8]) -> bool {
+    match (pattern.first(), value.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            matches_pattern(&pattern[1..], value)
+                || (!value.is_empty() && matches_pattern(pattern, &value[1..]))
+        }
+        (Some(b'?'), Some(_)) => matches_pattern(&pattern[1..], &value[1..]),
+        (Some(p), Some(v)) if p.eq_ignore_ascii_case(v) => {
+            matches_pattern(&pattern[1..], &value[1..])
+        }
+        _ => false,
+    }
+}
+
+impl GattTransport for GattBoard {
+    type ReadFuture<'m> = impl Future<Output = anyhow::Result<Option<Vec<u8>>>> + 'm
+    where
+        Self: 'm;
+
+    fn read_characteristic(
+        &mut self,
+        service: uuid::Uuid,
+        characteristic: uuid::Uuid,
+    ) -> Self::ReadFuture<'_> {
+        async move { self.read_optional_char(service, characteristic).await }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = anyhow::Result<()>> + 'm
+    where
+        Self: 'm;
+
+    fn write_characteristic<'m>(
+        &'m mut self,
+        service: uuid::Uuid,
+        characteristic: uuid::Uuid,
+        value: &'m [u8],
+    ) -> Self::WriteFuture<'m> {
+        async move { self.write_char(service, characteristic, value).await }
+    }
 }
 
 impl FirmwareDevice for GattBoard {
     const MTU: usize = 4096;
     type Version = Vec<u8>;
-    type Error = anyhow::Error;
+    type Error = DfuError;
 
     type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
     where
@@ -232,6 +1614,14 @@ impl FirmwareDevice for GattBoard {
                 next,
                 offset
             );
+            // Report no update in progress, so that the firmware is written again
+            if self.rejected.as_deref() == Some(&next[..]) {
+                return Ok(FirmwareStatus {
+                    current_version: version,
+                    next_version: None,
+                    next_offset: 0,
+                });
+            }
             Ok(FirmwareStatus {
                 current_version: version,
                 next_version: Some(next),
@@ -255,7 +1645,7 @@ impl FirmwareDevice for GattBoard {
         Self: 'm;
 
     fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
-        async move { Ok(self.write_firmware(offset, data).await?) }
+        async move { Ok(self.write_firmware_with_recovery(offset, data).await?) }
     }
 
     type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
@@ -263,11 +1653,15 @@ impl FirmwareDevice for GattBoard {
     where
         Self: 'm;
 
-    fn update<'m>(&'m mut self, _: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+    fn update<'m>(&'m mut self, _: &'m [u8], checksum: &'m [u8]) -> Self::UpdateFuture<'m> {
         async move {
+            if !checksum.is_empty() {
+                self.verify_checksum(checksum).await?;
+            }
             log::debug!("Swapping firmware");
             let r = Ok(self.swap_firmware().await?);
             tokio::time::sleep(std::time::Duration::from_secs(10)).await;
+            self.offsets = None;
             if let Some(board) = self.board.take() {
                 let _ = board.disconnect().await;
             }
@@ -294,3 +1688,109 @@ impl FirmwareDevice for GattBoard {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: [u8; 6] = [0xc2, 0xd1, 0xe5, 0xa3, 0xb4, 0xf7];
+
+    fn properties(address: [u8; 6], name: Option<&str>) -> PeripheralProperties {
+        PeripheralProperties {
+            address: BDAddr::from(address),
+            local_name: name.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    /// Write the image to a simulated device in blocks like the updater, with the chunks chosen
+    /// like `write_firmware` while the adaptive chunk size grows.
+    #[tokio::test]
+    async fn adaptive_chunks_fill_blocks() {
+        use crate::{FlashConfig, FlashSimulator};
+
+        let image: Vec<u8> = (0..3 * 4096 + 100).map(|i| (i % 251) as u8).collect();
+        let config = FlashConfig {
+            slot_size: 4 * 4096,
+            erase_size: 4096,
+            write_size: 16,
+        };
+        let mut device = FlashSimulator::new(b"1", config).unwrap();
+        device.start(b"2").await.unwrap();
+
+        let mut adaptive = AdaptiveChunk::new(244);
+        assert_eq!(adaptive.sizes, vec![16, 32, 64, 128]);
+        // Pretend that every sample took as long, so that larger chunks are faster
+        let started = Instant::now() - Duration::from_secs(1);
+        let mut buf = [0; u8::MAX as usize];
+        let mut offset = 0;
+        for block in image.chunks(4096) {
+            let end = offset + block.len() as u32;
+            let mut pos = 0;
+            while pos < block.len() {
+                let size = chunk_size_at(offset, adaptive.size());
+                fill_chunk(&mut buf, &block[pos..], size);
+                device.write(offset, &buf[..size]).await.unwrap();
+                pos += size;
+                offset += size as u32;
+                adaptive.written(size, started);
+            }
+            if block.len() == 4096 {
+                assert_eq!(offset, end);
+            }
+        }
+        assert_eq!(adaptive.size(), 128);
+        assert_eq!(offset, 3 * 4096 + 128);
+        assert_eq!(&device.slot()[..image.len()], &image[..]);
+    }
+
+    #[test]
+    fn chunk_sizes_are_aligned() {
+        assert_eq!(aligned_chunk_size(244), 128);
+        assert_eq!(aligned_chunk_size(20), 16);
+        assert_eq!(aligned_chunk_size(64), 64);
+        assert_eq!(AdaptiveChunk::new(12).sizes, vec![8]);
+        assert_eq!(chunk_size_at(512, 128), 128);
+        assert_eq!(chunk_size_at(4096 + 48, 64), 16);
+    }
+
+    #[test]
+    fn parse_address_forms() {
+        let address = Some(BDAddr::from(ADDRESS));
+        assert_eq!(parse_address("c2:d1:e5:a3:b4:f7"), address);
+        assert_eq!(parse_address("C2-D1-E5-A3-B4-F7"), address);
+        assert_eq!(parse_address("c2d1e5a3b4f7"), address);
+    }
+
+    #[test]
+    fn parse_address_invalid() {
+        assert_eq!(parse_address("c2:d1:e5:a3:b4"), None);
+        assert_eq!(parse_address("c2d1e5a3b4f7aa"), None);
+        assert_eq!(parse_address("e7c3b6a8-5bd1-4b0c-8e0a-1f0c6f1f2c3d"), None);
+    }
+
+    #[test]
+    fn target_matches_address() {
+        let target = Target::Address(BDAddr::from(ADDRESS));
+        assert!(target.matches_properties("hci0/dev_x", &properties(ADDRESS, None)));
+        assert!(!target.matches_properties("hci0/dev_x", &properties([0; 6], None)));
+    }
+
+    #[test]
+    fn target_matches_id() {
+        let target = Target::Id("E7C3B6A8-5BD1-4B0C-8E0A-1F0C6F1F2C3D".to_string());
+        let p = properties([0; 6], None);
+        assert!(target.matches_properties("e7c3b6a8-5bd1-4b0c-8e0a-1f0c6f1f2c3d", &p));
+        assert!(!target.matches_properties("e7c3b6a8-5bd1-4b0c-8e0a-000000000000", &p));
+    }
+
+    #[test]
+    fn target_matches_name() {
+        let target = Target::Name("dfu-??-*".to_string());
+        assert!(target.matches_properties("", &properties([0; 6], Some("DFU-01-sensor"))));
+        assert!(target.matches_properties("", &properties([0; 6], Some("dfu-01-"))));
+        assert!(!target.matches_properties("", &properties([0; 6], Some("dfu-1-sensor"))));
+        assert!(!target.matches_properties("", &properties([0; 6], Some("other"))));
+        assert!(!target.matches_properties("", &properties([0; 6], None)));
+    }
+}
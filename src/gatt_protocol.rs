@@ -0,0 +1,306 @@
+use crate::checksum::hex;
+use crate::runtime::sleep;
+use crate::{ChecksumMismatch, DfuError};
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const FIRMWARE_SERVICE_UUID: Uuid = Uuid::from_u128(0x00001000b0cd11ec871fd45ddf138840);
+
+pub const VERSION_CHAR_UUID: Uuid = Uuid::from_u128(0x00001001b0cd11ec871fd45ddf138840);
+pub const MTU_CHAR_UUID: Uuid = Uuid::from_u128(0x00001002b0cd11ec871fd45ddf138840);
+pub const CONTROL_CHAR_UUID: Uuid = Uuid::from_u128(0x00001003b0cd11ec871fd45ddf138840);
+pub const NEXT_VERSION_CHAR_UUID: Uuid = Uuid::from_u128(0x00001004b0cd11ec871fd45ddf138840);
+pub const OFFSET_CHAR_UUID: Uuid = Uuid::from_u128(0x00001005b0cd11ec871fd45ddf138840);
+pub const FIRMWARE_CHAR_UUID: Uuid = Uuid::from_u128(0x00001006b0cd11ec871fd45ddf138840);
+pub(crate) const SLOT_SIZE_CHAR_UUID: Uuid = Uuid::from_u128(0x00001007b0cd11ec871fd45ddf138840);
+/// Checksum of the firmware written to the slot, computed by the device when read with the
+/// algorithm it verifies the swap checksum with.
+pub const CHECKSUM_CHAR_UUID: Uuid = Uuid::from_u128(0x00001008b0cd11ec871fd45ddf138840);
+
+/// Commands written to the control characteristic.
+pub(crate) const CONTROL_START: u8 = 1;
+pub(crate) const CONTROL_SWAP: u8 = 2;
+pub(crate) const CONTROL_BOOTED: u8 = 3;
+
+/// Chunk size of devices without the MTU characteristic, fitting the default ATT MTU.
+const DEFAULT_CHUNK_SIZE: u8 = 16;
+
+/// The largest power of two up to `max`, which divides the blocks of firmware written by the
+/// updater. Chunks of this size never need padding before the end of the firmware, which
+/// would write the padding into the middle of the image and move the device offset past the
+/// block.
+pub(crate) fn aligned_chunk_size(max: usize) -> usize {
+    match max {
+        0 => 0,
+        max => 1 << (usize::BITS - 1 - max.leading_zeros()),
+    }
+}
+
+/// Access to the characteristics of a device exposing the firmware service, such as
+/// btleplug on the host or Web Bluetooth in the browser.
+pub trait GattTransport {
+    type ReadFuture<'m>: Future<Output = anyhow::Result<Option<Vec<u8>>>> + 'm
+    where
+        Self: 'm;
+    /// Read the characteristic, or none if the device does not expose it.
+    fn read_characteristic(&mut self, service: Uuid, characteristic: Uuid) -> Self::ReadFuture<'_>;
+
+    type WriteFuture<'m>: Future<Output = anyhow::Result<()>> + 'm
+    where
+        Self: 'm;
+    /// Write the value to the characteristic, waiting for the device to acknowledge it.
+    fn write_characteristic<'m>(
+        &'m mut self,
+        service: Uuid,
+        characteristic: Uuid,
+        value: &'m [u8],
+    ) -> Self::WriteFuture<'m>;
+}
+
+async fn read_required<T: GattTransport>(t: &mut T, c: Uuid) -> anyhow::Result<Vec<u8>> {
+    t.read_characteristic(FIRMWARE_SERVICE_UUID, c)
+        .await?
+        .ok_or_else(|| anyhow!("unable to locate characteristic"))
+}
+
+/// Read the version of the firmware running on the device.
+pub(crate) async fn read_version<T: GattTransport>(t: &mut T) -> anyhow::Result<Vec<u8>> {
+    read_required(t, VERSION_CHAR_UUID).await
+}
+
+/// Read the version of the firmware being written to the device.
+pub(crate) async fn read_next_version<T: GattTransport>(t: &mut T) -> anyhow::Result<Vec<u8>> {
+    read_required(t, NEXT_VERSION_CHAR_UUID).await
+}
+
+/// Read the offset of the next byte of firmware expected by the device.
+pub(crate) async fn read_offset<T: GattTransport>(t: &mut T) -> anyhow::Result<u32> {
+    let data = read_required(t, OFFSET_CHAR_UUID).await?;
+    match data[..] {
+        [a, b, c, d, ..] => Ok(u32::from_le_bytes([a, b, c, d])),
+        _ => Err(anyhow!("invalid firmware offset")),
+    }
+}
+
+/// Write a command to the control characteristic.
+pub(crate) async fn control<T: GattTransport>(t: &mut T, command: &[u8]) -> anyhow::Result<()> {
+    t.write_characteristic(FIRMWARE_SERVICE_UUID, CONTROL_CHAR_UUID, command)
+        .await
+}
+
+/// Tell the device which version is written next and start the update, which resets the
+/// firmware offset.
+pub(crate) async fn start_update<T: GattTransport>(
+    t: &mut T,
+    version: &[u8],
+) -> anyhow::Result<()> {
+    t.write_characteristic(FIRMWARE_SERVICE_UUID, NEXT_VERSION_CHAR_UUID, version)
+        .await?;
+    control(t, &[CONTROL_START]).await
+}
+
+/// Compare the checksum computed by the device for the written firmware with the checksum
+/// the firmware is swapped with. Devices without the checksum characteristic are not
+/// verified.
+pub(crate) async fn verify_checksum<T: GattTransport>(
+    t: &mut T,
+    expected: &[u8],
+) -> anyhow::Result<()> {
+    let checksum = match t
+        .read_characteristic(FIRMWARE_SERVICE_UUID, CHECKSUM_CHAR_UUID)
+        .await?
+    {
+        Some(checksum) => checksum,
+        None => {
+            log::debug!("Device does not report checksums, not verifying the firmware");
+            return Ok(());
+        }
+    };
+    if checksum[..] != expected[..] {
+        return Err(ChecksumMismatch {
+            expected: hex(expected),
+            actual: hex(&checksum),
+        }
+        .into());
+    }
+    log::debug!("Verified firmware checksum {}", hex(&checksum));
+    Ok(())
+}
+
+/// The state machine of the firmware service, updating a device through any GATT transport
+/// by writing chunks of firmware and polling the offset. Unlike `GattBoard`, it neither
+/// connects nor recovers from disconnects, which is left to the transport.
+///
+/// It does not depend on btleplug or Tokio, so that it can be compiled to `wasm32` and
+/// driven by Web Bluetooth, with the timers of the runtime set by `set_runtime`.
+pub struct GattProtocol<T> {
+    transport: T,
+    mtu: Option<u8>,
+    chunk_size: Option<u8>,
+    updated: bool,
+    /// Version whose written firmware did not match its checksum, to be written again from
+    /// the start.
+    rejected: Option<Vec<u8>>,
+}
+
+impl<T: GattTransport> GattProtocol<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            mtu: None,
+            chunk_size: None,
+            updated: false,
+            rejected: None,
+        }
+    }
+
+    /// Write at most `chunk_size` bytes of firmware at a time, instead of the size requested by
+    /// the device. Sizes not dividing the blocks of firmware are rounded down to a power of two.
+    pub fn with_chunk_size(mut self, chunk_size: u8) -> Self {
+        self.chunk_size.replace(chunk_size);
+        self
+    }
+
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    async fn wait_for_offset(&mut self, expected: u32) -> anyhow::Result<()> {
+        loop {
+            if read_offset(&mut self.transport).await? == expected {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// The chunk size requested by the device, limited to the configured chunk size and
+    /// rounded down to divide the firmware blocks.
+    async fn mtu(&mut self) -> anyhow::Result<u8> {
+        if let Some(mtu) = self.mtu {
+            return Ok(mtu);
+        }
+        let mtu = match self
+            .transport
+            .read_characteristic(FIRMWARE_SERVICE_UUID, MTU_CHAR_UUID)
+            .await?
+        {
+            Some(data) if !data.is_empty() => data[0],
+            _ => DEFAULT_CHUNK_SIZE,
+        };
+        let mtu = match self.chunk_size {
+            Some(chunk_size) => core::cmp::min(mtu, chunk_size),
+            None => mtu,
+        };
+        if mtu == 0 {
+            return Err(anyhow!("invalid chunk size 0"));
+        }
+        let mtu = aligned_chunk_size(mtu as usize) as u8;
+        self.mtu.replace(mtu);
+        Ok(mtu)
+    }
+}
+
+impl<T: GattTransport> FirmwareDevice for GattProtocol<T> {
+    const MTU: usize = 4096;
+    type Version = Vec<u8>;
+    type Error = DfuError;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            let version = read_version(&mut self.transport).await?;
+            let next = read_next_version(&mut self.transport).await?;
+            let offset = read_offset(&mut self.transport).await?;
+            // Report no update in progress, so that the firmware is written again
+            if self.rejected.as_deref() == Some(&next[..]) {
+                return Ok(FirmwareStatus {
+                    current_version: version,
+                    next_version: None,
+                    next_offset: 0,
+                });
+            }
+            Ok(FirmwareStatus {
+                current_version: version,
+                next_version: Some(next),
+                next_offset: offset,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            start_update(&mut self.transport, version).await?;
+            self.rejected = None;
+            Ok(self.wait_for_offset(0).await?)
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            let mtu = self.mtu().await? as usize;
+            let mut offset = offset;
+            for chunk in data.chunks(mtu) {
+                // Devices expect full chunks, only the end of the firmware is padded
+                let mut buf = chunk.to_vec();
+                buf.resize(mtu, 0);
+                self.transport
+                    .write_characteristic(FIRMWARE_SERVICE_UUID, FIRMWARE_CHAR_UUID, &buf)
+                    .await?;
+                offset += mtu as u32;
+                self.wait_for_offset(offset).await?;
+            }
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, _: &'m [u8], checksum: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            if !checksum.is_empty() {
+                if let Err(e) = verify_checksum(&mut self.transport, checksum).await {
+                    if e.is::<ChecksumMismatch>() {
+                        let version = read_next_version(&mut self.transport).await?;
+                        self.rejected.replace(version);
+                    }
+                    return Err(e.into());
+                }
+            }
+            log::info!("DFU process done, setting reset");
+            control(&mut self.transport, &[CONTROL_SWAP]).await?;
+            self.updated = true;
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            if self.updated {
+                self.updated = false;
+                control(&mut self.transport, &[CONTROL_BOOTED]).await?;
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use core::future::Future;
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use tokio::time::Duration;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+/// Messages of the `drgdfu.agent.v1.DfuAgent` service defined in `proto/agent.proto`.
+mod proto {
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct StatusRequest {}
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct StatusResponse {
+        #[prost(bytes = "vec", tag = "1")]
+        pub current_version: Vec<u8>,
+        #[prost(bytes = "vec", optional, tag = "2")]
+        pub next_version: Option<Vec<u8>>,
+        #[prost(uint32, tag = "3")]
+        pub next_offset: u32,
+    }
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct StartRequest {
+        #[prost(bytes = "vec", tag = "1")]
+        pub version: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct StartResponse {}
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct WriteRequest {
+        #[prost(uint32, tag = "1")]
+        pub offset: u32,
+        #[prost(bytes = "vec", tag = "2")]
+        pub data: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct WriteResponse {}
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct UpdateRequest {
+        #[prost(bytes = "vec", tag = "1")]
+        pub version: Vec<u8>,
+        #[prost(bytes = "vec", tag = "2")]
+        pub checksum: Vec<u8>,
+    }
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct UpdateResponse {}
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct SyncedRequest {}
+
+    #[derive(Clone, PartialEq, Eq, prost::Message)]
+    pub struct SyncedResponse {}
+}
+
+const SERVICE: &str = "/drgdfu.agent.v1.DfuAgent";
+
+/// A FirmwareDevice for agents implementing the gRPC service in `proto/agent.proto`, such as
+/// Linux based edge devices applying updates themselves.
+///
+/// TLS is used for `https` URLs, and a bearer token can be sent to authenticate with the agent.
+pub struct GrpcBoard {
+    endpoint: Endpoint,
+    tls: Option<ClientTlsConfig>,
+    token: Option<String>,
+    client: Option<tonic::client::Grpc<Channel>>,
+}
+
+impl GrpcBoard {
+    /// Connect to the agent at the given URL, such as `https://device.local:50051`.
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let endpoint = Endpoint::from_shared(url.to_string())?
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(60));
+        let tls = if url.starts_with("https://") {
+            Some(ClientTlsConfig::new())
+        } else {
+            None
+        };
+        Ok(Self {
+            endpoint,
+            tls,
+            token: None,
+            client: None,
+        })
+    }
+
+    /// Trust the given PEM encoded CA certificate in addition to the system ones.
+    pub fn with_ca_certificate(mut self, pem: &[u8]) -> Self {
+        self.tls = Some(
+            self.tls
+                .unwrap_or_default()
+                .ca_certificate(Certificate::from_pem(pem)),
+        );
+        self
+    }
+
+    /// Authenticate with the given PEM encoded client certificate and private key.
+    pub fn with_identity(mut self, cert: &[u8], key: &[u8]) -> Self {
+        self.tls = Some(
+            self.tls
+                .unwrap_or_default()
+                .identity(Identity::from_pem(cert, key)),
+        );
+        self
+    }
+
+    /// Send the given token as bearer token with each request.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    async fn call<Req, Resp>(&mut self, method: &str, request: Req) -> anyhow::Result<Resp>
+    where
+        Req: prost::Message + 'static,
+        Resp: prost::Message + Default + 'static,
+    {
+        if self.client.is_none() {
+            let mut endpoint = self.endpoint.clone();
+            if let Some(tls) = self.tls.clone() {
+                endpoint = endpoint.tls_config(tls)?;
+            }
+            self.client
+                .replace(tonic::client::Grpc::new(endpoint.connect_lazy()));
+        }
+        let client = self.client.as_mut().unwrap();
+        client.ready().await?;
+
+        let mut request = tonic::Request::new(request);
+        if let Some(token) = &self.token {
+            request
+                .metadata_mut()
+                .insert("authorization", format!("Bearer {}", token).parse()?);
+        }
+        let path = PathAndQuery::try_from(format!("{}/{}", SERVICE, method))?;
+        let response = client
+            .unary(request, path, ProstCodec::default())
+            .await
+            .map_err(|status| {
+                anyhow::anyhow!(
+                    "{} failed: {:?}: {}",
+                    method,
+                    status.code(),
+                    status.message()
+                )
+            })?;
+        Ok(response.into_inner())
+    }
+}
+
+impl FirmwareDevice for GrpcBoard {
+    const MTU: usize = 16384;
+    type Version = Vec<u8>;
+    type Error = anyhow::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            let status: proto::StatusResponse =
+                self.call("Status", proto::StatusRequest {}).await?;
+            Ok(FirmwareStatus {
+                current_version: status.current_version,
+                next_offset: status.next_offset,
+                next_version: status.next_version,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            let request = proto::StartRequest {
+                version: version.to_vec(),
+            };
+            let _: proto::StartResponse = self.call("Start", request).await?;
+            Ok(())
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            let request = proto::WriteRequest {
+                offset,
+                data: data.to_vec(),
+            };
+            let _: proto::WriteResponse = self.call("Write", request).await?;
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], checksum: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            let request = proto::UpdateRequest {
+                version: version.to_vec(),
+                checksum: checksum.to_vec(),
+            };
+            let _: proto::UpdateResponse = self.call("Update", request).await?;
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            let _: proto::SyncedResponse = self.call("Synced", proto::SyncedRequest {}).await?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::runtime::{sleep, timeout};
+use crate::Failure;
+use anyhow::anyhow;
+use embedded_update::FirmwareDevice;
+use std::time::{Duration, Instant};
+
+/// How to tell that a device came back healthy after it was updated.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthProbe {
+    /// Read the status of the device again, expecting it to run the new version.
+    Version,
+    /// Run a shell command expecting it to succeed, for instance to check a status value of
+    /// the device or wait for its heartbeat. The new version is given to the command in the
+    /// `DRGDFU_VERSION` environment variable.
+    Command(String),
+}
+
+impl core::str::FromStr for HealthProbe {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "version" => Ok(Self::Version),
+            Some(("command", command)) if !command.is_empty() => {
+                Ok(Self::Command(command.to_string()))
+            }
+            _ => Err(anyhow!(
+                "unknown health check '{}', expected 'version' or 'command:<command>'",
+                s
+            )),
+        }
+    }
+}
+
+/// A check run after a device was updated, failing the update if the device does not come
+/// back healthy within the timeout. The probe is repeated until it succeeds.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    probe: HealthProbe,
+    timeout: Duration,
+    interval: Duration,
+}
+
+impl HealthCheck {
+    pub fn new(probe: HealthProbe, timeout: Duration) -> Self {
+        Self {
+            probe,
+            timeout,
+            interval: Duration::from_secs(2),
+        }
+    }
+
+    /// Wait for the given time between failed probes.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Probe the device updated to the version until it is healthy.
+    pub async fn run<F>(&self, device: &mut F, version: &[u8]) -> anyhow::Result<()>
+    where
+        F: FirmwareDevice,
+        F::Error: core::fmt::Debug,
+    {
+        log::info!("Checking health of the device");
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let result = match timeout(remaining, self.probe(device, version)).await {
+                Some(result) => result,
+                None => Err(anyhow!("health check timed out")),
+            };
+            match result {
+                Ok(()) => {
+                    log::info!("Device is healthy");
+                    return Ok(());
+                }
+                Err(e) if Instant::now() + self.interval >= deadline => {
+                    return Err(e
+                        .context(format!(
+                            "device did not come back healthy within {:?}",
+                            self.timeout
+                        ))
+                        .context(Failure::Unhealthy));
+                }
+                Err(e) => {
+                    log::debug!("Device not healthy yet: {:?}", e);
+                    sleep(self.interval).await;
+                }
+            }
+        }
+    }
+
+    async fn probe<F>(&self, device: &mut F, version: &[u8]) -> anyhow::Result<()>
+    where
+        F: FirmwareDevice,
+        F::Error: core::fmt::Debug,
+    {
+        match &self.probe {
+            HealthProbe::Version => {
+                let status = device
+                    .status()
+                    .await
+                    .map_err(|e| anyhow!("error reading device status: {:?}", e))?;
+                if status.current_version.as_ref() != version {
+                    return Err(anyhow!(
+                        "device runs version {} instead of {}",
+                        String::from_utf8_lossy(status.current_version.as_ref()),
+                        String::from_utf8_lossy(version)
+                    ));
+                }
+                Ok(())
+            }
+            HealthProbe::Command(command) => {
+                let status = shell(command)
+                    .env("DRGDFU_VERSION", String::from_utf8_lossy(version).as_ref())
+                    .kill_on_drop(true)
+                    .status()
+                    .await?;
+                if !status.success() {
+                    return Err(anyhow!("health check command failed with {}", status));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn shell(command: &str) -> tokio::process::Command {
+    let mut shell = tokio::process::Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(target_os = "windows")]
+fn shell(command: &str) -> tokio::process::Command {
+    let mut shell = tokio::process::Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
@@ -0,0 +1,229 @@
+use crate::{
+    decode_image, is_dfuse, is_mcuboot, is_srec, is_uf2, uf2_blocks, ChecksumAlgorithm, Failure,
+    FirmwareBundle, FirmwareFileMeta, McubootInfo,
+};
+use serde::Serialize;
+
+/// Format of a firmware artifact.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FirmwareFormat {
+    /// A binary image, flashed as is.
+    Raw,
+    /// Intel HEX records.
+    Hex,
+    /// Motorola S-records.
+    Srec,
+    /// An ELF executable, such as the output of a build.
+    Elf,
+    /// An image with the header and TLV area of the MCUboot bootloader.
+    Mcuboot,
+    /// A USB Flashing Format image.
+    Uf2,
+    /// A DfuSe file of the STM32 DFU bootloader.
+    Dfuse,
+    /// A firmware bundle (`.drgfw`).
+    Bundle,
+}
+
+impl FirmwareFormat {
+    /// Detect the format of the artifact from its contents.
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(b"PK\x03\x04") {
+            Self::Bundle
+        } else if data.starts_with(b"\x7fELF") {
+            Self::Elf
+        } else if is_mcuboot(data) {
+            Self::Mcuboot
+        } else if is_uf2(data) {
+            Self::Uf2
+        } else if is_dfuse(data) {
+            Self::Dfuse
+        } else if is_srec(data) {
+            Self::Srec
+        } else if is_intel_hex(data) {
+            Self::Hex
+        } else {
+            Self::Raw
+        }
+    }
+}
+
+impl core::fmt::Display for FirmwareFormat {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Raw => write!(f, "raw"),
+            Self::Hex => write!(f, "hex"),
+            Self::Srec => write!(f, "srec"),
+            Self::Elf => write!(f, "elf"),
+            Self::Mcuboot => write!(f, "mcuboot"),
+            Self::Uf2 => write!(f, "uf2"),
+            Self::Dfuse => write!(f, "dfuse"),
+            Self::Bundle => write!(f, "bundle"),
+        }
+    }
+}
+
+/// Whether the artifact carries a signature, and whether it was verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignatureStatus {
+    Unsigned,
+    /// Signed, but not verified as no public key was given.
+    NotVerified,
+    Valid,
+    Invalid,
+}
+
+impl core::fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Unsigned => write!(f, "unsigned"),
+            Self::NotVerified => write!(f, "signed, not verified"),
+            Self::Valid => write!(f, "valid"),
+            Self::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
+/// What is known about a firmware artifact before flashing it.
+#[derive(Debug, Serialize)]
+pub struct Inspection {
+    pub format: FirmwareFormat,
+    /// Format of the firmware held by a bundle.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contents: Option<FirmwareFormat>,
+    /// Size of the artifact.
+    pub size: usize,
+    /// Size of the firmware written to the device, if the artifact is converted first.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binary_size: Option<usize>,
+    pub checksum_algorithm: ChecksumAlgorithm,
+    /// Checksum of the firmware written to the device.
+    pub checksum: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FirmwareFileMeta>,
+    /// Whether the firmware matches the size and checksum of the metadata.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_matches: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mcuboot: Option<McubootInfo>,
+    /// Number of blocks of UF2 images.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uf2_blocks: Option<usize>,
+    /// Family id of UF2 images, if they have one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uf2_family: Option<u32>,
+    pub signature: SignatureStatus,
+}
+
+impl Inspection {
+    /// Inspect the artifact, comparing it with the metadata if given and verifying its
+    /// signature with the PEM encoded public key if given. The metadata of bundles is used
+    /// unless other metadata is given.
+    pub fn new(
+        data: &[u8],
+        metadata: Option<FirmwareFileMeta>,
+        public_key: Option<&[u8]>,
+    ) -> anyhow::Result<Self> {
+        let format = FirmwareFormat::detect(data);
+        let (firmware, metadata, contents, signature) = if format == FirmwareFormat::Bundle {
+            let bundle = FirmwareBundle::from_bytes(data)?;
+            let signature = match (&bundle.signature, public_key) {
+                (None, _) => SignatureStatus::Unsigned,
+                (Some(_), None) => SignatureStatus::NotVerified,
+                (Some(_), Some(key)) => verified(bundle.verify(key))?,
+            };
+            let contents = FirmwareFormat::detect(&bundle.firmware);
+            let metadata = metadata.unwrap_or(bundle.metadata);
+            (bundle.firmware, Some(metadata), Some(contents), signature)
+        } else {
+            (data.to_vec(), metadata, None, SignatureStatus::Unsigned)
+        };
+
+        let mut inspection = Self {
+            format,
+            contents,
+            size: data.len(),
+            binary_size: None,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            checksum: String::new(),
+            metadata: None,
+            metadata_matches: None,
+            mcuboot: None,
+            uf2_blocks: None,
+            uf2_family: None,
+            signature,
+        };
+
+        match contents.unwrap_or(format) {
+            FirmwareFormat::Mcuboot => {
+                let mcuboot = McubootInfo::parse(&firmware)?;
+                inspection.signature = match (mcuboot.signed, public_key) {
+                    (false, _) => inspection.signature,
+                    (true, None) => SignatureStatus::NotVerified,
+                    (true, Some(key)) => verified(mcuboot.verify(&firmware, key))?,
+                };
+                inspection.mcuboot.replace(mcuboot);
+            }
+            FirmwareFormat::Uf2 => {
+                let (blocks, family) = uf2_blocks(&firmware);
+                inspection.uf2_blocks.replace(blocks);
+                inspection.uf2_family = family;
+            }
+            _ => {}
+        }
+
+        // Checksums in metadata are of the firmware as written, after converting it
+        let binary = match contents.unwrap_or(format) {
+            FirmwareFormat::Srec | FirmwareFormat::Dfuse => {
+                let binary = decode_image(firmware)?;
+                inspection.binary_size.replace(binary.len());
+                binary
+            }
+            _ => firmware,
+        };
+        let algorithm = metadata
+            .as_ref()
+            .map(|m| m.checksum_algorithm)
+            .unwrap_or_default();
+        inspection.checksum_algorithm = algorithm;
+        inspection.checksum = algorithm.hex(&binary);
+        if let Some(metadata) = metadata {
+            inspection.metadata_matches.replace(
+                metadata.size == binary.len()
+                    && metadata.checksum.eq_ignore_ascii_case(&inspection.checksum),
+            );
+            inspection.metadata.replace(metadata);
+        }
+        Ok(inspection)
+    }
+
+    /// Returns true if the firmware matches its metadata and its signature is not invalid.
+    pub fn is_valid(&self) -> bool {
+        self.metadata_matches != Some(false)
+            && self.signature != SignatureStatus::Invalid
+            && self
+                .mcuboot
+                .as_ref()
+                .map(|m| m.digest_valid)
+                .unwrap_or(true)
+    }
+}
+
+/// The status of a signature from the result of verifying it, failing on other errors than
+/// an invalid signature, such as an unsupported key.
+fn verified(result: anyhow::Result<()>) -> anyhow::Result<SignatureStatus> {
+    match result {
+        Ok(()) => Ok(SignatureStatus::Valid),
+        Err(e) if Failure::of(&e) == Some(Failure::Verification) => Ok(SignatureStatus::Invalid),
+        Err(e) => Err(e),
+    }
+}
+
+/// Intel HEX files consist of records starting with a colon and hex digits.
+fn is_intel_hex(data: &[u8]) -> bool {
+    let line = data.split(|b| *b == b'\n').next().unwrap_or_default();
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    line.len() >= 11 && line[0] == b':' && line[1..].iter().all(u8::is_ascii_hexdigit)
+}
@@ -1,11 +1,194 @@
 #![feature(type_alias_impl_trait)]
 
+mod checksum;
+mod error;
+mod failure;
+mod gatt_protocol;
+mod runtime;
+
+pub use checksum::*;
+pub use error::*;
+pub use failure::*;
+pub use gatt_protocol::*;
+pub use runtime::*;
+
+// Modules depending on the host, such as its file system, network and serial ports. Without
+// them, the GATT protocol can be compiled to wasm32.
+
+#[cfg(feature = "host")]
+mod audit;
+#[cfg(feature = "host")]
+mod avr109;
+#[cfg(feature = "host")]
+mod bundle;
+#[cfg(feature = "host")]
+mod cache;
+#[cfg(feature = "host")]
+mod compression;
+#[cfg(feature = "host")]
+mod delta;
+#[cfg(feature = "host")]
+mod dfuse;
+#[cfg(feature = "host")]
+mod encryption;
+#[cfg(feature = "host")]
+mod esp;
+#[cfg(feature = "host")]
+mod events;
+#[cfg(feature = "host")]
 mod firmware;
+#[cfg(feature = "host")]
+mod frame;
+#[cfg(feature = "host")]
+mod health;
+#[cfg(feature = "host")]
+mod inspect;
+#[cfg(feature = "host")]
+mod mcuboot;
+#[cfg(feature = "host")]
+mod modbus;
+#[cfg(feature = "host")]
+mod nrf;
+#[cfg(feature = "host")]
+mod oauth;
+#[cfg(feature = "host")]
+mod progress;
+#[cfg(feature = "host")]
+mod retry;
+#[cfg(feature = "host")]
+mod rp2040;
+#[cfg(feature = "host")]
+mod runner;
+#[cfg(feature = "host")]
+mod samba;
+#[cfg(feature = "host")]
+mod schedule;
+#[cfg(feature = "host")]
+mod serial;
+#[cfg(feature = "host")]
+mod simulator;
+#[cfg(feature = "host")]
+mod smp;
+#[cfg(feature = "host")]
+mod srec;
+#[cfg(feature = "host")]
+mod ssh;
+#[cfg(feature = "host")]
+mod stm32;
+#[cfg(feature = "host")]
+mod stream;
+#[cfg(feature = "host")]
+mod tftp;
+#[cfg(feature = "host")]
+mod uf2;
+
+#[cfg(feature = "host")]
+pub mod blocking;
+#[cfg(feature = "host")]
+pub mod metrics;
 
+#[cfg(feature = "host")]
+pub use audit::*;
+#[cfg(feature = "host")]
+pub use avr109::*;
+#[cfg(feature = "host")]
+pub use bundle::*;
+#[cfg(feature = "host")]
+pub use cache::*;
+#[cfg(feature = "host")]
+pub use compression::*;
+#[cfg(feature = "host")]
+pub use delta::*;
+#[cfg(feature = "host")]
+pub use dfuse::*;
+#[cfg(feature = "host")]
+pub use encryption::*;
+#[cfg(feature = "host")]
+pub use esp::*;
+#[cfg(feature = "host")]
+pub use events::*;
+#[cfg(feature = "host")]
 pub use firmware::*;
+#[cfg(feature = "host")]
+pub use frame::*;
+#[cfg(feature = "host")]
+pub use health::*;
+#[cfg(feature = "host")]
+pub use inspect::*;
+#[cfg(feature = "host")]
+pub use mcuboot::*;
+#[cfg(feature = "host")]
+pub use modbus::*;
+#[cfg(feature = "host")]
+pub use nrf::*;
+#[cfg(feature = "host")]
+pub use oauth::*;
+#[cfg(feature = "host")]
+pub use progress::*;
+#[cfg(feature = "host")]
+pub use retry::*;
+#[cfg(feature = "host")]
+pub use rp2040::*;
+#[cfg(feature = "host")]
+pub use runner::*;
+#[cfg(feature = "host")]
+pub use samba::*;
+#[cfg(feature = "host")]
+pub use schedule::*;
+#[cfg(feature = "host")]
+pub use serial::*;
+#[cfg(feature = "host")]
+pub use simulator::*;
+#[cfg(feature = "host")]
+pub use smp::*;
+#[cfg(feature = "host")]
+pub use srec::*;
+#[cfg(feature = "host")]
+pub use ssh::*;
+#[cfg(feature = "host")]
+pub use stm32::*;
+#[cfg(feature = "host")]
+pub use stream::*;
+#[cfg(feature = "host")]
+pub use tftp::*;
+#[cfg(feature = "host")]
+pub use uf2::*;
 
 #[cfg(feature = "ble")]
 mod gatt;
 
 #[cfg(feature = "ble")]
 pub use gatt::*;
+
+#[cfg(all(feature = "bluez", target_os = "linux"))]
+mod bluez;
+
+#[cfg(all(feature = "bluez", target_os = "linux"))]
+pub use bluez::*;
+
+#[cfg(feature = "grpc")]
+mod grpc;
+
+#[cfg(feature = "grpc")]
+pub use grpc::*;
+
+#[cfg(feature = "mqtt")]
+mod mqtt;
+
+#[cfg(feature = "mqtt")]
+pub use mqtt::*;
+
+#[cfg(feature = "pkcs11")]
+mod pkcs11;
+
+#[cfg(feature = "pkcs11")]
+pub use pkcs11::*;
+
+#[cfg(feature = "websocket")]
+mod websocket;
+
+#[cfg(feature = "websocket")]
+pub use websocket::*;
+
+#[cfg(all(feature = "host", any(test, feature = "testing")))]
+pub mod testing;
@@ -0,0 +1,355 @@
+use crate::Failure;
+use anyhow::anyhow;
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, Ed25519KeyPair, KeyPair, UnparsedPublicKey, ECDSA_P256_SHA256_ASN1,
+    ECDSA_P256_SHA256_ASN1_SIGNING, ED25519,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+
+const IMAGE_MAGIC: u32 = 0x96f3b83d;
+const IMAGE_HEADER_LEN: usize = 32;
+const TLV_INFO_MAGIC: u16 = 0x6907;
+const TLV_PROT_INFO_MAGIC: u16 = 0x6908;
+
+const TLV_KEYHASH: u16 = 0x01;
+const TLV_SHA256: u16 = 0x10;
+const TLV_RSA2048: u16 = 0x20;
+const TLV_ECDSA256: u16 = 0x22;
+const TLV_RSA3072: u16 = 0x23;
+const TLV_ED25519: u16 = 0x24;
+
+// DER prefixes of the SubjectPublicKeyInfo for the supported keys, hashed by MCUboot
+const P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+const ED25519_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// Key used to sign MCUboot images.
+pub enum SigningKey {
+    EcdsaP256(EcdsaKeyPair),
+    Ed25519(Ed25519KeyPair),
+    /// An ECDSA P-256 key on a PKCS#11 token.
+    #[cfg(feature = "pkcs11")]
+    Pkcs11(crate::Pkcs11Key),
+}
+
+impl SigningKey {
+    /// Read an ECDSA P-256 or Ed25519 private key in PEM encoded PKCS#8 format, as
+    /// generated by imgtool.
+    pub fn from_pem(pem: &[u8]) -> anyhow::Result<Self> {
+        let der = pem_to_der(pem)?;
+        if let Ok(key) = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &der) {
+            return Ok(Self::EcdsaP256(key));
+        }
+        if let Ok(key) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der) {
+            return Ok(Self::Ed25519(key));
+        }
+        Err(anyhow!("unsupported key, expected ECDSA P-256 or Ed25519"))
+    }
+
+    /// Read the key from a PEM file, or open it on a PKCS#11 token if given a `pkcs11:` URI
+    /// as described for `Pkcs11Key`.
+    pub fn open(key: &Path) -> anyhow::Result<Self> {
+        match key.to_str() {
+            #[cfg(feature = "pkcs11")]
+            Some(uri) if uri.starts_with("pkcs11:") => {
+                Ok(Self::Pkcs11(crate::Pkcs11Key::open(uri)?))
+            }
+            #[cfg(not(feature = "pkcs11"))]
+            Some(uri) if uri.starts_with("pkcs11:") => {
+                Err(anyhow!("PKCS#11 keys require the pkcs11 feature"))
+            }
+            _ => Self::from_pem(&std::fs::read(key)?),
+        }
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        let (prefix, key) = match self {
+            Self::EcdsaP256(key) => (P256_SPKI_PREFIX, key.public_key().as_ref()),
+            Self::Ed25519(key) => (ED25519_SPKI_PREFIX, key.public_key().as_ref()),
+            #[cfg(feature = "pkcs11")]
+            Self::Pkcs11(key) => (P256_SPKI_PREFIX, key.public_key()),
+        };
+        [prefix, key].concat()
+    }
+
+    /// Sign the image, returning the TLV type and signature.
+    fn sign(&self, payload: &[u8], digest: &[u8]) -> anyhow::Result<(u16, Vec<u8>)> {
+        match self {
+            Self::EcdsaP256(key) => {
+                let signature = key
+                    .sign(&SystemRandom::new(), payload)
+                    .map_err(|_| anyhow!("error signing image"))?;
+                Ok((TLV_ECDSA256, signature.as_ref().to_vec()))
+            }
+            // imgtool signs the digest rather than the image for Ed25519
+            Self::Ed25519(key) => Ok((TLV_ED25519, key.sign(digest).as_ref().to_vec())),
+            #[cfg(feature = "pkcs11")]
+            Self::Pkcs11(key) => Ok((TLV_ECDSA256, key.sign(payload)?)),
+        }
+    }
+
+    /// Sign the data, returning an ASN.1 encoded ECDSA or a raw Ed25519 signature.
+    pub(crate) fn sign_data(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::EcdsaP256(key) => Ok(key
+                .sign(&SystemRandom::new(), data)
+                .map_err(|_| anyhow!("error signing data"))?
+                .as_ref()
+                .to_vec()),
+            Self::Ed25519(key) => Ok(key.sign(data).as_ref().to_vec()),
+            #[cfg(feature = "pkcs11")]
+            Self::Pkcs11(key) => key.sign(data),
+        }
+    }
+}
+
+/// Read a PEM encoded ECDSA P-256 or Ed25519 public key, for verifying signatures created
+/// with `SigningKey::sign_data`.
+pub(crate) fn parse_public_key(pem: &[u8]) -> anyhow::Result<UnparsedPublicKey<Vec<u8>>> {
+    let der = pem_to_der(pem)?;
+    if let Some(key) = der.strip_prefix(P256_SPKI_PREFIX) {
+        Ok(UnparsedPublicKey::new(
+            &ECDSA_P256_SHA256_ASN1,
+            key.to_vec(),
+        ))
+    } else if let Some(key) = der.strip_prefix(ED25519_SPKI_PREFIX) {
+        Ok(UnparsedPublicKey::new(&ED25519, key.to_vec()))
+    } else {
+        Err(anyhow!(
+            "unsupported public key, expected ECDSA P-256 or Ed25519"
+        ))
+    }
+}
+
+/// Decode the first PEM block of the data.
+pub(crate) fn pem_to_der(pem: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let pem = core::str::from_utf8(pem)?;
+    let encoded: String = pem
+        .lines()
+        .skip_while(|l| !l.starts_with("-----BEGIN"))
+        .skip(1)
+        .take_while(|l| !l.starts_with("-----END"))
+        .map(|l| l.trim())
+        .collect();
+    Ok(base64::decode(encoded)?)
+}
+
+/// Creates images for devices running the MCUboot bootloader, compatible with `imgtool sign`.
+pub struct McubootImage {
+    version: (u8, u8, u16, u32),
+    header_size: u16,
+    load_address: u32,
+    key: Option<SigningKey>,
+}
+
+impl McubootImage {
+    /// Create images with the given version, in the `major.minor.revision+build` format.
+    pub fn new(version: &str) -> anyhow::Result<Self> {
+        let (version, build) = match version.split_once('+') {
+            Some((version, build)) => (version, build.parse()?),
+            None => (version, 0),
+        };
+        let mut parts = version.split('.');
+        let mut next = || parts.next().unwrap_or("0");
+        let version = (next().parse()?, next().parse()?, next().parse()?, build);
+        Ok(Self {
+            version,
+            header_size: 0x200,
+            load_address: 0,
+            key: None,
+        })
+    }
+
+    /// Size of the header preceding the firmware, which must match the bootloader configuration.
+    pub fn with_header_size(mut self, header_size: u16) -> Self {
+        self.header_size = header_size;
+        self
+    }
+
+    /// Address to load the image to, for bootloaders running images from RAM.
+    pub fn with_load_address(mut self, load_address: u32) -> Self {
+        self.load_address = load_address;
+        self
+    }
+
+    /// Sign images with the key.
+    pub fn with_signing_key(mut self, key: SigningKey) -> Self {
+        self.key.replace(key);
+        self
+    }
+
+    /// Wrap the firmware in an image header and TLV area.
+    pub fn create(&self, firmware: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let header_size = self.header_size as usize;
+        if header_size < IMAGE_HEADER_LEN {
+            return Err(anyhow!(
+                "header size must be at least {} bytes",
+                IMAGE_HEADER_LEN
+            ));
+        }
+
+        let (major, minor, revision, build) = self.version;
+        let mut image = Vec::with_capacity(header_size + firmware.len() + 256);
+        image.extend_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        image.extend_from_slice(&self.load_address.to_le_bytes());
+        image.extend_from_slice(&self.header_size.to_le_bytes());
+        // No protected TLVs
+        image.extend_from_slice(&0u16.to_le_bytes());
+        image.extend_from_slice(&(firmware.len() as u32).to_le_bytes());
+        // Flags
+        image.extend_from_slice(&0u32.to_le_bytes());
+        image.push(major);
+        image.push(minor);
+        image.extend_from_slice(&revision.to_le_bytes());
+        image.extend_from_slice(&build.to_le_bytes());
+        image.extend_from_slice(&0u32.to_le_bytes());
+        image.resize(header_size, 0);
+        image.extend_from_slice(firmware);
+
+        let digest = Sha256::digest(&image);
+        let mut tlvs = vec![(TLV_SHA256, digest.to_vec())];
+        if let Some(key) = &self.key {
+            tlvs.push((TLV_KEYHASH, Sha256::digest(key.public_key()).to_vec()));
+            tlvs.push(key.sign(&image, &digest)?);
+        }
+
+        let total: usize = 4 + tlvs.iter().map(|(_, v)| 4 + v.len()).sum::<usize>();
+        image.extend_from_slice(&TLV_INFO_MAGIC.to_le_bytes());
+        image.extend_from_slice(&(total as u16).to_le_bytes());
+        for (kind, value) in tlvs {
+            image.extend_from_slice(&kind.to_le_bytes());
+            image.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            image.extend_from_slice(&value);
+        }
+        Ok(image)
+    }
+}
+
+/// Returns true if the data starts with an MCUboot image header.
+pub fn is_mcuboot(data: &[u8]) -> bool {
+    data.len() >= IMAGE_HEADER_LEN && read_u32(data, 0) == IMAGE_MAGIC
+}
+
+/// The header and TLV area of an MCUboot image.
+#[derive(Debug, Clone, Serialize)]
+pub struct McubootInfo {
+    /// Version in the `major.minor.revision+build` format.
+    pub version: String,
+    pub header_size: u16,
+    pub image_size: u32,
+    pub load_address: u32,
+    /// Whether the SHA-256 digest in the TLV area matches the image.
+    pub digest_valid: bool,
+    /// Whether the TLV area holds a signature.
+    pub signed: bool,
+    #[serde(skip)]
+    digest: Vec<u8>,
+    #[serde(skip)]
+    signature: Option<(u16, Vec<u8>)>,
+    #[serde(skip)]
+    signed_len: usize,
+}
+
+impl McubootInfo {
+    /// Parse the header and TLV area of an image.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if !is_mcuboot(data) {
+            return Err(anyhow!("not an MCUboot image"));
+        }
+        let header_size = read_u16(data, 8);
+        let protected_size = read_u16(data, 10) as usize;
+        let image_size = read_u32(data, 12);
+        let version = format!(
+            "{}.{}.{}+{}",
+            data[20],
+            data[21],
+            read_u16(data, 22),
+            read_u32(data, 24)
+        );
+
+        // The digest and signature cover the header, the image and the protected TLVs
+        let signed_len = header_size as usize + image_size as usize + protected_size;
+        let mut offset = signed_len;
+        if data.len() < offset + 4 {
+            return Err(anyhow!("MCUboot image is truncated"));
+        }
+        if protected_size > 0 && read_u16(data, offset - protected_size) != TLV_PROT_INFO_MAGIC {
+            return Err(anyhow!("invalid protected TLV area"));
+        }
+        if read_u16(data, offset) != TLV_INFO_MAGIC {
+            return Err(anyhow!("invalid TLV area"));
+        }
+        let end = offset + read_u16(data, offset + 2) as usize;
+        if data.len() < end {
+            return Err(anyhow!("MCUboot TLV area is truncated"));
+        }
+        offset += 4;
+
+        let mut digest = Vec::new();
+        let mut signature = None;
+        while offset + 4 <= end {
+            let kind = read_u16(data, offset);
+            let len = read_u16(data, offset + 2) as usize;
+            let value = data
+                .get(offset + 4..offset + 4 + len)
+                .ok_or_else(|| anyhow!("MCUboot TLV area is truncated"))?;
+            match kind {
+                TLV_SHA256 => digest = value.to_vec(),
+                TLV_RSA2048 | TLV_ECDSA256 | TLV_RSA3072 | TLV_ED25519 => {
+                    signature.replace((kind, value.to_vec()));
+                }
+                _ => {}
+            }
+            offset += 4 + len;
+        }
+
+        Ok(Self {
+            version,
+            header_size,
+            image_size,
+            load_address: read_u32(data, 4),
+            digest_valid: Sha256::digest(&data[..signed_len])[..] == digest[..],
+            signed: signature.is_some(),
+            digest,
+            signature,
+            signed_len,
+        })
+    }
+
+    /// Verify the signature of the image with a PEM encoded ECDSA P-256 or Ed25519 public
+    /// key.
+    pub fn verify(&self, data: &[u8], public_key: &[u8]) -> anyhow::Result<()> {
+        let (kind, signature) = self
+            .signature
+            .as_ref()
+            .ok_or_else(|| anyhow!("image is not signed"))?;
+        let key = parse_public_key(public_key)?;
+        // imgtool signs the digest rather than the image for Ed25519
+        let result = match *kind {
+            TLV_ECDSA256 => key.verify(&data[..self.signed_len], signature),
+            TLV_ED25519 => key.verify(&self.digest, signature),
+            _ => return Err(anyhow!("unsupported signature type {:#04x}", kind)),
+        };
+        result.map_err(|_| anyhow!("invalid image signature").context(Failure::Verification))
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
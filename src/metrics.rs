@@ -0,0 +1,55 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter, Encoder, Histogram, IntCounter, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref UPDATES_STARTED: IntCounter = register_int_counter!(
+        "drgdfu_updates_started_total",
+        "Number of firmware updates started"
+    )
+    .unwrap();
+    pub static ref UPDATES_SUCCEEDED: IntCounter = register_int_counter!(
+        "drgdfu_updates_succeeded_total",
+        "Number of firmware updates completed successfully"
+    )
+    .unwrap();
+    pub static ref UPDATES_FAILED: IntCounter = register_int_counter!(
+        "drgdfu_updates_failed_total",
+        "Number of firmware updates that failed"
+    )
+    .unwrap();
+    pub static ref BYTES_TRANSFERRED: IntCounter = register_int_counter!(
+        "drgdfu_bytes_transferred_total",
+        "Number of firmware bytes written to devices"
+    )
+    .unwrap();
+    pub static ref UPDATE_DURATION: Histogram = register_histogram!(
+        "drgdfu_update_duration_seconds",
+        "Duration of successful firmware updates",
+        vec![1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0]
+    )
+    .unwrap();
+    pub static ref BLE_RECONNECTS: IntCounter = register_int_counter!(
+        "drgdfu_ble_reconnects_total",
+        "Number of unexpected reconnects to BLE devices"
+    )
+    .unwrap();
+}
+
+/// Encode all metrics in the Prometheus text format.
+pub fn gather() -> String {
+    // Make sure metrics are reported before they are first updated
+    lazy_static::initialize(&UPDATES_STARTED);
+    lazy_static::initialize(&UPDATES_SUCCEEDED);
+    lazy_static::initialize(&UPDATES_FAILED);
+    lazy_static::initialize(&BYTES_TRANSFERRED);
+    lazy_static::initialize(&UPDATE_DURATION);
+    lazy_static::initialize(&BLE_RECONNECTS);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
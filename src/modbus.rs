@@ -0,0 +1,343 @@
+use crate::{Failure, SerialPort};
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_io::adapters::FromTokio;
+use embedded_io::asynch::{Read, Write};
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_serial::{Parity, SerialStream, StopBits};
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+const WRITE_FILE_RECORD: u8 = 0x15;
+
+/// Reference type of file records.
+const FILE_REFERENCE: u8 = 0x06;
+
+const CURRENT_VERSION_REGISTER: u16 = 0x0000;
+const NEXT_VERSION_REGISTER: u16 = 0x0010;
+const OFFSET_REGISTER: u16 = 0x0020;
+const CONTROL_REGISTER: u16 = 0x0030;
+
+/// Registers holding a version of up to 32 bytes.
+const VERSION_REGISTERS: u16 = 16;
+
+const CONTROL_START: u16 = 1;
+const CONTROL_SWAP: u16 = 2;
+const CONTROL_BOOTED: u16 = 3;
+
+/// Records of 2 bytes per file, the largest record number being 9999.
+const FILE_RECORDS: u32 = 10000;
+
+/// Bytes of firmware written per file record request, which divides the size of a file so
+/// that requests do not span files.
+const MAX_WRITE: usize = 200;
+
+/// Silence of at least 3.5 characters separating frames, for baud rates of 9600 and up.
+const FRAME_DELAY: Duration = Duration::from_millis(4);
+
+/// Parity of a Modbus RTU bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModbusParity {
+    /// Even parity, the default of Modbus RTU.
+    Even,
+    /// Odd parity.
+    Odd,
+    /// No parity, using two stop bits instead.
+    None,
+}
+
+impl core::str::FromStr for ModbusParity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "even" => Ok(Self::Even),
+            "odd" => Ok(Self::Odd),
+            "none" => Ok(Self::None),
+            _ => Err(anyhow!("unknown parity '{}'", s)),
+        }
+    }
+}
+
+/// Open a serial port for use with Modbus RTU.
+pub fn open_modbus_port(
+    port: &Path,
+    baud_rate: u32,
+    parity: ModbusParity,
+) -> anyhow::Result<SerialPort> {
+    let p = port.to_str().ok_or_else(|| anyhow!("invalid port name"))?;
+    let builder = tokio_serial::new(p, baud_rate);
+    let builder = match parity {
+        ModbusParity::Even => builder.parity(Parity::Even),
+        ModbusParity::Odd => builder.parity(Parity::Odd),
+        ModbusParity::None => builder.parity(Parity::None).stop_bits(StopBits::Two),
+    };
+    Ok(FromTokio::new(SerialStream::open(&builder)?))
+}
+
+/// A device on a Modbus RTU bus, such as RS-485, updated by writing the firmware as file
+/// records.
+///
+/// The device exposes the following holding registers:
+///
+/// * `0x0000`-`0x000F`: the current version, as up to 32 bytes padded with zeros
+/// * `0x0010`-`0x001F`: the version being written, written when an update is started
+/// * `0x0020`-`0x0021`: the offset of the next byte of firmware, as a 32 bit big endian value
+/// * `0x0030`: the control register, where 1 starts an update, 2 swaps to the written
+///   firmware and 3 marks the firmware as booted
+///
+/// Firmware is written with Write File Record requests, where offset `n` of the firmware is
+/// in file `1 + n / 20000` at record `(n % 20000) / 2`.
+pub struct ModbusBoard<T>
+where
+    T: Read + Write,
+{
+    transport: T,
+    unit: u8,
+    timeout: Duration,
+    updated: bool,
+}
+
+impl<T> ModbusBoard<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    /// Update the device with the given unit id, between 1 and 247.
+    pub fn new(transport: T, unit: u8) -> Self {
+        Self {
+            transport,
+            unit,
+            timeout: Duration::from_secs(1),
+            updated: false,
+        }
+    }
+
+    /// Wait for responses for the given time.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send a request, returning the response without the unit id, function code and CRC.
+    async fn request(&mut self, pdu: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut frame = Vec::with_capacity(pdu.len() + 3);
+        frame.push(self.unit);
+        frame.extend_from_slice(pdu);
+        frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+        sleep(FRAME_DELAY).await;
+        self.transport
+            .write_all(&frame)
+            .await
+            .map_err(|e| anyhow!("error writing to bus: {:?}", e))?;
+        self.transport
+            .flush()
+            .await
+            .map_err(|e| anyhow!("error writing to bus: {:?}", e))?;
+
+        let function = pdu[0];
+        let mut response = self.read(2).await?;
+        if response[0] != self.unit {
+            return Err(anyhow!("response from unexpected unit {}", response[0]));
+        }
+        let remaining = if response[1] == function | 0x80 {
+            3
+        } else if response[1] != function {
+            return Err(anyhow!(
+                "unexpected function {:#04x} in response",
+                response[1]
+            ));
+        } else {
+            match function {
+                READ_HOLDING_REGISTERS => {
+                    response.extend(self.read(1).await?);
+                    response[2] as usize + 2
+                }
+                WRITE_MULTIPLE_REGISTERS => 6,
+                // Write File Record responses echo the request
+                _ => frame.len() - 2,
+            }
+        };
+        response.extend(self.read(remaining).await?);
+
+        let (data, crc) = response.split_at(response.len() - 2);
+        if crc16(data).to_le_bytes() != crc {
+            return Err(anyhow!("invalid CRC in response"));
+        }
+        if data[1] & 0x80 != 0 {
+            return Err(anyhow!(
+                "device responded with exception code {:#04x}",
+                data[2]
+            ));
+        }
+        Ok(data[2..].to_vec())
+    }
+
+    async fn read(&mut self, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        timeout(self.timeout, self.transport.read_exact(&mut data))
+            .await
+            .map_err(|_| {
+                anyhow!("timeout waiting for unit {}", self.unit).context(Failure::ConnectTimeout)
+            })?
+            .map_err(|e| anyhow!("error reading from bus: {:?}", e))?;
+        Ok(data)
+    }
+
+    async fn read_registers(&mut self, address: u16, count: u16) -> anyhow::Result<Vec<u8>> {
+        let mut pdu = vec![READ_HOLDING_REGISTERS];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&count.to_be_bytes());
+        let response = self.request(&pdu).await?;
+        if response.len() != 1 + count as usize * 2 {
+            return Err(anyhow!("invalid register response"));
+        }
+        Ok(response[1..].to_vec())
+    }
+
+    async fn write_registers(&mut self, address: u16, data: &[u8]) -> anyhow::Result<()> {
+        let mut pdu = vec![WRITE_MULTIPLE_REGISTERS];
+        pdu.extend_from_slice(&address.to_be_bytes());
+        pdu.extend_from_slice(&((data.len() / 2) as u16).to_be_bytes());
+        pdu.push(data.len() as u8);
+        pdu.extend_from_slice(data);
+        self.request(&pdu).await?;
+        Ok(())
+    }
+
+    async fn control(&mut self, command: u16) -> anyhow::Result<()> {
+        self.write_registers(CONTROL_REGISTER, &command.to_be_bytes())
+            .await
+    }
+
+    async fn read_version(&mut self, address: u16) -> anyhow::Result<Vec<u8>> {
+        let mut version = self.read_registers(address, VERSION_REGISTERS).await?;
+        if let Some(end) = version.iter().position(|b| *b == 0) {
+            version.truncate(end);
+        }
+        Ok(version)
+    }
+
+    async fn read_offset(&mut self) -> anyhow::Result<u32> {
+        let data = self.read_registers(OFFSET_REGISTER, 2).await?;
+        Ok(u32::from_be_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    async fn write_file_record(&mut self, offset: u32, data: &[u8]) -> anyhow::Result<()> {
+        let file = 1 + offset / (FILE_RECORDS * 2);
+        let record = (offset % (FILE_RECORDS * 2)) / 2;
+        // Records are 2 bytes
+        let mut data = data.to_vec();
+        data.resize((data.len() + 1) / 2 * 2, 0xFF);
+
+        let mut pdu = vec![WRITE_FILE_RECORD, (7 + data.len()) as u8, FILE_REFERENCE];
+        pdu.extend_from_slice(&(file as u16).to_be_bytes());
+        pdu.extend_from_slice(&(record as u16).to_be_bytes());
+        pdu.extend_from_slice(&((data.len() / 2) as u16).to_be_bytes());
+        pdu.extend_from_slice(&data);
+        self.request(&pdu).await?;
+        Ok(())
+    }
+}
+
+/// CRC-16 of Modbus RTU frames, sent least significant byte first.
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, b| {
+        (0..8).fold(crc ^ *b as u16, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+impl<T> FirmwareDevice for ModbusBoard<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    const MTU: usize = 4000;
+    type Version = Vec<u8>;
+    type Error = anyhow::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            let current_version = self.read_version(CURRENT_VERSION_REGISTER).await?;
+            let next_version = self.read_version(NEXT_VERSION_REGISTER).await?;
+            let next_offset = self.read_offset().await?;
+            Ok(FirmwareStatus {
+                current_version,
+                next_offset,
+                next_version: Some(next_version).filter(|v| !v.is_empty()),
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            if version.len() > VERSION_REGISTERS as usize * 2 {
+                return Err(anyhow!("version longer than 32 bytes"));
+            }
+            let mut data = version.to_vec();
+            data.resize(VERSION_REGISTERS as usize * 2, 0);
+            self.write_registers(NEXT_VERSION_REGISTER, &data).await?;
+            self.control(CONTROL_START).await
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            let mut offset = offset;
+            for chunk in data.chunks(MAX_WRITE) {
+                self.write_file_record(offset, chunk).await?;
+                offset += chunk.len() as u32;
+            }
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, _: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            log::info!("Swapping firmware of unit {}", self.unit);
+            self.control(CONTROL_SWAP).await?;
+            self.updated = true;
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            if self.updated {
+                self.updated = false;
+                self.control(CONTROL_BOOTED).await?;
+            }
+            Ok(())
+        }
+    }
+}
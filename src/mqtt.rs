@@ -0,0 +1,334 @@
+use crate::{decode_command, DfuError, JsonCommand, PayloadEncoding};
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_update::*;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS, Transport};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Duration};
+
+/// A FirmwareDevice for devices connected to an MQTT broker.
+///
+/// Commands are published as CBOR to `<prefix>/<device>/command`, and the device is
+/// expected to publish its CBOR encoded status to `<prefix>/<device>/status`.
+pub struct MqttBoard {
+    client: AsyncClient,
+    command_topic: String,
+    statuses: mpsc::Receiver<Vec<u8>>,
+    current_version: Vec<u8>,
+    next_version: Option<Vec<u8>>,
+}
+
+impl MqttBoard {
+    pub fn new(host: &str, port: u16, prefix: &str, device: &str) -> Self {
+        let mut options = MqttOptions::new(format!("drgdfu-{}", uuid::Uuid::new_v4()), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_max_packet_size(64 * 1024, 64 * 1024);
+
+        let command_topic = format!("{}/{}/command", prefix, device);
+        let status_topic = format!("{}/{}/status", prefix, device);
+
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        let (tx, statuses) = mpsc::channel(10);
+        let subscriber = client.clone();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to broker, subscribing to {}", status_topic);
+                        if let Err(e) = subscriber.subscribe(&status_topic, QoS::AtLeastOnce).await
+                        {
+                            log::warn!("Error subscribing to {}: {:?}", status_topic, e);
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(p))) if p.topic == status_topic => {
+                        if tx.send(p.payload.to_vec()).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::info!("Broker connection error, retrying: {:?}", e);
+                        sleep(Duration::from_secs(2)).await;
+                    }
+                }
+            }
+        });
+
+        Self {
+            client,
+            command_topic,
+            statuses,
+            current_version: Vec::new(),
+            next_version: None,
+        }
+    }
+
+    async fn publish(&mut self, command: &Command<'_>) -> Result<(), DfuError> {
+        let payload = serde_cbor::to_vec(command).map_err(|e| DfuError::Protocol(e.into()))?;
+        self.client
+            .publish(&self.command_topic, QoS::AtLeastOnce, false, payload)
+            .await
+            .map_err(|e| DfuError::Connect(e.into()))?;
+        Ok(())
+    }
+}
+
+impl FirmwareDevice for MqttBoard {
+    const MTU: usize = 1024;
+    type Version = Vec<u8>;
+    type Error = DfuError;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            let payload = self
+                .statuses
+                .recv()
+                .await
+                .ok_or_else(|| DfuError::Connect(anyhow!("connection to broker closed")))?;
+            let status: Status =
+                serde_cbor::from_slice(&payload).map_err(|e| DfuError::Protocol(e.into()))?;
+            log::trace!("Received status: {:?}", status);
+
+            self.current_version = status.version.to_vec();
+            let mut next_offset = 0;
+            if let Some(update) = status.update {
+                next_offset = update.offset;
+                self.next_version.replace(update.version.to_vec());
+            }
+            Ok(FirmwareStatus {
+                current_version: self.current_version.clone(),
+                next_version: self.next_version.clone(),
+                next_offset,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            self.next_version.replace(version.to_vec());
+            Ok(())
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            let version = self
+                .next_version
+                .clone()
+                .ok_or_else(|| DfuError::Protocol(anyhow!("write before update was started")))?;
+            self.publish(&Command::new_write(&version, offset, data, None))
+                .await
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], checksum: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            log::debug!("Swapping firmware");
+            self.publish(&Command::new_swap(version, checksum, None))
+                .await
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            let version = self.current_version.clone();
+            self.publish(&Command::new_sync(&version, None, None)).await
+        }
+    }
+}
+
+/// Channel the status of devices is published to, as with the HTTP endpoint.
+const DFU_CHANNEL: &str = "dfu";
+/// Name of the commands sent to devices by the firmware service.
+const DFU_COMMAND: &str = "dfu";
+/// Topic filter of the commands sent to the device, or to the devices behind a gateway.
+const COMMAND_INBOX: &str = "command/inbox/#";
+
+/// An UpdateService talking to the MQTT endpoint of Drogue IoT Cloud instead of long-polling
+/// the HTTP endpoint like `DrogueFirmwareService`, for gateways which already keep an MQTT
+/// session.
+///
+/// The status is published to the `dfu` channel, and the DFU commands answering it are
+/// received on the command inbox. Gateways connect with their own credentials and act on
+/// behalf of the device given with `with_device`.
+pub struct DrogueMqttService {
+    client: AsyncClient,
+    commands: mpsc::Receiver<(String, Vec<u8>)>,
+    device: Option<String>,
+    encoding: PayloadEncoding,
+    timeout: Duration,
+    last_response: Vec<u8>,
+    last_command: Option<JsonCommand>,
+}
+
+impl DrogueMqttService {
+    /// Connect to the endpoint at `mqtts://<host>:<port>`, or `mqtt://<host>:<port>` without
+    /// TLS, with the user given as `<device or gateway>@<application>`.
+    pub fn new(url: &str, user: &str, password: &str) -> anyhow::Result<Self> {
+        let url = reqwest::Url::parse(url)?;
+        let tls = match url.scheme() {
+            "mqtts" => true,
+            "mqtt" => false,
+            scheme => {
+                return Err(anyhow!(
+                    "unsupported scheme '{}', expected mqtt or mqtts",
+                    scheme
+                ))
+            }
+        };
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("no host in {}", url))?;
+        let port = url.port().unwrap_or(if tls { 8883 } else { 1883 });
+
+        let mut options = MqttOptions::new(format!("drgdfu-{}", uuid::Uuid::new_v4()), host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_max_packet_size(64 * 1024, 64 * 1024);
+        options.set_credentials(user, password);
+        if tls {
+            options.set_transport(Transport::tls_with_default_config());
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        let (tx, commands) = mpsc::channel(10);
+        let subscriber = client.clone();
+        tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        log::info!("Connected to Drogue IoT Cloud, subscribing to commands");
+                        if let Err(e) = subscriber.subscribe(COMMAND_INBOX, QoS::AtLeastOnce).await
+                        {
+                            log::warn!("Error subscribing to {}: {:?}", COMMAND_INBOX, e);
+                        }
+                    }
+                    Ok(Event::Incoming(Packet::Publish(p))) => {
+                        if tx.send((p.topic, p.payload.to_vec())).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        log::info!("Connection error, retrying: {:?}", e);
+                        sleep(Duration::from_secs(2)).await;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            client,
+            commands,
+            device: None,
+            encoding: PayloadEncoding::default(),
+            timeout: Duration::from_secs(30),
+            last_response: Vec::new(),
+            last_command: None,
+        })
+    }
+
+    /// Act on behalf of the device, when connected as a gateway.
+    pub fn with_device(mut self, device: &str) -> Self {
+        self.device = Some(device.to_string());
+        self
+    }
+
+    /// Use the given encoding for status payloads and commands.
+    pub fn with_encoding(mut self, encoding: PayloadEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Wait this long for a command answering a status before polling again.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether the command received on the topic is a DFU command for the device, sent to
+    /// `command/inbox/<device>/dfu`, with an empty device for the connected device itself.
+    fn is_dfu_command(&self, topic: &str) -> bool {
+        match topic.split('/').collect::<Vec<_>>()[..] {
+            ["command", "inbox", device, DFU_COMMAND] => {
+                device == self.device.as_deref().unwrap_or_default()
+            }
+            _ => false,
+        }
+    }
+}
+
+impl UpdateService for DrogueMqttService {
+    type Error = DfuError;
+
+    type RequestFuture<'m> = impl Future<Output = Result<Command<'m>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Self::RequestFuture<'m> {
+        async move {
+            // Drop commands answering earlier statuses
+            while self.commands.try_recv().is_ok() {}
+
+            let payload = match self.encoding {
+                PayloadEncoding::Cbor => {
+                    serde_cbor::to_vec(status).map_err(|e| DfuError::Protocol(e.into()))?
+                }
+                PayloadEncoding::Json => {
+                    serde_json::to_vec(status).map_err(|e| DfuError::Protocol(e.into()))?
+                }
+            };
+            let topic = match &self.device {
+                Some(device) => format!("{}/{}", DFU_CHANNEL, device),
+                None => DFU_CHANNEL.to_string(),
+            };
+            self.client
+                .publish(topic, QoS::AtLeastOnce, false, payload)
+                .await
+                .map_err(|e| DfuError::Connect(e.into()))?;
+
+            let deadline = tokio::time::Instant::now() + self.timeout;
+            loop {
+                match tokio::time::timeout_at(deadline, self.commands.recv()).await {
+                    Ok(Some((topic, payload))) if self.is_dfu_command(&topic) => {
+                        log::trace!("Received command: {:?}", payload);
+                        self.last_response = payload;
+                        break;
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => {
+                        return Err(DfuError::Connect(anyhow!(
+                            "connection to Drogue IoT Cloud closed"
+                        )))
+                    }
+                    // Poll again like the HTTP endpoint does when no command arrives in time
+                    Err(_) => return Ok(Command::new_wait(None, status.correlation_id)),
+                }
+            }
+            decode_command(
+                self.encoding,
+                &mut self.last_response,
+                &mut self.last_command,
+            )
+        }
+    }
+}
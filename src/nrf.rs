@@ -0,0 +1,155 @@
+use crate::mcuboot::pem_to_der;
+use anyhow::anyhow;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use zip::write::{FileOptions, ZipWriter};
+
+// Values from dfu-cc.proto of the nRF5 SDK
+const OP_CODE_INIT: u64 = 1;
+const FW_TYPE_APPLICATION: u64 = 0;
+const HASH_TYPE_SHA256: u64 = 3;
+const VALIDATE_GENERATED_CRC: u64 = 1;
+const SIGNATURE_ECDSA_P256_SHA256: u64 = 0;
+
+/// Value of `sd_req` for devices without a SoftDevice.
+pub const NO_SOFTDEVICE: u32 = 0xFFFE;
+
+/// Creates Nordic DFU packages for the nRF5 SDK secure bootloader, compatible with
+/// `nrfutil pkg generate`.
+pub struct NrfPackage {
+    fw_version: u32,
+    hw_version: u32,
+    sd_req: Vec<u32>,
+    key: Option<EcdsaKeyPair>,
+}
+
+impl NrfPackage {
+    pub fn new(fw_version: u32) -> Self {
+        Self {
+            fw_version,
+            hw_version: 52,
+            sd_req: vec![NO_SOFTDEVICE],
+            key: None,
+        }
+    }
+
+    /// Hardware version the firmware is built for, such as 52 for nRF52 devices.
+    pub fn with_hw_version(mut self, hw_version: u32) -> Self {
+        self.hw_version = hw_version;
+        self
+    }
+
+    /// SoftDevice firmware ids the application is compatible with.
+    pub fn with_sd_req(mut self, sd_req: Vec<u32>) -> Self {
+        self.sd_req = sd_req;
+        self
+    }
+
+    /// Sign the init packet with a PEM encoded ECDSA P-256 private key.
+    pub fn with_signing_key(mut self, pem: &[u8]) -> anyhow::Result<Self> {
+        let der = pem_to_der(pem)?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &der)
+            .map_err(|_| anyhow!("unsupported key, expected ECDSA P-256"))?;
+        self.key.replace(key);
+        Ok(self)
+    }
+
+    /// Create the zip package for the application firmware.
+    pub fn create(&self, firmware: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let manifest = serde_json::json!({
+            "manifest": {
+                "application": {
+                    "bin_file": "application.bin",
+                    "dat_file": "application.dat",
+                }
+            }
+        });
+
+        let mut zip = ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = FileOptions::default();
+        zip.start_file("manifest.json", options)?;
+        zip.write_all(serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+        zip.start_file("application.dat", options)?;
+        zip.write_all(&self.init_packet(firmware)?)?;
+        zip.start_file("application.bin", options)?;
+        zip.write_all(firmware)?;
+        Ok(zip.finish()?.into_inner())
+    }
+
+    /// Encode the protobuf init packet describing the firmware.
+    fn init_packet(&self, firmware: &[u8]) -> anyhow::Result<Vec<u8>> {
+        // The bootloader expects the hash in little endian
+        let mut hash_bytes = Sha256::digest(firmware).to_vec();
+        hash_bytes.reverse();
+        let mut hash = Vec::new();
+        put_varint_field(&mut hash, 1, HASH_TYPE_SHA256);
+        put_bytes_field(&mut hash, 2, &hash_bytes);
+
+        let mut validation = Vec::new();
+        put_varint_field(&mut validation, 1, VALIDATE_GENERATED_CRC);
+        put_bytes_field(&mut validation, 2, &[]);
+
+        let mut sd_req = Vec::new();
+        for req in &self.sd_req {
+            put_varint(&mut sd_req, *req as u64);
+        }
+
+        let mut init = Vec::new();
+        put_varint_field(&mut init, 1, self.fw_version as u64);
+        put_varint_field(&mut init, 2, self.hw_version as u64);
+        put_bytes_field(&mut init, 3, &sd_req);
+        put_varint_field(&mut init, 4, FW_TYPE_APPLICATION);
+        put_varint_field(&mut init, 5, 0);
+        put_varint_field(&mut init, 6, 0);
+        put_varint_field(&mut init, 7, firmware.len() as u64);
+        put_bytes_field(&mut init, 8, &hash);
+        put_varint_field(&mut init, 9, 0);
+        put_bytes_field(&mut init, 10, &validation);
+
+        let mut command = Vec::new();
+        put_varint_field(&mut command, 1, OP_CODE_INIT);
+        put_bytes_field(&mut command, 2, &init);
+
+        let mut packet = Vec::new();
+        match &self.key {
+            None => put_bytes_field(&mut packet, 1, &command),
+            Some(key) => {
+                let signature = key
+                    .sign(&SystemRandom::new(), &command)
+                    .map_err(|_| anyhow!("error signing init packet"))?;
+                // The bootloader expects r and s in little endian
+                let mut signature = signature.as_ref().to_vec();
+                signature[..32].reverse();
+                signature[32..].reverse();
+
+                let mut signed = Vec::new();
+                put_bytes_field(&mut signed, 1, &command);
+                put_varint_field(&mut signed, 2, SIGNATURE_ECDSA_P256_SHA256);
+                put_bytes_field(&mut signed, 3, &signature);
+                put_bytes_field(&mut packet, 2, &signed);
+            }
+        }
+        Ok(packet)
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, value);
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    put_varint(buf, (field << 3) | 2);
+    put_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
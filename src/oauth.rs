@@ -0,0 +1,195 @@
+use anyhow::anyhow;
+use serde::Deserialize;
+use tokio::time::{sleep, Duration, Instant};
+
+/// An access token obtained from an OAuth2 provider.
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<Instant>,
+}
+
+impl AccessToken {
+    /// Returns true if the token expires within the given margin.
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        self.expires_at
+            .map(|e| e.saturating_duration_since(Instant::now()) <= margin)
+            .unwrap_or(false)
+    }
+
+    fn from_response(token: TokenResponse) -> Self {
+        Self {
+            access_token: token.access_token,
+            refresh_token: token.refresh_token,
+            expires_at: token
+                .expires_in
+                .map(|e| Instant::now() + Duration::from_secs(e)),
+        }
+    }
+}
+
+/// Log in a user with the OAuth2 device authorization flow, as used by the Drogue IoT SSO.
+///
+/// The user is asked to open a URL in a browser and confirm the login, while the token
+/// endpoint is polled until the login completes.
+#[derive(Debug, Clone)]
+pub struct DeviceLogin {
+    issuer_url: String,
+    client_id: String,
+    client: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    device_authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+impl DeviceLogin {
+    pub fn new(issuer_url: &str, client_id: &str) -> Self {
+        Self {
+            issuer_url: issuer_url.trim_end_matches('/').to_string(),
+            client_id: client_id.to_string(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Use the HTTP client for talking to the provider, to apply the same CA certificates and
+    /// proxy as for the firmware service.
+    pub fn with_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    async fn metadata(&self) -> anyhow::Result<ProviderMetadata> {
+        Ok(self
+            .client
+            .get(format!(
+                "{}/.well-known/openid-configuration",
+                self.issuer_url
+            ))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    pub async fn login(&self) -> anyhow::Result<AccessToken> {
+        let metadata = self.metadata().await?;
+
+        let authorization: DeviceAuthorization = self
+            .client
+            .post(&metadata.device_authorization_endpoint)
+            .form(&[("client_id", self.client_id.as_str()), ("scope", "openid")])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        match &authorization.verification_uri_complete {
+            Some(uri) => println!("Open {} in a browser to log in", uri),
+            None => println!(
+                "Open {} in a browser and enter the code {} to log in",
+                authorization.verification_uri, authorization.user_code
+            ),
+        }
+
+        let expires_at = Instant::now() + Duration::from_secs(authorization.expires_in);
+        let mut interval = Duration::from_secs(authorization.interval.unwrap_or(5));
+        loop {
+            sleep(interval).await;
+            if Instant::now() >= expires_at {
+                return Err(anyhow!("login expired before it was completed"));
+            }
+
+            let response = self
+                .client
+                .post(&metadata.token_endpoint)
+                .form(&[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+                    ("device_code", authorization.device_code.as_str()),
+                    ("client_id", self.client_id.as_str()),
+                ])
+                .send()
+                .await?;
+
+            if response.status().is_success() {
+                let token: TokenResponse = response.json().await?;
+                log::info!("Logged in to {}", self.issuer_url);
+                return Ok(AccessToken::from_response(token));
+            }
+
+            let error: ErrorResponse = response.json().await?;
+            match error.error.as_str() {
+                "authorization_pending" => {}
+                "slow_down" => interval += Duration::from_secs(5),
+                _ => {
+                    return Err(anyhow!(
+                        "login failed: {}",
+                        error.error_description.unwrap_or(error.error)
+                    ))
+                }
+            }
+        }
+    }
+    /// Obtain a new access token with the refresh token of the given one, without involving
+    /// the user.
+    pub async fn refresh(&self, token: &AccessToken) -> anyhow::Result<AccessToken> {
+        let refresh_token = token
+            .refresh_token
+            .as_deref()
+            .ok_or_else(|| anyhow!("access token cannot be refreshed"))?;
+        let metadata = self.metadata().await?;
+        let response = self
+            .client
+            .post(&metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token),
+                ("client_id", self.client_id.as_str()),
+            ])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            let error: ErrorResponse = response.json().await?;
+            return Err(anyhow!(
+                "refreshing access token failed: {}",
+                error.error_description.unwrap_or(error.error)
+            ));
+        }
+
+        let mut refreshed = AccessToken::from_response(response.json().await?);
+        log::info!("Refreshed access token from {}", self.issuer_url);
+        // Providers may keep using the same refresh token without returning it again
+        if refreshed.refresh_token.is_none() {
+            refreshed.refresh_token = token.refresh_token.clone();
+        }
+        Ok(refreshed)
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::anyhow;
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+/// An ECDSA P-256 private key on a PKCS#11 token, such as a YubiKey or an HSM, so that release
+/// signing keys never exist on disk.
+///
+/// The key is identified by a `pkcs11:` URI as defined by RFC 7512, matching the `token`,
+/// `object` and `id` attributes of the URI:
+///
+/// ```text
+/// pkcs11:token=release;object=firmware?module-path=/usr/lib/libykcs11.so
+/// ```
+///
+/// The module is given by the `module-path` query attribute or the `PKCS11_MODULE`
+/// environment variable, and the PIN of the token by the `pin-value` query attribute or the
+/// `PKCS11_PIN` environment variable.
+pub struct Pkcs11Key {
+    session: Session,
+    key: ObjectHandle,
+    public_key: Vec<u8>,
+}
+
+impl Pkcs11Key {
+    /// Open the key identified by the URI, logging in to its token.
+    pub fn open(uri: &str) -> anyhow::Result<Self> {
+        let (path, query) = parse_uri(uri)?;
+        let module = query
+            .get("module-path")
+            .map(|m| String::from_utf8_lossy(m).into_owned())
+            .or_else(|| std::env::var("PKCS11_MODULE").ok())
+            .ok_or_else(|| anyhow!("no PKCS#11 module given with module-path or PKCS11_MODULE"))?;
+        let pin = query
+            .get("pin-value")
+            .map(|p| String::from_utf8_lossy(p).into_owned())
+            .or_else(|| std::env::var("PKCS11_PIN").ok());
+
+        let pkcs11 = Pkcs11::new(module)?;
+        pkcs11.initialize(CInitializeArgs::OsThreads)?;
+        let mut slot = None;
+        for s in pkcs11.get_slots_with_token()? {
+            let label = pkcs11.get_token_info(s)?.label().trim_end().to_string();
+            if path
+                .get("token")
+                .map(|t| t[..] == *label.as_bytes())
+                .unwrap_or(true)
+            {
+                slot.replace(s);
+                break;
+            }
+        }
+        let slot = slot.ok_or_else(|| anyhow!("no PKCS#11 token found for {}", uri))?;
+        let session = pkcs11.open_ro_session(slot)?;
+        if let Some(pin) = pin {
+            session.login(UserType::User, Some(&AuthPin::new(pin)))?;
+        }
+
+        let mut template = vec![Attribute::KeyType(KeyType::EC)];
+        if let Some(label) = path.get("object") {
+            template.push(Attribute::Label(label.clone()));
+        }
+        if let Some(id) = path.get("id") {
+            template.push(Attribute::Id(id.clone()));
+        }
+        let find = |class| -> anyhow::Result<Option<ObjectHandle>> {
+            let mut template = template.clone();
+            template.push(Attribute::Class(class));
+            Ok(session.find_objects(&template)?.into_iter().next())
+        };
+        let key = find(ObjectClass::PRIVATE_KEY)?
+            .ok_or_else(|| anyhow!("no ECDSA private key found for {}", uri))?;
+        let public = find(ObjectClass::PUBLIC_KEY)?
+            .ok_or_else(|| anyhow!("no public key found for {}", uri))?;
+        let point = match session
+            .get_attributes(public, &[AttributeType::EcPoint])?
+            .into_iter()
+            .next()
+        {
+            Some(Attribute::EcPoint(point)) => point,
+            _ => return Err(anyhow!("unable to read the public key of {}", uri)),
+        };
+        // Tokens return the uncompressed point, usually wrapped in a DER octet string
+        let public_key = match &point[..] {
+            [0x04, 0x41, point @ ..] if point.len() == 65 => point.to_vec(),
+            point if point.len() == 65 && point[0] == 0x04 => point.to_vec(),
+            _ => return Err(anyhow!("unsupported key, expected ECDSA P-256")),
+        };
+        Ok(Self {
+            session,
+            key,
+            public_key,
+        })
+    }
+
+    /// The uncompressed point of the public key.
+    pub(crate) fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Sign the SHA-256 digest of the data, returning an ASN.1 encoded signature like ring.
+    pub(crate) fn sign(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let signature = self
+            .session
+            .sign(&Mechanism::Ecdsa, self.key, &Sha256::digest(data))?;
+        if signature.len() != 64 {
+            return Err(anyhow!("invalid signature from PKCS#11 token"));
+        }
+        let (r, s) = signature.split_at(32);
+        let body = [der_integer(r), der_integer(s)].concat();
+        Ok([vec![0x30, body.len() as u8], body].concat())
+    }
+}
+
+/// Encode the unsigned big endian value as a DER integer.
+fn der_integer(value: &[u8]) -> Vec<u8> {
+    let start = value
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(value.len() - 1);
+    let value = &value[start..];
+    let pad = value[0] & 0x80 != 0;
+    let mut integer = vec![0x02, value.len() as u8 + pad as u8];
+    if pad {
+        integer.push(0);
+    }
+    integer.extend_from_slice(value);
+    integer
+}
+
+type Attributes = HashMap<String, Vec<u8>>;
+
+/// Split a `pkcs11:` URI into its percent-decoded path and query attributes.
+fn parse_uri(uri: &str) -> anyhow::Result<(Attributes, Attributes)> {
+    let uri = uri
+        .strip_prefix("pkcs11:")
+        .ok_or_else(|| anyhow!("invalid PKCS#11 URI '{}'", uri))?;
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let attributes = |s: &str, separator| -> anyhow::Result<Attributes> {
+        s.split(separator)
+            .filter(|a| !a.is_empty())
+            .map(|a| {
+                let (name, value) = a.split_once('=').unwrap_or((a, ""));
+                Ok((name.to_string(), percent_decode(value)?))
+            })
+            .collect()
+    };
+    Ok((attributes(path, ';')?, attributes(query, '&')?))
+}
+
+fn percent_decode(s: &str) -> anyhow::Result<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+            let hex = core::str::from_utf8(&hex).unwrap_or_default();
+            decoded.push(
+                u8::from_str_radix(hex, 16)
+                    .map_err(|_| anyhow!("invalid escape in PKCS#11 URI '{}'", s))?,
+            );
+        } else {
+            decoded.push(b);
+        }
+    }
+    Ok(decoded)
+}
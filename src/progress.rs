@@ -0,0 +1,88 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Tracks the throughput of a transfer over a sliding window, to estimate its completion.
+pub struct Throughput {
+    started: Instant,
+    window: Duration,
+    samples: VecDeque<(Instant, usize)>,
+    total: usize,
+    transferred: usize,
+}
+
+impl Throughput {
+    /// Track a transfer of `total` bytes, starting now.
+    pub fn new(total: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            window: Duration::from_secs(10),
+            samples: VecDeque::new(),
+            total,
+            transferred: 0,
+        }
+    }
+
+    /// Compute the throughput over the given window instead of the last 10 seconds.
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Record that the transfer is at `position` bytes, for instance when resuming it.
+    pub fn skip(&mut self, position: usize) {
+        self.transferred = position;
+        self.samples.clear();
+    }
+
+    /// Record that `len` bytes have been transferred.
+    pub fn record(&mut self, len: usize) {
+        let now = Instant::now();
+        self.transferred += len;
+        self.samples.push_back((now, len));
+        while let Some((time, _)) = self.samples.front() {
+            if now.duration_since(*time) > self.window {
+                self.samples.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Bytes transferred so far.
+    pub fn transferred(&self) -> usize {
+        self.transferred
+    }
+
+    /// Bytes of the whole transfer.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Time since the transfer started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Bytes per second over the window, or none until enough has been transferred.
+    pub fn bytes_per_second(&self) -> Option<f64> {
+        // The first sample marks the start of the window, so its bytes are not counted
+        let (first, _) = self.samples.front()?;
+        let (last, _) = self.samples.back()?;
+        let duration = last.duration_since(*first).as_secs_f64();
+        if duration <= 0.0 {
+            return None;
+        }
+        let bytes: usize = self.samples.iter().skip(1).map(|(_, len)| len).sum();
+        Some(bytes as f64 / duration)
+    }
+
+    /// Estimated time until the transfer completes at the current throughput.
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.bytes_per_second()?;
+        if rate <= 0.0 {
+            return None;
+        }
+        let remaining = self.total.saturating_sub(self.transferred);
+        Some(Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
@@ -0,0 +1,130 @@
+use crate::runtime::sleep;
+use core::future::Future;
+use embedded_update::{Command, Status, UpdateService};
+use rand::Rng;
+use std::time::Duration;
+
+/// How long to wait between attempts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Wait the same amount of time between all attempts.
+    Fixed(Duration),
+    /// Double the time to wait after each attempt, starting at `initial` and up to `max`.
+    Exponential { initial: Duration, max: Duration },
+}
+
+/// Policy for retrying failed operations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, or None to retry forever.
+    pub max_attempts: Option<u32>,
+    pub backoff: Backoff,
+    /// Randomize delays by up to this fraction of the delay, such as 0.2 for 20%.
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn fixed(delay: Duration) -> Self {
+        Self {
+            max_attempts: None,
+            backoff: Backoff::Fixed(delay),
+            jitter: 0.0,
+        }
+    }
+
+    pub fn exponential(initial: Duration, max: Duration) -> Self {
+        Self {
+            max_attempts: None,
+            backoff: Backoff::Exponential { initial, max },
+            jitter: 0.0,
+        }
+    }
+
+    /// Give up after the given number of attempts.
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts.replace(attempts);
+        self
+    }
+
+    /// Randomize delays by up to the given fraction of the delay.
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Returns true if another attempt is allowed after `attempts` failed attempts.
+    pub fn retry(&self, attempts: u32) -> bool {
+        self.max_attempts.map(|max| attempts < max).unwrap_or(true)
+    }
+
+    /// The time to wait after `attempts` failed attempts.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let delay = match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => {
+                let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+                core::cmp::min(initial.saturating_mul(factor), max)
+            }
+        };
+        if self.jitter > 0.0 {
+            let jitter = rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+            delay.mul_f64((1.0 + jitter).max(0.0))
+        } else {
+            delay
+        }
+    }
+
+    /// Wait before the next attempt after `attempts` failed attempts.
+    pub async fn wait(&self, attempts: u32) {
+        sleep(self.delay(attempts)).await;
+    }
+}
+
+/// An update service backing off while the wrapped service has no update for the device, by
+/// asking the device to poll again after the delay of the policy. The delay grows with each
+/// consecutive wait command that does not set its own poll interval, and is reset by any
+/// other command.
+pub struct PollBackoff<S> {
+    service: S,
+    policy: RetryPolicy,
+    waits: u32,
+}
+
+impl<S> PollBackoff<S> {
+    pub fn new(service: S, policy: RetryPolicy) -> Self {
+        Self {
+            service,
+            policy,
+            waits: 0,
+        }
+    }
+}
+
+impl<S> UpdateService for PollBackoff<S>
+where
+    S: UpdateService,
+{
+    type Error = S::Error;
+
+    type RequestFuture<'m> = impl Future<Output = Result<Command<'m>, Self::Error>> + 'm where Self: 'm;
+    fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Self::RequestFuture<'m> {
+        async move {
+            match self.service.request(status).await? {
+                Command::Wait {
+                    poll: None,
+                    correlation_id,
+                } => {
+                    self.waits += 1;
+                    // Poll intervals are in seconds
+                    let delay = self.policy.delay(self.waits).as_secs().max(1);
+                    log::debug!("No update available, polling again in {} seconds", delay);
+                    Ok(Command::new_wait(Some(delay as u32), correlation_id))
+                }
+                command => {
+                    self.waits = 0;
+                    Ok(command)
+                }
+            }
+        }
+    }
+}
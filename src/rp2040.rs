@@ -0,0 +1,161 @@
+use crate::{Failure, Uf2Image, UF2_FAMILY_RP2040};
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use std::path::{Path, PathBuf};
+use tokio::time::{Duration, Instant};
+
+/// Board id reported in `INFO_UF2.TXT` by the RP2040 boot ROM.
+const RP2040_BOARD_ID: &str = "RPI-RP2";
+
+/// An RP2040 in BOOTSEL mode, updated by copying a UF2 image to the mass storage device
+/// exposed by its boot ROM.
+///
+/// The boot ROM has no notion of firmware versions, so the firmware is written whenever an
+/// update is started. The device reboots into the firmware once the image is copied. The
+/// picoboot interface of the boot ROM is not used, as it requires raw USB access.
+pub struct Rp2040Board {
+    drive: PathBuf,
+    firmware: Vec<u8>,
+    version: Vec<u8>,
+}
+
+impl Rp2040Board {
+    /// Use the BOOTSEL drive mounted at the given path.
+    pub fn new(drive: &Path) -> Self {
+        Self {
+            drive: drive.to_path_buf(),
+            firmware: Vec::new(),
+            version: Vec::new(),
+        }
+    }
+
+    /// Wait for an RP2040 in BOOTSEL mode to be mounted.
+    pub async fn find(wait: Duration) -> anyhow::Result<Self> {
+        let started = Instant::now();
+        loop {
+            if let Some(drive) = find_bootsel_drive() {
+                log::info!("Found RP2040 in BOOTSEL mode at {}", drive.display());
+                return Ok(Self::new(&drive));
+            }
+            if started.elapsed() >= wait {
+                return Err(anyhow!("no RP2040 in BOOTSEL mode found within {:?}", wait)
+                    .context(Failure::DeviceNotFound));
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }
+}
+
+/// Find the mount point of an RP2040 in BOOTSEL mode.
+pub fn find_bootsel_drive() -> Option<PathBuf> {
+    mount_points().into_iter().find(|path| {
+        std::fs::read_to_string(path.join("INFO_UF2.TXT"))
+            .map(|info| {
+                info.lines()
+                    .any(|l| l.trim() == format!("Board-ID: {}", RP2040_BOARD_ID))
+            })
+            .unwrap_or(false)
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn mount_points() -> Vec<PathBuf> {
+    std::fs::read_to_string("/proc/mounts")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|l| l.split_whitespace().nth(1))
+        // Spaces in mount points are escaped
+        .map(|p| PathBuf::from(p.replace("\\040", " ")))
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+fn mount_points() -> Vec<PathBuf> {
+    std::fs::read_dir("/Volumes")
+        .map(|entries| entries.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(target_os = "windows")]
+fn mount_points() -> Vec<PathBuf> {
+    (b'D'..=b'Z')
+        .map(|letter| PathBuf::from(format!("{}:\\", letter as char)))
+        .filter(|p| p.exists())
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn mount_points() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+impl FirmwareDevice for Rp2040Board {
+    const MTU: usize = 4096;
+    type Version = Vec<u8>;
+    type Error = anyhow::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            Ok(FirmwareStatus {
+                current_version: self.version.clone(),
+                next_offset: 0,
+                next_version: None,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, _: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            self.firmware.clear();
+            Ok(())
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            // The image is copied at once when the update is applied
+            let end = offset as usize + data.len();
+            if self.firmware.len() < end {
+                self.firmware.resize(end, 0xFF);
+            }
+            self.firmware[offset as usize..end].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            let image = Uf2Image::new(UF2_FAMILY_RP2040).create(&self.firmware);
+            let path = self.drive.join("firmware.uf2");
+            log::info!("Copying {} bytes to {}", image.len(), path.display());
+            tokio::fs::write(&path, image).await?;
+            self.version = version.to_vec();
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move { Ok(()) }
+    }
+}
@@ -0,0 +1,921 @@
+use crate::{
+    metrics, runtime, AuditLog, AuditOutcome, AuditRecord, ChecksumAlgorithm, ChecksumService,
+    DfuError, DrogueFirmwareService, Failure, FileService, FirmwareFileMeta, HealthCheck,
+    PollBackoff, ProgressReport, RetryPolicy, UpdatePhase, UpdateReport,
+};
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_update::{
+    service::InMemory, DeviceStatus, FirmwareDevice, FirmwareStatus, FirmwareUpdater,
+    UpdateService, UpdaterConfig,
+};
+use futures::future::{Either, LocalBoxFuture};
+use std::time::Duration;
+
+/// Where an UpdateRunner gets the firmware from.
+pub enum UpdateSource {
+    /// A firmware image held in memory, for instance read from a file or downloaded.
+    InMemory {
+        metadata: FirmwareFileMeta,
+        data: Vec<u8>,
+    },
+    /// A single image firmware read from a file as it is transferred, for images too large
+    /// to hold in memory.
+    File {
+        metadata: FirmwareFileMeta,
+        service: FileService,
+    },
+    /// Drogue IoT Cloud, which decides which firmware the device should run.
+    Cloud(DrogueFirmwareService),
+    /// Drogue IoT Cloud, reached through its MQTT endpoint.
+    #[cfg(feature = "mqtt")]
+    CloudMqtt(crate::DrogueMqttService),
+}
+
+/// Summary of an update about to replace the firmware of a device.
+#[derive(Debug, Clone)]
+pub struct UpdateSummary {
+    pub current_version: String,
+    pub target_version: String,
+    pub size: usize,
+    /// Whether the target version is older than the current one.
+    pub downgrade: bool,
+}
+
+/// Hooks called while an update is running, for instance to report progress.
+pub trait UpdateHooks {
+    /// Called before the firmware of the device is replaced, resolving to false to abort the
+    /// update. Not called for Drogue IoT Cloud, as it decides which firmware a device should
+    /// run.
+    fn confirm(&mut self, summary: &UpdateSummary) -> LocalBoxFuture<'static, bool> {
+        let _ = summary;
+        Box::pin(futures::future::ready(true))
+    }
+
+    /// Called when the status of the device has been read for the first time.
+    fn connected(&mut self, version: &[u8]) {
+        let _ = version;
+    }
+
+    /// Called when the device has started receiving a firmware of the given version.
+    fn started(&mut self, version: &[u8]) {
+        let _ = version;
+    }
+
+    /// Called when the device has been told to swap to the written firmware.
+    fn swapped(&mut self, version: &[u8]) {
+        let _ = version;
+    }
+
+    /// Called when the device is in sync with the firmware source.
+    fn synced(&mut self, version: &[u8]) {
+        let _ = version;
+    }
+
+    /// Called when the device has a partial transfer which is continued at `offset`.
+    fn resuming(&mut self, offset: usize, size: usize) {
+        let _ = (offset, size);
+    }
+
+    /// Called when the device has a partial transfer up to `offset` which is restarted.
+    fn restarting(&mut self, offset: usize) {
+        let _ = offset;
+    }
+
+    /// Called before transferring an image of a multi-image firmware.
+    fn image(&mut self, name: &str, version: &str) {
+        let _ = (name, version);
+    }
+
+    /// Called before transferring a firmware image of `size` bytes to the device.
+    fn transferring(&mut self, size: usize) {
+        let _ = size;
+    }
+
+    /// Called when a block of firmware has been written to the device.
+    fn written(&mut self, offset: u32, len: usize) {
+        let _ = (offset, len);
+    }
+
+    /// Called when the update has finished.
+    fn finished(&mut self, result: &anyhow::Result<()>) {
+        let _ = result;
+    }
+}
+
+impl UpdateHooks for () {}
+
+/// How an update which finished successfully left the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateOutcome {
+    /// The device was told to swap to the firmware of the source.
+    Swapped,
+    /// The device already ran the firmware of the source.
+    UpToDate,
+}
+
+/// Runs a firmware update of a device until it is in sync with the source.
+pub struct UpdateRunner<F, H = ()> {
+    source: UpdateSource,
+    device: F,
+    resume: bool,
+    allow_downgrade: bool,
+    config: Option<UpdaterConfig>,
+    retry: RetryPolicy,
+    poll_backoff: Option<RetryPolicy>,
+    slot_size: Option<usize>,
+    audit: Option<AuditLog>,
+    report: bool,
+    progress: Option<Duration>,
+    health_check: Option<HealthCheck>,
+    hooks: H,
+}
+
+impl<F> UpdateRunner<F>
+where
+    F: FirmwareDevice,
+    F::Error: core::fmt::Debug + 'static,
+{
+    pub fn new(source: UpdateSource, device: F) -> Self {
+        Self {
+            source,
+            device,
+            resume: false,
+            allow_downgrade: false,
+            config: None,
+            retry: RetryPolicy::fixed(Duration::from_secs(1)),
+            poll_backoff: None,
+            slot_size: None,
+            audit: None,
+            report: false,
+            progress: None,
+            health_check: None,
+            hooks: (),
+        }
+    }
+}
+
+impl<F, H> UpdateRunner<F, H>
+where
+    F: FirmwareDevice,
+    F::Error: core::fmt::Debug + 'static,
+    H: UpdateHooks,
+{
+    /// Continue an interrupted transfer of the same version instead of restarting it.
+    pub fn with_resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Allow flashing a firmware older than the one running on the device. Versions are only
+    /// compared when both can be parsed as semantic versions, and not for Drogue IoT Cloud,
+    /// as it decides which firmware a device should run.
+    pub fn with_allow_downgrade(mut self, allow_downgrade: bool) -> Self {
+        self.allow_downgrade = allow_downgrade;
+        self
+    }
+
+    /// Override the timeout and backoff of the updater.
+    pub fn with_config(mut self, config: UpdaterConfig) -> Self {
+        self.config.replace(config);
+        self
+    }
+
+    /// Retry failed update steps according to the policy.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Back off according to the policy while Drogue IoT Cloud has no update for the device,
+    /// instead of polling at the backoff of the updater config.
+    pub fn with_poll_backoff(mut self, policy: RetryPolicy) -> Self {
+        self.poll_backoff.replace(policy);
+        self
+    }
+
+    /// Refuse firmware larger than the slot size reported by the device before transferring
+    /// it. Without a slot size, the one given in the metadata is used, if any.
+    ///
+    /// Only single image firmware is checked, and not firmware from Drogue IoT Cloud, as its
+    /// size is not known before the transfer starts.
+    pub fn with_slot_size(mut self, slot_size: Option<usize>) -> Self {
+        self.slot_size = slot_size;
+        self
+    }
+
+    /// Record the outcome of the update in the audit log.
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit.replace(audit);
+        self
+    }
+
+    /// Publish the outcome of updates from Drogue IoT Cloud back to the cloud as telemetry of
+    /// the device.
+    pub fn with_cloud_report(mut self, report: bool) -> Self {
+        self.report = report;
+        self
+    }
+
+    /// Publish the progress of updates from Drogue IoT Cloud back to the cloud as telemetry
+    /// of the device, at most once per interval while writing and whenever the phase changes.
+    pub fn with_cloud_progress(mut self, interval: Duration) -> Self {
+        self.progress.replace(interval);
+        self
+    }
+
+    /// Check that the device comes back healthy after it was updated, failing the update
+    /// otherwise. Devices which were already up to date are not checked.
+    pub fn with_health_check(mut self, check: HealthCheck) -> Self {
+        self.health_check.replace(check);
+        self
+    }
+
+    pub fn with_hooks<H2: UpdateHooks>(self, hooks: H2) -> UpdateRunner<F, H2> {
+        UpdateRunner {
+            source: self.source,
+            device: self.device,
+            resume: self.resume,
+            allow_downgrade: self.allow_downgrade,
+            config: self.config,
+            retry: self.retry,
+            poll_backoff: self.poll_backoff,
+            slot_size: self.slot_size,
+            audit: self.audit,
+            report: self.report,
+            progress: self.progress,
+            health_check: self.health_check,
+            hooks,
+        }
+    }
+
+    /// Run the update until the device is in sync.
+    pub async fn run(self) -> anyhow::Result<UpdateOutcome> {
+        self.run_until(futures::future::pending()).await
+    }
+
+    /// Run the update until the device is in sync, or abort it when `cancel` completes.
+    pub async fn run_until<C: Future<Output = ()>>(
+        self,
+        cancel: C,
+    ) -> anyhow::Result<UpdateOutcome> {
+        metrics::UPDATES_STARTED.inc();
+        let timer = metrics::UPDATE_DURATION.start_timer();
+        let started = std::time::Instant::now();
+        let target_version = match &self.source {
+            UpdateSource::InMemory { metadata, .. } | UpdateSource::File { metadata, .. } => {
+                Some(metadata.version.clone())
+            }
+            UpdateSource::Cloud(_) => None,
+            #[cfg(feature = "mqtt")]
+            UpdateSource::CloudMqtt(_) => None,
+        };
+        let reporter = match &self.source {
+            UpdateSource::Cloud(service) if self.report => Some(service.clone()),
+            _ => None,
+        };
+        let progress = match (&self.source, self.progress) {
+            (UpdateSource::Cloud(service), Some(interval)) => {
+                Some(CloudProgress::new(service.clone(), interval))
+            }
+            _ => None,
+        };
+
+        let mut device = Observed {
+            device: self.device,
+            hooks: self.hooks,
+            progress,
+            first_version: None,
+            last_version: None,
+            swapped_version: None,
+            confirmed: false,
+            failure: None,
+        };
+        let health_check = self.health_check;
+        let update = async {
+            let result = match self.source {
+                UpdateSource::InMemory { metadata, data } if metadata.images.is_empty() => {
+                    // Fail before starting, instead of when the device runs out of space
+                    if let Some(slot_size) = self.slot_size.or(metadata.slot_size) {
+                        if data.len() > slot_size {
+                            return Err(anyhow!(
+                                "firmware of {} bytes does not fit the firmware slot of {} bytes",
+                                data.len(),
+                                slot_size
+                            ));
+                        }
+                    }
+                    let config = self.config.unwrap_or_default();
+                    update_in_memory(
+                        metadata.version.as_bytes(),
+                        &data,
+                        metadata.checksum_algorithm,
+                        &mut device,
+                        self.resume,
+                        self.allow_downgrade,
+                        config,
+                        &self.retry,
+                    )
+                    .await
+                }
+                UpdateSource::File { metadata, service } => {
+                    if let Some(slot_size) = self.slot_size.or(metadata.slot_size) {
+                        if service.size() > slot_size {
+                            return Err(anyhow!(
+                                "firmware of {} bytes does not fit the firmware slot of {} bytes",
+                                service.size(),
+                                slot_size
+                            ));
+                        }
+                    }
+                    let config = self.config.unwrap_or_default();
+                    let size = service.size();
+                    update_from_service(
+                        metadata.version.as_bytes(),
+                        size,
+                        service,
+                        &mut device,
+                        self.resume,
+                        self.allow_downgrade,
+                        config,
+                        &self.retry,
+                    )
+                    .await
+                }
+                UpdateSource::InMemory { metadata, data } => {
+                    let config = self.config.unwrap_or_default();
+                    update_images(
+                        &metadata,
+                        &data,
+                        &mut device,
+                        self.resume,
+                        self.allow_downgrade,
+                        config,
+                        &self.retry,
+                    )
+                    .await
+                }
+                UpdateSource::Cloud(service) => {
+                    let config = self.config.unwrap_or(UpdaterConfig {
+                        timeout_ms: 30_000,
+                        backoff_ms: 5_000,
+                    });
+                    update_from_cloud(service, &mut device, config, &self.retry, self.poll_backoff)
+                        .await
+                        .map_err(|e| device.classify(e, Some(Failure::Transfer)))
+                }
+                #[cfg(feature = "mqtt")]
+                UpdateSource::CloudMqtt(service) => {
+                    let config = self.config.unwrap_or(UpdaterConfig {
+                        timeout_ms: 30_000,
+                        backoff_ms: 5_000,
+                    });
+                    update_from_cloud(service, &mut device, config, &self.retry, self.poll_backoff)
+                        .await
+                        .map_err(|e| device.classify(e, Some(Failure::Transfer)))
+                }
+            };
+            match (result, &health_check, device.swapped_version.clone()) {
+                (Ok(()), Some(check), Some(version)) => check.run(&mut device, &version).await,
+                (result, _, _) => result,
+            }
+        };
+        futures::pin_mut!(update, cancel);
+        let result = match futures::future::select(update, cancel).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(anyhow!("update cancelled").context(Failure::Cancelled)),
+        };
+
+        if result.is_ok() {
+            metrics::UPDATES_SUCCEEDED.inc();
+            timer.observe_duration();
+        } else {
+            metrics::UPDATES_FAILED.inc();
+            timer.stop_and_discard();
+        }
+        device.hooks.finished(&result);
+        let version =
+            |v: &Option<Vec<u8>>| v.as_ref().map(|v| String::from_utf8_lossy(v).to_string());
+        let outcome = if result.is_ok() {
+            AuditOutcome::Succeeded
+        } else {
+            AuditOutcome::Failed
+        };
+
+        if let Some(mut reporter) = reporter {
+            let report = UpdateReport {
+                outcome,
+                version: version(&device.last_version),
+                error: result.as_ref().err().map(|e| e.to_string()),
+                duration_ms: started.elapsed().as_millis() as u64,
+            };
+            if let Err(e) = reporter.report(&report).await {
+                log::warn!("Error reporting update to cloud: {:?}", e);
+            }
+        }
+
+        if let Some(audit) = &self.audit {
+            let record = AuditRecord {
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                device: audit.device().to_string(),
+                operator: audit.operator().to_string(),
+                previous_version: version(&device.first_version),
+                target_version,
+                final_version: version(&device.last_version),
+                outcome,
+                error: result.as_ref().err().map(|e| e.to_string()),
+                duration_ms: started.elapsed().as_millis() as u64,
+            };
+            // The update has happened regardless, so only report failing to record it
+            if let Err(e) = audit.append(&record) {
+                log::error!("Error writing audit log: {:?}", e);
+            }
+        }
+        result.map(|()| match device.swapped_version {
+            Some(_) => UpdateOutcome::Swapped,
+            None => UpdateOutcome::UpToDate,
+        })
+    }
+}
+
+/// Transfer the images of a multi-image firmware one after another.
+///
+/// The device reports the version of the last image it was updated with, so the images are
+/// skipped only if the device already runs the version of the last image.
+async fn update_images<F, H>(
+    metadata: &FirmwareFileMeta,
+    data: &[u8],
+    d: &mut Observed<F, H>,
+    resume: bool,
+    allow_downgrade: bool,
+    config: UpdaterConfig,
+    retry: &RetryPolicy,
+) -> Result<(), anyhow::Error>
+where
+    F: FirmwareDevice,
+    F::Error: core::fmt::Debug + 'static,
+    H: UpdateHooks,
+{
+    let mut images = Vec::new();
+    for image in &metadata.images {
+        let image_data = data
+            .get(image.offset..image.offset + image.size)
+            .ok_or_else(|| anyhow!("image {} exceeds the firmware size", image.name))?;
+        if !image.checksum.is_empty()
+            && !image
+                .checksum
+                .eq_ignore_ascii_case(&metadata.checksum_algorithm.hex(image_data))
+        {
+            return Err(anyhow!("checksum mismatch for image {}", image.name)
+                .context(Failure::Verification));
+        }
+        images.push((image, image_data));
+    }
+
+    let status = d.status().await.map_err(|e| {
+        let error = anyhow!("error reading device status: {:?}", e);
+        d.classify(error, None)
+    })?;
+    if let Some((last, _)) = images.last() {
+        if status.current_version.as_ref() == last.version.as_bytes() {
+            return Ok(());
+        }
+        if !allow_downgrade {
+            check_downgrade(status.current_version.as_ref(), last.version.as_bytes())?;
+        }
+        confirm(
+            d,
+            status.current_version.as_ref(),
+            last.version.as_bytes(),
+            data.len(),
+        )
+        .await?;
+    }
+
+    for (image, image_data) in images {
+        d.hooks.image(&image.name, &image.version);
+        update_in_memory(
+            image.version.as_bytes(),
+            image_data,
+            metadata.checksum_algorithm,
+            d,
+            resume,
+            true,
+            UpdaterConfig {
+                timeout_ms: config.timeout_ms,
+                backoff_ms: config.backoff_ms,
+            },
+            retry,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+async fn update_in_memory<F, H>(
+    version: &[u8],
+    data: &[u8],
+    algorithm: ChecksumAlgorithm,
+    d: &mut Observed<F, H>,
+    resume: bool,
+    allow_downgrade: bool,
+    config: UpdaterConfig,
+    retry: &RetryPolicy,
+) -> Result<(), anyhow::Error>
+where
+    F: FirmwareDevice,
+    F::Error: core::fmt::Debug + 'static,
+    H: UpdateHooks,
+{
+    let service = ChecksumService::new(InMemory::new(version, data), version, algorithm, data);
+    update_from_service(
+        version,
+        data.len(),
+        service,
+        d,
+        resume,
+        allow_downgrade,
+        config,
+        retry,
+    )
+    .await
+}
+
+/// Transfer the firmware of the given version and size served by the service.
+#[allow(clippy::too_many_arguments)]
+async fn update_from_service<S, F, H>(
+    version: &[u8],
+    size: usize,
+    service: S,
+    d: &mut Observed<F, H>,
+    resume: bool,
+    allow_downgrade: bool,
+    config: UpdaterConfig,
+    retry: &RetryPolicy,
+) -> Result<(), anyhow::Error>
+where
+    S: UpdateService,
+    S::Error: core::fmt::Debug,
+    F: FirmwareDevice,
+    F::Error: core::fmt::Debug + 'static,
+    H: UpdateHooks,
+{
+    let status = d.status().await.map_err(|e| {
+        let error = anyhow!("error reading device status: {:?}", e);
+        d.classify(error, None)
+    })?;
+    if !allow_downgrade {
+        check_downgrade(status.current_version.as_ref(), version)?;
+    }
+    if status.current_version.as_ref() != version {
+        confirm(d, status.current_version.as_ref(), version, size).await?;
+        d.hooks.transferring(size);
+    }
+    let partial = status.next_offset > 0
+        && status.current_version.as_ref() != version
+        && status.next_version.as_ref().map(|v| v.as_ref()) == Some(version);
+    if partial {
+        let offset = status.next_offset as usize;
+        if resume {
+            d.hooks.resuming(offset, size);
+        } else {
+            d.hooks.restarting(offset);
+            d.start(version).await.map_err(|e| {
+                let error = anyhow!("error restarting transfer: {:?}", e);
+                d.classify(error, Some(Failure::Transfer))
+            })?;
+        }
+    }
+
+    let mut updater = FirmwareUpdater::new(service, config);
+    run_updater(&mut updater, d, retry)
+        .await
+        .map_err(|e| d.classify(e, Some(Failure::Transfer)))
+}
+
+/// Ask the hooks to confirm replacing the firmware of the device, unless already confirmed
+/// for an earlier image of the update.
+async fn confirm<F, H: UpdateHooks>(
+    d: &mut Observed<F, H>,
+    current: &[u8],
+    target: &[u8],
+    size: usize,
+) -> Result<(), anyhow::Error> {
+    if d.confirmed {
+        return Ok(());
+    }
+    let summary = UpdateSummary {
+        current_version: String::from_utf8_lossy(current).to_string(),
+        target_version: String::from_utf8_lossy(target).to_string(),
+        size,
+        downgrade: is_downgrade(current, target),
+    };
+    if !d.hooks.confirm(&summary).await {
+        return Err(anyhow!("update was not confirmed"));
+    }
+    d.confirmed = true;
+    Ok(())
+}
+
+/// Refuse to replace the current version with an older one, if both are semantic versions.
+fn check_downgrade(current: &[u8], target: &[u8]) -> Result<(), anyhow::Error> {
+    if is_downgrade(current, target) {
+        return Err(anyhow!(
+            "refusing to downgrade device from version {} to {}",
+            String::from_utf8_lossy(current),
+            String::from_utf8_lossy(target)
+        ));
+    }
+    Ok(())
+}
+
+/// Whether the target version is older than the current one, if both are semantic versions.
+fn is_downgrade(current: &[u8], target: &[u8]) -> bool {
+    let parse = |v: &[u8]| {
+        let v = core::str::from_utf8(v).ok()?;
+        semver::Version::parse(v.strip_prefix('v').unwrap_or(v)).ok()
+    };
+    matches!((parse(current), parse(target)), (Some(current), Some(target)) if target < current)
+}
+
+/// Update the device from a cloud service, backing off according to `poll_backoff` while the
+/// cloud has no update, if given.
+async fn update_from_cloud<S, F>(
+    service: S,
+    d: &mut F,
+    config: UpdaterConfig,
+    retry: &RetryPolicy,
+    poll_backoff: Option<RetryPolicy>,
+) -> Result<(), anyhow::Error>
+where
+    S: UpdateService,
+    S::Error: core::fmt::Debug,
+    F: FirmwareDevice,
+    F::Error: core::fmt::Debug,
+{
+    match poll_backoff {
+        Some(policy) => {
+            let mut updater = FirmwareUpdater::new(PollBackoff::new(service, policy), config);
+            run_updater(&mut updater, d, retry).await
+        }
+        None => {
+            let mut updater = FirmwareUpdater::new(service, config);
+            run_updater(&mut updater, d, retry).await
+        }
+    }
+}
+
+/// Run the updater until the device is in sync, retrying errors according to the policy.
+pub(crate) async fn run_updater<S, F>(
+    updater: &mut FirmwareUpdater<S>,
+    d: &mut F,
+    retry: &RetryPolicy,
+) -> Result<(), anyhow::Error>
+where
+    S: UpdateService,
+    S::Error: core::fmt::Debug,
+    F: FirmwareDevice,
+    F::Error: core::fmt::Debug,
+{
+    let mut failures = 0;
+    loop {
+        match updater.run(d, &mut Timer).await {
+            Ok(DeviceStatus::Synced(_)) => return Ok(()),
+            Ok(_) => failures = 0,
+            Err(e) => {
+                failures += 1;
+                if !retry.retry(failures) {
+                    return Err(anyhow!("error updating device: {:?}", e));
+                }
+                log::warn!("Error updating device, retrying: {:?}", e);
+                retry.wait(failures).await;
+            }
+        }
+    }
+}
+
+/// A FirmwareDevice reporting the firmware written to the wrapped device, and remembering
+/// the versions it reported.
+struct Observed<F, H> {
+    device: F,
+    hooks: H,
+    progress: Option<CloudProgress>,
+    first_version: Option<Vec<u8>>,
+    last_version: Option<Vec<u8>>,
+    /// Version the device was last told to swap to.
+    swapped_version: Option<Vec<u8>>,
+    /// Whether the hooks confirmed replacing the firmware.
+    confirmed: bool,
+    /// Class of the error of the last device operation, if it failed with a classified error.
+    failure: Option<Failure>,
+}
+
+impl<F: FirmwareDevice, H> Observed<F, H>
+where
+    F::Error: 'static,
+{
+    /// Remember the class of the error of the device operation.
+    fn observe<T>(&mut self, result: Result<T, F::Error>) -> Result<T, F::Error> {
+        self.failure = result.as_ref().err().and_then(|e| {
+            let e = e as &dyn core::any::Any;
+            match e.downcast_ref::<DfuError>() {
+                Some(e) => e.failure(),
+                None => e.downcast_ref::<anyhow::Error>().and_then(Failure::of),
+            }
+        });
+        result
+    }
+
+    /// Add the class of the failed device operation to the error, or `fallback` if it is not
+    /// known.
+    fn classify(&self, error: anyhow::Error, fallback: Option<Failure>) -> anyhow::Error {
+        match self.failure.or(fallback) {
+            Some(failure) => error.context(failure),
+            None => error,
+        }
+    }
+}
+
+impl<F: FirmwareDevice, H: UpdateHooks> FirmwareDevice for Observed<F, H>
+where
+    F::Error: 'static,
+{
+    const MTU: usize = F::MTU;
+    type Version = F::Version;
+    type Error = F::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            let status = self.device.status().await;
+            let status = self.observe(status)?;
+            let version = status.current_version.as_ref().to_vec();
+            if self.first_version.is_none() {
+                self.hooks.connected(&version);
+                self.first_version.replace(version.clone());
+            }
+            self.last_version.replace(version);
+            Ok(status)
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            let result = self.device.start(version).await;
+            self.observe(result)?;
+            self.hooks.started(version);
+            if let Some(progress) = &mut self.progress {
+                progress.started(version).await;
+            }
+            Ok(())
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            let result = self.device.write(offset, data).await;
+            self.observe(result)?;
+            metrics::BYTES_TRANSFERRED.inc_by(data.len() as u64);
+            self.hooks.written(offset, data.len());
+            if let Some(progress) = &mut self.progress {
+                progress.offset = offset + data.len() as u32;
+                progress.publish(UpdatePhase::Writing).await;
+            }
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], checksum: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            let result = self.device.update(version, checksum).await;
+            self.observe(result)?;
+            self.swapped_version.replace(version.to_vec());
+            self.hooks.swapped(version);
+            if let Some(progress) = &mut self.progress {
+                progress.publish(UpdatePhase::Swapped).await;
+            }
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            let result = self.device.synced().await;
+            self.observe(result)?;
+            if let Some(version) = &self.last_version {
+                self.hooks.synced(version);
+            }
+            // Only devices which were updated have progress to report
+            if let Some(progress) = self.progress.as_mut().filter(|p| p.version.is_some()) {
+                progress.publish(UpdatePhase::Synced).await;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Publishes the progress of an update from Drogue IoT Cloud back to the cloud.
+struct CloudProgress {
+    service: DrogueFirmwareService,
+    interval: Duration,
+    published: Option<std::time::Instant>,
+    version: Option<Vec<u8>>,
+    size: Option<usize>,
+    offset: u32,
+}
+
+impl CloudProgress {
+    fn new(service: DrogueFirmwareService, interval: Duration) -> Self {
+        Self {
+            service,
+            interval,
+            published: None,
+            version: None,
+            size: None,
+            offset: 0,
+        }
+    }
+
+    async fn started(&mut self, version: &[u8]) {
+        self.size = self.service.firmware_size(version);
+        self.version.replace(version.to_vec());
+        self.offset = 0;
+        self.publish(UpdatePhase::Started).await;
+    }
+
+    /// Publish the progress, unless it was published less than the interval ago while writing.
+    async fn publish(&mut self, phase: UpdatePhase) {
+        let now = std::time::Instant::now();
+        if phase == UpdatePhase::Writing
+            && self
+                .published
+                .map(|t| now.duration_since(t) < self.interval)
+                .unwrap_or(false)
+        {
+            return;
+        }
+        self.published.replace(now);
+        let percent = match phase {
+            UpdatePhase::Swapped | UpdatePhase::Synced => Some(100),
+            _ => self.size.map(|size| match size {
+                0 => 100,
+                size => core::cmp::min(self.offset as usize * 100 / size, 100) as u8,
+            }),
+        };
+        let report = ProgressReport {
+            phase,
+            version: self
+                .version
+                .as_ref()
+                .map(|v| String::from_utf8_lossy(v).to_string()),
+            offset: self.offset,
+            size: self.size,
+            percent,
+        };
+        // Progress is informational, so failing to publish it does not fail the update
+        if let Err(e) = self.service.report_progress(&report).await {
+            log::warn!("Error reporting progress to cloud: {:?}", e);
+        }
+    }
+}
+
+/// A delay implementation using the timers of the runtime.
+pub struct Timer;
+
+impl embedded_hal_async::delay::DelayUs for Timer {
+    type Error = core::convert::Infallible;
+    type DelayUsFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm where Self: 'm;
+    fn delay_us(&mut self, i: u32) -> Self::DelayUsFuture<'_> {
+        async move {
+            runtime::sleep(Duration::from_micros(i as u64)).await;
+            Ok(())
+        }
+    }
+
+    type DelayMsFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm where Self: 'm;
+    fn delay_ms(&mut self, i: u32) -> Self::DelayMsFuture<'_> {
+        async move {
+            runtime::sleep(Duration::from_millis(i as u64)).await;
+            Ok(())
+        }
+    }
+}
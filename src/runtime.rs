@@ -0,0 +1,92 @@
+use core::future::Future;
+use core::pin::Pin;
+use futures::future::{select, Either};
+use lazy_static::lazy_static;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// A timer of the runtime. It must be Send, except on wasm32, where the timers of the browser
+/// are bound to the thread they are created on.
+#[cfg(not(target_arch = "wasm32"))]
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A timer of the runtime, which need not be Send on wasm32.
+#[cfg(target_arch = "wasm32")]
+pub type Sleep = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Timers of the async runtime driving updates, used by the update runner, retries and
+/// health checks so that they run on other executors than Tokio, such as async-std or smol:
+///
+/// ```ignore
+/// # use core::time::Duration;
+/// struct AsyncStd;
+///
+/// impl drgdfu::Runtime for AsyncStd {
+///     fn sleep(&self, duration: Duration) -> drgdfu::Sleep {
+///         Box::pin(async_std::task::sleep(duration))
+///     }
+/// }
+///
+/// drgdfu::set_runtime(AsyncStd);
+/// ```
+///
+/// In the browser, the runtime can use the timers of the window, such as
+/// `gloo_timers::future::sleep`.
+///
+/// The transports still need a Tokio runtime, as their drivers are built on it.
+pub trait Runtime: Send + Sync {
+    /// Complete after the duration has passed.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The Tokio runtime, used unless another runtime is set.
+#[cfg(feature = "host")]
+pub struct TokioRuntime;
+
+#[cfg(feature = "host")]
+impl Runtime for TokioRuntime {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Without the host, there is no runtime to fall back to, so one must be set before updating.
+#[cfg(not(feature = "host"))]
+struct NoRuntime;
+
+#[cfg(not(feature = "host"))]
+impl Runtime for NoRuntime {
+    fn sleep(&self, _: Duration) -> Sleep {
+        panic!("no runtime for timers, use set_runtime to set one")
+    }
+}
+
+lazy_static! {
+    static ref RUNTIME: RwLock<Arc<dyn Runtime>> = {
+        #[cfg(feature = "host")]
+        let runtime: Arc<dyn Runtime> = Arc::new(TokioRuntime);
+        #[cfg(not(feature = "host"))]
+        let runtime: Arc<dyn Runtime> = Arc::new(NoRuntime);
+        RwLock::new(runtime)
+    };
+}
+
+/// Use the runtime for the timers of updates started afterwards.
+pub fn set_runtime<R: Runtime + 'static>(runtime: R) {
+    *RUNTIME.write().unwrap() = Arc::new(runtime);
+}
+
+/// Complete after the duration has passed, using the timers of the runtime.
+pub(crate) fn sleep(duration: Duration) -> Sleep {
+    let runtime = RUNTIME.read().unwrap().clone();
+    runtime.sleep(duration)
+}
+
+/// Run the future until it completes, or return None if the duration passes first.
+pub(crate) async fn timeout<F: Future>(duration: Duration, future: F) -> Option<F::Output> {
+    futures::pin_mut!(future);
+    match select(future, sleep(duration)).await {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
@@ -0,0 +1,198 @@
+use crate::Failure;
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_io::asynch::{Read, Write};
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use tokio::time::{timeout, Duration};
+
+/// Flash offset of applications on SAMD21 boards with the Arduino bootloader. SAMD51 boards
+/// reserve 16 KiB for the bootloader, with applications starting at 0x4000.
+pub const SAMBA_APP_ADDRESS: u32 = 0x2000;
+
+/// RAM used to buffer firmware before copying it to flash.
+pub const SAMBA_BUFFER_ADDRESS: u32 = 0x2000_4000;
+
+/// Written to the AIRCR register to reset the device.
+const SYSRESETREQ: (u32, u32) = (0xE000_ED0C, 0x05FA_0004);
+
+/// Writes to flash are padded to a multiple of the largest flash page size of the supported
+/// devices.
+const PAGE_SIZE: usize = 512;
+
+/// A SAMD21 or SAMD51 device running a SAM-BA bootloader with the extended flash commands of
+/// the Arduino and UF2 bootloaders.
+///
+/// The bootloader has no notion of firmware versions, so the application area is erased and
+/// written whenever an update is started, and the device is reset afterwards.
+pub struct SambaBoard<T>
+where
+    T: Read + Write,
+{
+    transport: T,
+    address: u32,
+    buffer: u32,
+    version: Vec<u8>,
+    binary: bool,
+}
+
+impl<T> SambaBoard<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            address: SAMBA_APP_ADDRESS,
+            buffer: SAMBA_BUFFER_ADDRESS,
+            version: Vec::new(),
+            binary: false,
+        }
+    }
+
+    /// Write the firmware to the given flash offset, which must match the bootloader size.
+    pub fn with_address(mut self, address: u32) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Buffer firmware at the given RAM address, which must not be used by the bootloader.
+    pub fn with_buffer_address(mut self, buffer: u32) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    /// Switch the bootloader to binary mode, as used for transferring data.
+    async fn init(&mut self) -> anyhow::Result<()> {
+        if !self.binary {
+            self.send(b"N#").await?;
+            self.expect(b"\n\r", Duration::from_secs(1)).await?;
+            self.binary = true;
+        }
+        Ok(())
+    }
+
+    async fn erase(&mut self, address: u32) -> anyhow::Result<()> {
+        self.init().await?;
+        self.send(format!("X{:08X}#", address).as_bytes()).await?;
+        // Erasing the application area may take a while
+        self.expect(b"X\n\r", Duration::from_secs(30)).await
+    }
+
+    async fn write_flash(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        self.init().await?;
+        let mut block = data.to_vec();
+        block.resize((data.len() + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE, 0xFF);
+
+        // Transfer the data to RAM, then copy it to flash
+        self.send(format!("S{:08X},{:08X}#", self.buffer, block.len()).as_bytes())
+            .await?;
+        self.send(&block).await?;
+        self.send(format!("Y{:08X},0#", self.buffer).as_bytes())
+            .await?;
+        self.expect(b"Y\n\r", Duration::from_secs(1)).await?;
+        self.send(format!("Y{:08X},{:08X}#", address, block.len()).as_bytes())
+            .await?;
+        self.expect(b"Y\n\r", Duration::from_secs(5)).await
+    }
+
+    async fn reset(&mut self) -> anyhow::Result<()> {
+        let (register, value) = SYSRESETREQ;
+        self.send(format!("W{:08X},{:08X}#", register, value).as_bytes())
+            .await
+    }
+
+    async fn send(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.transport
+            .write_all(data)
+            .await
+            .map_err(|e| anyhow!("error writing to bootloader: {:?}", e))?;
+        self.transport
+            .flush()
+            .await
+            .map_err(|e| anyhow!("error writing to bootloader: {:?}", e))
+    }
+
+    async fn expect(&mut self, expected: &[u8], wait: Duration) -> anyhow::Result<()> {
+        let mut response = vec![0; expected.len()];
+        timeout(wait, self.transport.read_exact(&mut response))
+            .await
+            .map_err(|_| {
+                anyhow!("timeout waiting for bootloader").context(Failure::ConnectTimeout)
+            })?
+            .map_err(|e| anyhow!("error reading from bootloader: {:?}", e))?;
+        if response == expected {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "unexpected response from bootloader: {:?}",
+                String::from_utf8_lossy(&response)
+            ))
+        }
+    }
+}
+
+impl<T> FirmwareDevice for SambaBoard<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    const MTU: usize = 4096;
+    type Version = Vec<u8>;
+    type Error = anyhow::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            self.init().await?;
+            Ok(FirmwareStatus {
+                current_version: self.version.clone(),
+                next_offset: 0,
+                next_version: None,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, _: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            log::info!("Erasing flash from {:#010x}", self.address);
+            self.erase(self.address).await
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move { self.write_flash(self.address + offset, data).await }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            log::info!("Resetting device");
+            self.reset().await?;
+            self.version = version.to_vec();
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move { Ok(()) }
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, Local, NaiveTime, Timelike, Weekday};
+use tokio::time::Duration;
+
+/// A period of local time during which updates may be applied, such as `02:00-04:00` or
+/// `Sat,Sun 22:00-06:00`.
+///
+/// Windows ending before they start span midnight, and belong to the day they start on.
+/// Without days, the window applies every day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MaintenanceWindow {
+    days: [bool; 7],
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl MaintenanceWindow {
+    /// Returns true if the window is open at the given time.
+    pub fn contains(&self, time: DateTime<Local>) -> bool {
+        let day = self.days[time.weekday().num_days_from_monday() as usize];
+        let previous_day = self.days[time.weekday().pred().num_days_from_monday() as usize];
+        let t = time.time();
+        if self.start <= self.end {
+            day && self.start <= t && t < self.end
+        } else {
+            (day && t >= self.start) || (previous_day && t < self.end)
+        }
+    }
+}
+
+impl core::str::FromStr for MaintenanceWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, times) = match s.trim().rsplit_once(' ') {
+            Some((days, times)) => (parse_days(days.trim())?, times),
+            None => ([true; 7], s.trim()),
+        };
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| anyhow!("invalid maintenance window '{}', expected HH:MM-HH:MM", s))?;
+        Ok(Self {
+            days,
+            start: NaiveTime::parse_from_str(start, "%H:%M")?,
+            end: NaiveTime::parse_from_str(end, "%H:%M")?,
+        })
+    }
+}
+
+/// Parse days such as `Mon-Fri` or `Sat,Sun`.
+fn parse_days(s: &str) -> anyhow::Result<[bool; 7]> {
+    let mut days = [false; 7];
+    for range in s.split(',') {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let first = first
+            .parse::<Weekday>()
+            .map_err(|_| anyhow!("invalid day '{}'", first))?
+            .num_days_from_monday();
+        let last = last
+            .parse::<Weekday>()
+            .map_err(|_| anyhow!("invalid day '{}'", last))?
+            .num_days_from_monday();
+        let mut day = first;
+        loop {
+            days[day as usize] = true;
+            if day == last {
+                break;
+            }
+            day = (day + 1) % 7;
+        }
+    }
+    Ok(days)
+}
+
+/// Wait until one of the windows is open, returning immediately if there are none.
+pub async fn wait_for_window(windows: &[MaintenanceWindow]) {
+    if windows.is_empty() {
+        return;
+    }
+    let mut logged = false;
+    loop {
+        let now = Local::now();
+        if windows.iter().any(|w| w.contains(now)) {
+            return;
+        }
+        if !logged {
+            log::info!("Waiting for a maintenance window to open");
+            logged = true;
+        }
+        // Windows are given in minutes, so wait until the start of the next one
+        tokio::time::sleep(Duration::from_secs(60 - now.second() as u64)).await;
+    }
+}
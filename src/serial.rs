@@ -0,0 +1,1017 @@
+use crate::checksum::hex;
+use crate::{
+    decode_response, decode_status, ChecksumMismatch, DfuError, Failure, RetryPolicy,
+    MAX_VERSION_LEN,
+};
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_io::adapters::FromTokio;
+use embedded_io::asynch::{Read, Write};
+use embedded_update::{Command, FirmwareDevice, FirmwareStatus};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::time::{Duration, Instant};
+use tokio_serial::{ClearBuffer, SerialPort as _, SerialPortType, SerialStream, UsbPortInfo};
+
+/// A serial port usable with the embedded-io traits.
+pub type SerialPort = FromTokio<SerialStream>;
+
+/// Open a serial port for use with the serial protocol.
+pub fn open_port(port: &Path) -> anyhow::Result<SerialPort> {
+    open_port_with_flow_control(port, FlowControl::None)
+}
+
+/// Open a serial port for use with the serial protocol, using the given flow control.
+pub fn open_port_with_flow_control(
+    port: &Path,
+    flow_control: FlowControl,
+) -> anyhow::Result<SerialPort> {
+    let p: String = port
+        .to_str()
+        .ok_or_else(|| anyhow!("invalid port name"))?
+        .to_string();
+    let builder = tokio_serial::new(p, 115200).flow_control(flow_control.into());
+    match SerialStream::open(&builder) {
+        Ok(stream) => Ok(FromTokio::new(stream)),
+        Err(e) => match e.kind {
+            tokio_serial::ErrorKind::NoDevice
+            | tokio_serial::ErrorKind::Io(std::io::ErrorKind::NotFound) => {
+                Err(anyhow::Error::new(e).context(Failure::DeviceNotFound))
+            }
+            _ => Err(e.into()),
+        },
+    }
+}
+
+/// Flow control used on a serial port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FlowControl {
+    None,
+    /// RTS/CTS, for devices with small receive buffers.
+    Hardware,
+    /// XON/XOFF. Frames are binary and may contain the XON and XOFF characters, so this is only
+    /// usable with devices escaping them.
+    Software,
+}
+
+impl Default for FlowControl {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl From<FlowControl> for tokio_serial::FlowControl {
+    fn from(flow_control: FlowControl) -> Self {
+        match flow_control {
+            FlowControl::None => Self::None,
+            FlowControl::Hardware => Self::Hardware,
+            FlowControl::Software => Self::Software,
+        }
+    }
+}
+
+impl core::str::FromStr for FlowControl {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "hardware" | "rts-cts" => Ok(Self::Hardware),
+            "software" | "xon-xoff" => Ok(Self::Software),
+            _ => Err(anyhow!("unknown flow control '{}'", s)),
+        }
+    }
+}
+
+/// How to reset a device into its bootloader before updating it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnterBootloader {
+    /// Reset the device with RTS while selecting the bootloader with DTR, as done by the
+    /// auto-reset circuits of ESP32 and similar boards.
+    DtrRts,
+}
+
+impl EnterBootloader {
+    /// Toggle the modem control lines of the port to reset the device into its bootloader.
+    pub async fn enter(&self, port: &mut SerialStream) -> anyhow::Result<()> {
+        match self {
+            Self::DtrRts => {
+                port.write_data_terminal_ready(false)?;
+                port.write_request_to_send(true)?;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                port.write_data_terminal_ready(true)?;
+                port.write_request_to_send(false)?;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                port.write_data_terminal_ready(false)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl core::str::FromStr for EnterBootloader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dtr-rts" => Ok(Self::DtrRts),
+            _ => Err(anyhow!("unknown bootloader entry method '{}'", s)),
+        }
+    }
+}
+
+/// Request a reset into the bootloader by opening the port at 1200 baud and dropping DTR.
+pub fn reset_1200(port: &Path) -> anyhow::Result<()> {
+    let name = port.to_str().ok_or_else(|| anyhow!("invalid port name"))?;
+    let mut stream = SerialStream::open(&tokio_serial::new(name, 1200))?;
+    stream.write_data_terminal_ready(false)?;
+    Ok(())
+}
+
+/// Reset a device into its bootloader by opening its port at 1200 baud, as done by the
+/// Arduino IDE. Returns the port of the bootloader, which may appear under a different name.
+pub async fn touch_1200(port: &Path, wait: Duration) -> anyhow::Result<PathBuf> {
+    let before: Vec<String> = tokio_serial::available_ports()?
+        .into_iter()
+        .map(|p| p.port_name)
+        .collect();
+    reset_1200(port)?;
+
+    let started = Instant::now();
+    while started.elapsed() < wait {
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        for p in tokio_serial::available_ports().unwrap_or_default() {
+            if !before.contains(&p.port_name) {
+                log::info!("Bootloader appeared at {}", p.port_name);
+                return Ok(PathBuf::from(p.port_name));
+            }
+        }
+    }
+    // Some devices keep the name of the port
+    if port.exists() {
+        Ok(port.to_path_buf())
+    } else {
+        Err(anyhow!(
+            "bootloader port did not appear within {:?} after reset",
+            wait
+        )
+        .context(Failure::DeviceNotFound))
+    }
+}
+
+/// Frame size used by the serial protocol.
+pub const FRAME_SIZE: usize = 1024;
+
+/// Version of the serial protocol implemented by the host.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Magic carried by every `SerialRequest`, so that devices can tell requests apart from update
+/// commands decoding as a request.
+pub const HANDSHAKE_MAGIC: [u8; 4] = *b"DRGD";
+
+/// The device answers `SerialRequest::Read`.
+pub const FEATURE_READ: u32 = 1 << 0;
+/// The device answers `SerialRequest::SlotSize`.
+pub const FEATURE_SLOT_SIZE: u32 = 1 << 1;
+/// The device answers `SerialRequest::Checksum`.
+pub const FEATURE_CHECKSUM: u32 = 1 << 2;
+
+/// Requests sent from the host in addition to the update commands.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SerialRequest {
+    /// Read back `len` bytes of firmware starting at `offset`.
+    Read {
+        magic: [u8; 4],
+        offset: u32,
+        len: u32,
+    },
+    /// Query the size of the firmware slot.
+    SlotSize { magic: [u8; 4] },
+    /// Start of the handshake, sent when the port is opened. The device answers with its
+    /// capabilities, followed by its status.
+    Hello { magic: [u8; 4], protocol: u32 },
+    /// Compute the SHA-256 checksum of the first `len` bytes written to the firmware slot,
+    /// sent before swapping firmware.
+    Checksum { magic: [u8; 4], len: u32 },
+}
+
+/// Responses sent by the device to a `SerialRequest`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum SerialResponse<'a> {
+    /// Firmware data read at the given offset.
+    Data {
+        offset: u32,
+        #[serde(borrow)]
+        data: &'a [u8],
+    },
+    /// Size of the firmware slot in bytes.
+    SlotSize { size: u32 },
+    /// Answer to `SerialRequest::Hello`.
+    Capabilities {
+        magic: [u8; 4],
+        protocol: u32,
+        /// Largest block of firmware the device accepts in a write.
+        mtu: u32,
+        /// Bitmask of the `FEATURE_` flags supported by the device.
+        features: u32,
+    },
+    /// Answer to `SerialRequest::Checksum`.
+    Checksum { magic: [u8; 4], checksum: [u8; 32] },
+}
+
+/// Capabilities reported by a device during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub protocol: u32,
+    pub mtu: u32,
+    pub features: u32,
+}
+
+impl Capabilities {
+    /// Returns true if the device supports the `FEATURE_` flag.
+    pub fn supports(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+}
+
+/// Reads firmware back from a device and queries its slot size using the serial protocol.
+pub struct SerialReader<T>
+where
+    T: Read + Write,
+{
+    transport: T,
+    buf: [u8; FRAME_SIZE],
+}
+
+impl<T> SerialReader<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            buf: [0; FRAME_SIZE],
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Read the size of the firmware slot of the device.
+    pub async fn slot_size(&mut self) -> anyhow::Result<u32> {
+        self.buf.fill(0);
+        postcard::to_slice(
+            &SerialRequest::SlotSize {
+                magic: HANDSHAKE_MAGIC,
+            },
+            &mut self.buf,
+        )?;
+        self.transport
+            .write_all(&self.buf)
+            .await
+            .map_err(|e| anyhow!("error writing request: {:?}", e))?;
+
+        self.transport
+            .read_exact(&mut self.buf)
+            .await
+            .map_err(|e| anyhow!("error reading response: {:?}", e))?;
+        match decode_response(&self.buf)? {
+            SerialResponse::SlotSize { size } => Ok(size),
+            r => Err(anyhow!("unexpected response {:?}", r)),
+        }
+    }
+
+    /// Read `len` bytes of firmware starting at `offset`.
+    pub async fn read_firmware(&mut self, mut offset: u32, len: usize) -> anyhow::Result<Vec<u8>> {
+        let mut firmware = Vec::with_capacity(len);
+        while firmware.len() < len {
+            let remaining = (len - firmware.len()) as u32;
+            let request = SerialRequest::Read {
+                magic: HANDSHAKE_MAGIC,
+                offset,
+                len: core::cmp::min(remaining, (FRAME_SIZE / 2) as u32),
+            };
+            self.buf.fill(0);
+            postcard::to_slice(&request, &mut self.buf)?;
+            self.transport
+                .write_all(&self.buf)
+                .await
+                .map_err(|e| anyhow!("error writing request: {:?}", e))?;
+
+            self.transport
+                .read_exact(&mut self.buf)
+                .await
+                .map_err(|e| anyhow!("error reading response: {:?}", e))?;
+            match decode_response(&self.buf)? {
+                SerialResponse::Data { offset: o, data } if o == offset && !data.is_empty() => {
+                    firmware.extend_from_slice(data);
+                    offset += data.len() as u32;
+                }
+                SerialResponse::Data { offset: o, .. } => {
+                    return Err(anyhow!(
+                        "unexpected response for offset {}, expected {}",
+                        o,
+                        offset
+                    ));
+                }
+                r => return Err(anyhow!("unexpected response {:?}", r)),
+            }
+        }
+        firmware.truncate(len);
+        Ok(firmware)
+    }
+}
+
+/// Largest block of firmware sent in a frame, leaving room for the rest of the command.
+const SERIAL_MTU: usize = 968;
+
+/// A FirmwareDevice using the serial protocol over any transport implementing the embedded-io
+/// traits.
+///
+/// Frames are always read and written whole, and status frames are validated before use, so
+/// that a misbehaving device results in an error rather than a corrupt update.
+pub struct SerialUpdater<T>
+where
+    T: Read + Write,
+{
+    transport: T,
+    buf: [u8; FRAME_SIZE],
+    status: FirmwareStatus<Vec<u8>>,
+    mtu: usize,
+    capabilities: Option<Capabilities>,
+    /// Checksum of the firmware written since the update started, unless it was resumed.
+    hasher: Option<Sha256>,
+    hashed: u32,
+    /// Version whose written firmware did not match, to be written again from the start.
+    rejected: Option<Vec<u8>>,
+}
+
+impl<T> SerialUpdater<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            buf: [0; FRAME_SIZE],
+            status: FirmwareStatus {
+                current_version: Vec::new(),
+                next_offset: 0,
+                next_version: None,
+            },
+            mtu: SERIAL_MTU,
+            capabilities: None,
+            hasher: None,
+            hashed: 0,
+            rejected: None,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Capabilities of the device, if the handshake was performed.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.capabilities
+    }
+
+    /// Exchange capabilities with the device, failing if it uses an incompatible version of
+    /// the protocol. Firmware is written in blocks no larger than the device accepts
+    /// afterwards.
+    ///
+    /// Frames received before the answer, such as a status sent when the port was opened,
+    /// are discarded.
+    pub async fn handshake(&mut self) -> anyhow::Result<Capabilities> {
+        self.buf.fill(0);
+        postcard::to_slice(
+            &SerialRequest::Hello {
+                magic: HANDSHAKE_MAGIC,
+                protocol: PROTOCOL_VERSION,
+            },
+            &mut self.buf,
+        )?;
+        self.transport
+            .write_all(&self.buf)
+            .await
+            .map_err(|e| anyhow!("error writing handshake: {:?}", e))?;
+
+        let capabilities = loop {
+            self.transport
+                .read_exact(&mut self.buf)
+                .await
+                .map_err(|e| anyhow!("error reading handshake: {:?}", e))?;
+            match decode_response(&self.buf) {
+                Ok(SerialResponse::Capabilities {
+                    magic,
+                    protocol,
+                    mtu,
+                    features,
+                }) if magic == HANDSHAKE_MAGIC => {
+                    break Capabilities {
+                        protocol,
+                        mtu,
+                        features,
+                    }
+                }
+                _ => log::debug!("Ignoring frame received before handshake"),
+            }
+        };
+        log::debug!("Device capabilities: {:?}", capabilities);
+
+        if capabilities.protocol != PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "device uses serial protocol version {}, but version {} is supported",
+                capabilities.protocol,
+                PROTOCOL_VERSION
+            ));
+        }
+        self.mtu = core::cmp::min(capabilities.mtu as usize, SERIAL_MTU);
+        self.capabilities.replace(capabilities);
+        Ok(capabilities)
+    }
+
+    /// Compare the checksum of the firmware written by the device with the firmware sent, so
+    /// that corrupted firmware is not swapped to.
+    async fn verify(&mut self) -> anyhow::Result<()> {
+        let expected = match self.hasher.take() {
+            Some(hasher) if self.hashed == self.status.next_offset => hasher.finalize(),
+            _ => {
+                log::debug!("Update was resumed, not verifying the firmware checksum");
+                return Ok(());
+            }
+        };
+
+        self.buf.fill(0);
+        postcard::to_slice(
+            &SerialRequest::Checksum {
+                magic: HANDSHAKE_MAGIC,
+                len: self.hashed,
+            },
+            &mut self.buf,
+        )?;
+        self.transport
+            .write_all(&self.buf)
+            .await
+            .map_err(|e| anyhow!("error writing checksum request: {:?}", e))?;
+
+        let checksum = loop {
+            self.transport
+                .read_exact(&mut self.buf)
+                .await
+                .map_err(|e| anyhow!("error reading checksum: {:?}", e))?;
+            match decode_response(&self.buf) {
+                Ok(SerialResponse::Checksum { magic, checksum }) if magic == HANDSHAKE_MAGIC => {
+                    break checksum
+                }
+                _ => log::debug!("Ignoring frame received before checksum"),
+            }
+        };
+        if checksum[..] != expected[..] {
+            self.rejected = self.status.next_version.clone();
+            return Err(ChecksumMismatch {
+                expected: hex(&expected),
+                actual: hex(&checksum),
+            }
+            .into());
+        }
+        log::debug!("Verified firmware checksum {}", hex(&checksum));
+        Ok(())
+    }
+
+    async fn send(
+        transport: &mut T,
+        buf: &mut [u8; FRAME_SIZE],
+        command: &Command<'_>,
+    ) -> anyhow::Result<()> {
+        // Clear the previous frame, so that no stale data is sent after the command
+        buf.fill(0);
+        postcard::to_slice(command, buf)?;
+        transport
+            .write_all(buf)
+            .await
+            .map_err(|e| anyhow!("error writing command: {:?}", e))?;
+        transport
+            .flush()
+            .await
+            .map_err(|e| anyhow!("error writing command: {:?}", e))?;
+        Ok(())
+    }
+}
+
+impl<T> FirmwareDevice for SerialUpdater<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    const MTU: usize = SERIAL_MTU;
+    type Version = Vec<u8>;
+    type Error = DfuError;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            self.transport
+                .read_exact(&mut self.buf)
+                .await
+                .map_err(|e| anyhow!("error reading status: {:?}", e))?;
+            let status = decode_status(&self.buf).map_err(|e| DfuError::Protocol(e.into()))?;
+            self.status.current_version = status.version.to_vec();
+            match status.update {
+                // Report no update in progress, so that the firmware is written again
+                Some(update) if self.rejected.as_deref() == Some(&update.version[..]) => {
+                    self.status.next_offset = 0;
+                    self.status.next_version = None;
+                }
+                Some(update) => {
+                    self.status.next_offset = update.offset;
+                    self.status.next_version.replace(update.version.to_vec());
+                }
+                None => {
+                    self.status.next_offset = 0;
+                    self.status.next_version = None;
+                }
+            }
+            Ok(self.status.clone())
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            if version.len() > MAX_VERSION_LEN {
+                return Err(DfuError::Protocol(anyhow!(
+                    "version of {} bytes exceeds {} bytes",
+                    version.len(),
+                    MAX_VERSION_LEN
+                )));
+            }
+            self.status.next_offset = 0;
+            self.status.next_version.replace(version.to_vec());
+            self.hasher.replace(Sha256::new());
+            self.hashed = 0;
+            self.rejected = None;
+            Ok(())
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            if data.len() > SERIAL_MTU {
+                return Err(DfuError::Protocol(anyhow!(
+                    "block of {} bytes exceeds {} bytes",
+                    data.len(),
+                    SERIAL_MTU
+                )));
+            }
+            let version = self
+                .status
+                .next_version
+                .as_ref()
+                .ok_or_else(|| anyhow!("write before update was started"))?;
+            // Split the block if the device accepts less than the updater sends
+            let mut offset = offset;
+            for block in data.chunks(self.mtu) {
+                let command = Command::new_write(version, offset, block, None);
+                Self::send(&mut self.transport, &mut self.buf, &command).await?;
+                match &mut self.hasher {
+                    Some(hasher) if offset == self.hashed => {
+                        hasher.update(block);
+                        self.hashed += block.len() as u32;
+                    }
+                    _ => self.hasher = None,
+                }
+                offset += block.len() as u32;
+            }
+            self.status.next_offset = offset;
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], checksum: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            if self
+                .capabilities
+                .map(|c| c.supports(FEATURE_CHECKSUM))
+                .unwrap_or(false)
+            {
+                self.verify().await?;
+            }
+            let command = Command::new_swap(version, checksum, None);
+            Ok(Self::send(&mut self.transport, &mut self.buf, &command).await?)
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            let command = Command::new_sync(&self.status.current_version, None, None);
+            Ok(Self::send(&mut self.transport, &mut self.buf, &command).await?)
+        }
+    }
+}
+
+/// A serial FirmwareDevice which reopens the port when the device resets, for instance
+/// after swapping firmware.
+pub struct SerialBoard {
+    port: PathBuf,
+    usb: Option<UsbPortInfo>,
+    serial: Option<SerialUpdater<SerialPort>>,
+    reconnect_timeout: Duration,
+    retry: RetryPolicy,
+    enter_bootloader: Option<EnterBootloader>,
+    bootloader_magic: Vec<u8>,
+    entered_bootloader: bool,
+    handshake: Option<Duration>,
+    flow_control: FlowControl,
+}
+
+impl SerialBoard {
+    pub fn new(port: &Path) -> anyhow::Result<Self> {
+        let serial = SerialUpdater::new(open_port(port)?);
+        Ok(Self {
+            port: port.to_path_buf(),
+            usb: usb_info(port),
+            serial: Some(serial),
+            reconnect_timeout: Duration::from_secs(60),
+            retry: RetryPolicy::fixed(Duration::from_secs(1)),
+            enter_bootloader: None,
+            bootloader_magic: Vec::new(),
+            entered_bootloader: false,
+            handshake: None,
+            flow_control: FlowControl::None,
+        })
+    }
+
+    /// Reset the device into its bootloader before the first operation.
+    pub fn with_enter_bootloader(mut self, enter: EnterBootloader) -> Self {
+        self.enter_bootloader.replace(enter);
+        self
+    }
+
+    /// Send the bytes to the device before the first operation, for devices which enter
+    /// their bootloader when receiving a magic sequence.
+    pub fn with_bootloader_magic(mut self, magic: Vec<u8>) -> Self {
+        self.bootloader_magic = magic;
+        self
+    }
+
+    /// Fail if the port does not reappear within the timeout after the device resets.
+    pub fn with_reconnect_timeout(mut self, timeout: Duration) -> Self {
+        self.reconnect_timeout = timeout;
+        self
+    }
+
+    /// Retry opening the port according to the policy while waiting for it to reappear.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Use the given flow control on the port, reopening it if needed.
+    pub fn with_flow_control(mut self, flow_control: FlowControl) -> anyhow::Result<Self> {
+        if flow_control != self.flow_control {
+            self.flow_control = flow_control;
+            // Close the port first, as it may not be opened twice
+            self.serial = None;
+            self.serial
+                .replace(SerialUpdater::new(self.open_port(&self.port)?));
+        }
+        Ok(self)
+    }
+
+    fn open_port(&self, port: &Path) -> anyhow::Result<SerialPort> {
+        open_port_with_flow_control(port, self.flow_control)
+    }
+
+    /// Perform the handshake whenever the port is opened, failing if the device does not
+    /// answer within the timeout.
+    pub fn with_handshake(mut self, timeout: Duration) -> Self {
+        self.handshake.replace(timeout);
+        self
+    }
+
+    /// Capabilities of the device on the open port, if the handshake was performed.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        self.serial.as_ref().and_then(|s| s.capabilities())
+    }
+
+    async fn serial(&mut self) -> anyhow::Result<&mut SerialUpdater<SerialPort>> {
+        if !self.entered_bootloader
+            && (self.enter_bootloader.is_some() || !self.bootloader_magic.is_empty())
+        {
+            self.entered_bootloader = true;
+            self.enter_bootloader().await?;
+        }
+        if self.serial.is_none() {
+            self.reopen().await?;
+        }
+        let serial = self.serial.as_mut().unwrap();
+        if let Some(timeout) = self.handshake {
+            if serial.capabilities().is_none() {
+                match tokio::time::timeout(timeout, serial.handshake()).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => {
+                        self.serial = None;
+                        return Err(e);
+                    }
+                    Err(_) => {
+                        self.serial = None;
+                        return Err(anyhow!(
+                            "device did not answer the handshake within {:?}",
+                            timeout
+                        )
+                        .context(Failure::ConnectTimeout));
+                    }
+                }
+            }
+        }
+        Ok(self.serial.as_mut().unwrap())
+    }
+
+    async fn enter_bootloader(&mut self) -> anyhow::Result<()> {
+        // Close the port so that it can be used directly
+        self.serial = None;
+        let mut port = self.open_port(&self.port)?;
+        if let Some(enter) = self.enter_bootloader {
+            enter
+                .enter(port.inner_mut())
+                .await
+                .map_err(|e| e.context("error resetting device into bootloader"))?;
+        }
+        if !self.bootloader_magic.is_empty() {
+            port.write_all(&self.bootloader_magic)
+                .await
+                .map_err(|e| anyhow!("error writing bootloader magic: {:?}", e))?;
+            port.flush()
+                .await
+                .map_err(|e| anyhow!("error writing bootloader magic: {:?}", e))?;
+        }
+        log::info!("Reset {} into bootloader", self.port.display());
+        // The port may be re-enumerated by the bootloader
+        Ok(())
+    }
+
+    async fn reopen(&mut self) -> anyhow::Result<()> {
+        log::info!("Waiting for {} to reappear", self.port.display());
+        let started = Instant::now();
+        let mut attempts = 0;
+        loop {
+            // Give the device some time to reset and set up the port
+            attempts += 1;
+            self.retry.wait(attempts).await;
+            if let Some(port) = self.find_port() {
+                match self.open_port(&port) {
+                    Ok(mut p) => {
+                        // Discard what the device sent before it reset
+                        if let Err(e) = p.inner_mut().clear(ClearBuffer::Input) {
+                            log::debug!("Error clearing {}: {:?}", port.display(), e);
+                        }
+                        log::info!("Reopened {}", port.display());
+                        self.serial.replace(SerialUpdater::new(p));
+                        self.port = port;
+                        return Ok(());
+                    }
+                    Err(e) => log::debug!("Error opening {}: {:?}", port.display(), e),
+                }
+            }
+            if started.elapsed() >= self.reconnect_timeout || !self.retry.retry(attempts) {
+                return Err(anyhow!(
+                    "port {} did not reappear within {:?}",
+                    self.port.display(),
+                    self.reconnect_timeout
+                )
+                .context(Failure::DeviceNotFound));
+            }
+        }
+    }
+
+    /// Locate the port by USB id if known, as the port name may change when the device resets.
+    fn find_port(&self) -> Option<PathBuf> {
+        if let Some(usb) = &self.usb {
+            for p in tokio_serial::available_ports().unwrap_or_default() {
+                if let SerialPortType::UsbPort(info) = p.port_type {
+                    if info.vid == usb.vid
+                        && info.pid == usb.pid
+                        && info.serial_number == usb.serial_number
+                    {
+                        return Some(PathBuf::from(p.port_name));
+                    }
+                }
+            }
+        }
+        if self.port.exists() {
+            Some(self.port.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Query the size of the firmware slot of the device, after entering its bootloader if
+    /// configured. Returns none if the device does not answer within the timeout, as devices
+    /// need not support the query.
+    pub async fn slot_size(&mut self, timeout: Duration) -> anyhow::Result<Option<u32>> {
+        self.serial().await?;
+        // The port of the update protocol is not accessible, so query on a port of our own
+        self.serial = None;
+        let mut reader = SerialReader::new(self.open_port(&self.port)?);
+        let size = match tokio::time::timeout(timeout, reader.slot_size()).await {
+            Ok(size) => Some(size?),
+            Err(_) => {
+                log::debug!("Device did not report its slot size");
+                None
+            }
+        };
+        self.serial.replace(SerialUpdater::new(reader.into_inner()));
+        Ok(size)
+    }
+
+    fn closed<T>(&mut self, result: Result<T, DfuError>) -> Result<T, DfuError> {
+        if result.is_err() {
+            // The port is likely gone, reopen it on the next operation
+            self.serial = None;
+        }
+        result
+    }
+}
+
+/// USB devices known to run firmware that can be updated with drgdfu, given as the vendor
+/// and product id, a description and the device subcommand to use.
+const KNOWN_USB_DEVICES: &[(u16, u16, &str, &str)] = &[
+    (0x03eb, 0x6124, "Atmel SAM-BA bootloader", "samba"),
+    (0x2341, 0x0036, "Arduino Leonardo bootloader", "avr109"),
+    (0x2341, 0x0037, "Arduino Micro bootloader", "avr109"),
+    (0x303a, 0x1001, "Espressif USB serial/JTAG", "esp"),
+    (
+        0x10c4,
+        0xea60,
+        "CP210x USB to UART bridge (ESP32 boards)",
+        "esp",
+    ),
+    (
+        0x1a86,
+        0x7523,
+        "CH340 USB to UART bridge (ESP32 boards)",
+        "esp",
+    ),
+    (0x0483, 0x374b, "ST-LINK virtual COM port", "stm32"),
+];
+
+/// A serial port which may be connected to a device to update.
+#[derive(Debug, Clone, Serialize)]
+pub struct PortInfo {
+    pub name: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+    /// The known firmware the device of the port runs, if any.
+    pub known: Option<KnownFirmware>,
+}
+
+/// Firmware known to be updatable with drgdfu.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct KnownFirmware {
+    pub description: &'static str,
+    /// The device subcommand to update the firmware with.
+    pub device: &'static str,
+}
+
+/// List the serial ports of the system, recognizing USB devices running known firmware.
+pub fn list_ports() -> anyhow::Result<Vec<PortInfo>> {
+    let mut ports: Vec<PortInfo> = tokio_serial::available_ports()?
+        .into_iter()
+        .map(|p| match p.port_type {
+            SerialPortType::UsbPort(info) => PortInfo {
+                name: p.port_name,
+                vid: Some(info.vid),
+                pid: Some(info.pid),
+                manufacturer: info.manufacturer,
+                product: info.product,
+                serial_number: info.serial_number,
+                known: KNOWN_USB_DEVICES
+                    .iter()
+                    .find(|(vid, pid, _, _)| *vid == info.vid && *pid == info.pid)
+                    .map(|&(_, _, description, device)| KnownFirmware {
+                        description,
+                        device,
+                    }),
+            },
+            _ => PortInfo {
+                name: p.port_name,
+                vid: None,
+                pid: None,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+                known: None,
+            },
+        })
+        .collect();
+    ports.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(ports)
+}
+
+fn usb_info(port: &Path) -> Option<UsbPortInfo> {
+    let name = port.to_str()?;
+    tokio_serial::available_ports()
+        .ok()?
+        .into_iter()
+        .find(|p| p.port_name == name)
+        .and_then(|p| match p.port_type {
+            SerialPortType::UsbPort(info) => Some(info),
+            _ => None,
+        })
+}
+
+impl FirmwareDevice for SerialBoard {
+    const MTU: usize = SERIAL_MTU;
+    type Version = Vec<u8>;
+    type Error = DfuError;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            let result = self.serial().await?.status().await;
+            self.closed(result)
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            let result = self.serial().await?.start(version).await;
+            self.closed(result)
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            let result = self.serial().await?.write(offset, data).await;
+            self.closed(result)
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], checksum: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            let result = self.serial().await?.update(version, checksum).await;
+            // The connection is fine, and remembers to write the firmware again
+            if let Err(DfuError::DeviceRejected(_)) = &result {
+                return result;
+            }
+            self.closed(result)?;
+            // The device resets to apply the firmware, so the port must be reopened
+            self.serial = None;
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            let result = self.serial().await?.synced().await;
+            self.closed(result)
+        }
+    }
+}
@@ -0,0 +1,377 @@
+use crate::{upload, Transport};
+use axum::{
+    extract::{Extension, Path},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use drgdfu::{decode_image, sha256, FirmwareFileMeta};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// An update job submitted through the API.
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    id: String,
+    state: JobState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+type Jobs = Arc<Mutex<Vec<Job>>>;
+
+/// Jobs waiting to run, beyond which submitted jobs are refused.
+const MAX_QUEUED_JOBS: usize = 64;
+/// Finished jobs kept for their status, dropping the oldest ones beyond it.
+const MAX_FINISHED_JOBS: usize = 256;
+
+#[derive(Clone)]
+struct Api {
+    jobs: Jobs,
+    queue: mpsc::UnboundedSender<(String, Transport)>,
+    /// Expected value of the authorization header.
+    authorization: String,
+}
+
+impl Api {
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        match headers.get(header::AUTHORIZATION) {
+            Some(value) if equal(value.as_bytes(), self.authorization.as_bytes()) => Ok(()),
+            _ => Err(StatusCode::UNAUTHORIZED),
+        }
+    }
+}
+
+/// Compare credentials in constant time.
+fn equal(a: &[u8], b: &[u8]) -> bool {
+    ring::constant_time::verify_slices_are_equal(a, b).is_ok()
+}
+
+/// Serve the REST API on the given address, executing submitted jobs one at a time.
+///
+/// * `POST /jobs` enqueues an update job, described like the `upload` command in JSON.
+/// * `GET /jobs` lists all jobs.
+/// * `GET /jobs/:id` returns the status of a job.
+/// * `GET /metrics` returns metrics in the Prometheus text format.
+///
+/// Requests to the jobs must carry the token as a bearer token.
+pub async fn run(listen: SocketAddr, token: String) -> anyhow::Result<()> {
+    if token.is_empty() {
+        return Err(anyhow::anyhow!("the API token must not be empty"));
+    }
+    let jobs = Jobs::default();
+    let (queue, jobs_rx) = mpsc::unbounded_channel();
+    let app = Router::new()
+        .route("/jobs", get(list_jobs).post(submit_job))
+        .route("/jobs/:id", get(get_job))
+        .route("/metrics", get(get_metrics))
+        .layer(Extension(Api {
+            jobs: jobs.clone(),
+            queue,
+            authorization: format!("Bearer {}", token),
+        }));
+
+    log::info!("Listening on {}", listen);
+    let server = axum::Server::try_bind(&listen)?.serve(app.into_make_service());
+
+    // Jobs run on this task, as the transports are not required to be Send
+    let serve = futures::future::join(server, execute(jobs, jobs_rx));
+    tokio::select! {
+        (result, _) = serve => result?,
+        // A running job is cancelled by the same signal
+        _ = tokio::signal::ctrl_c() => log::info!("Shutting down"),
+    }
+    Ok(())
+}
+
+async fn execute(jobs: Jobs, mut queue: mpsc::UnboundedReceiver<(String, Transport)>) {
+    while let Some((id, transport)) = queue.recv().await {
+        log::info!("Running job {}", id);
+        set_state(&jobs, &id, JobState::Running, None);
+        match upload(transport).await {
+            Ok(_) => {
+                log::info!("Job {} succeeded", id);
+                set_state(&jobs, &id, JobState::Succeeded, None);
+            }
+            Err(e) => {
+                log::warn!("Job {} failed: {:?}", id, e);
+                set_state(&jobs, &id, JobState::Failed, Some(e.to_string()));
+            }
+        }
+    }
+}
+
+fn set_state(jobs: &Jobs, id: &str, state: JobState, error: Option<String>) {
+    let mut jobs = jobs.lock().unwrap();
+    if let Some(job) = jobs.iter_mut().find(|j| j.id == id) {
+        job.state = state;
+        job.error = error;
+    }
+
+    // Jobs are kept in the order they were submitted, so the oldest finished ones go first
+    let finished = |j: &Job| matches!(j.state, JobState::Succeeded | JobState::Failed);
+    let mut excess = jobs
+        .iter()
+        .filter(|j| finished(j))
+        .count()
+        .saturating_sub(MAX_FINISHED_JOBS);
+    jobs.retain(|j| {
+        if excess > 0 && finished(j) {
+            excess -= 1;
+            false
+        } else {
+            true
+        }
+    });
+}
+
+async fn submit_job(
+    Extension(api): Extension<Api>,
+    headers: HeaderMap,
+    Json(transport): Json<Transport>,
+) -> Result<(StatusCode, Json<Job>), StatusCode> {
+    api.authorize(&headers)?;
+    let job = Job {
+        id: uuid::Uuid::new_v4().to_string(),
+        state: JobState::Queued,
+        error: None,
+    };
+    {
+        let mut jobs = api.jobs.lock().unwrap();
+        if jobs.iter().filter(|j| j.state == JobState::Queued).count() >= MAX_QUEUED_JOBS {
+            return Err(StatusCode::TOO_MANY_REQUESTS);
+        }
+        jobs.push(job.clone());
+    }
+    api.queue
+        .send((job.id.clone(), transport))
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+async fn list_jobs(
+    Extension(api): Extension<Api>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Job>>, StatusCode> {
+    api.authorize(&headers)?;
+    Ok(Json(api.jobs.lock().unwrap().clone()))
+}
+
+async fn get_job(
+    Extension(api): Extension<Api>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, StatusCode> {
+    api.authorize(&headers)?;
+    api.jobs
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|j| j.id == id)
+        .cloned()
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn get_metrics() -> String {
+    drgdfu::metrics::gather()
+}
+
+/// Firmware served to devices pulling it over HTTP.
+#[derive(Clone)]
+pub struct FirmwareFiles {
+    /// The firmware image, decoded to a binary if it is in another supported format.
+    pub firmware: PathBuf,
+    /// The metadata of the firmware, served as is.
+    pub metadata: Option<PathBuf>,
+    /// Version of the firmware, used to generate metadata if there is no metadata file.
+    pub version: Option<String>,
+}
+
+#[derive(Clone)]
+struct FirmwareServer {
+    files: FirmwareFiles,
+    /// Expected value of the authorization header, if clients must authenticate.
+    authorization: Option<String>,
+}
+
+/// Serve the firmware on the given address, for devices pulling it themselves. Files are read
+/// when requested, so that the firmware can be replaced while serving.
+///
+/// * `GET /firmware.bin` returns the firmware, continuing from an offset with range requests.
+/// * `GET /metadata.json` returns the metadata, as used by the URL firmware source.
+/// * `GET /version` returns the version of the firmware as text.
+///
+/// With credentials, clients must authenticate with HTTP basic authentication. As the
+/// credentials are sent in plain text, the server should only listen on a loopback address
+/// behind a TLS terminating proxy.
+pub async fn run_http(
+    listen: SocketAddr,
+    files: FirmwareFiles,
+    credentials: Option<(String, String)>,
+) -> anyhow::Result<()> {
+    if credentials.is_some() && !listen.ip().is_loopback() {
+        log::warn!(
+            "Credentials are sent in plain text on {}, serve behind a TLS terminating proxy",
+            listen
+        );
+    }
+    let authorization = credentials.map(|(username, password)| {
+        format!(
+            "Basic {}",
+            base64::encode(format!("{}:{}", username, password))
+        )
+    });
+    let app = Router::new()
+        .route("/firmware.bin", get(get_firmware))
+        .route("/metadata.json", get(get_metadata))
+        .route("/version", get(get_version))
+        .layer(Extension(FirmwareServer {
+            files,
+            authorization,
+        }));
+
+    log::info!("Serving firmware on http://{}", listen);
+    let server = axum::Server::try_bind(&listen)?.serve(app.into_make_service());
+    tokio::select! {
+        result = server => result?,
+        _ = tokio::signal::ctrl_c() => log::info!("Shutting down"),
+    }
+    Ok(())
+}
+
+impl FirmwareServer {
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), Response> {
+        match &self.authorization {
+            Some(expected)
+                if !headers
+                    .get(header::AUTHORIZATION)
+                    .map(|v| equal(v.as_bytes(), expected.as_bytes()))
+                    .unwrap_or(false) =>
+            {
+                Err((
+                    StatusCode::UNAUTHORIZED,
+                    [(header::WWW_AUTHENTICATE, "Basic realm=\"drgdfu\"")],
+                )
+                    .into_response())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn image(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(decode_image(tokio::fs::read(&self.files.firmware).await?)?)
+    }
+
+    /// The metadata file, or else metadata generated for the image.
+    async fn metadata(&self) -> anyhow::Result<Vec<u8>> {
+        match (&self.files.metadata, &self.files.version) {
+            (Some(metadata), _) => Ok(tokio::fs::read(metadata).await?),
+            (None, Some(version)) => {
+                let image = self.image().await?;
+                Ok(serde_json::to_vec(&FirmwareFileMeta {
+                    version: version.clone(),
+                    size: image.len(),
+                    checksum: sha256(&image),
+                    checksum_algorithm: Default::default(),
+                    images: Vec::new(),
+                    slot_size: None,
+                    encryption: None,
+                })?)
+            }
+            (None, None) => Err(anyhow::anyhow!("no metadata or version for the firmware")),
+        }
+    }
+}
+
+fn internal_error(e: anyhow::Error) -> Response {
+    log::warn!("Error serving firmware: {:?}", e);
+    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+}
+
+async fn get_firmware(
+    Extension(server): Extension<FirmwareServer>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    server.authorize(&headers)?;
+    let image = server.image().await.map_err(internal_error)?;
+    let etag = format!("\"{}\"", sha256(&image));
+
+    // Only ranges continuing at an offset are supported, as used to resume downloads
+    let offset = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes="))
+        .and_then(|v| v.strip_suffix('-'))
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|_| match headers.get(header::IF_RANGE) {
+            Some(validator) => validator.as_bytes() == etag.as_bytes(),
+            None => true,
+        });
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    response_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    // The checksum is hex encoded, which is always a valid header value
+    response_headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+    match offset {
+        Some(offset) if offset >= image.len() => {
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                format!("bytes */{}", image.len()).parse().unwrap(),
+            );
+            Ok((StatusCode::RANGE_NOT_SATISFIABLE, response_headers).into_response())
+        }
+        Some(offset) => {
+            response_headers.insert(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", offset, image.len() - 1, image.len())
+                    .parse()
+                    .unwrap(),
+            );
+            Ok((
+                StatusCode::PARTIAL_CONTENT,
+                response_headers,
+                image[offset..].to_vec(),
+            )
+                .into_response())
+        }
+        None => Ok((StatusCode::OK, response_headers, image).into_response()),
+    }
+}
+
+async fn get_metadata(
+    Extension(server): Extension<FirmwareServer>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    server.authorize(&headers)?;
+    let metadata = server.metadata().await.map_err(internal_error)?;
+    Ok(([(header::CONTENT_TYPE, "application/json")], metadata).into_response())
+}
+
+async fn get_version(
+    Extension(server): Extension<FirmwareServer>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    server.authorize(&headers)?;
+    let metadata = server.metadata().await.map_err(internal_error)?;
+    let metadata: FirmwareFileMeta =
+        serde_json::from_slice(&metadata).map_err(|e| internal_error(e.into()))?;
+    Ok(metadata.version.into_response())
+}
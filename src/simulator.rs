@@ -0,0 +1,418 @@
+use core::future::Future;
+use embedded_update::*;
+
+/// Flash characteristics of a simulated device.
+#[derive(Debug, Clone, Copy)]
+pub struct FlashConfig {
+    /// Size of the slot receiving the new firmware.
+    pub slot_size: usize,
+    /// Size of an erase block. Blocks are erased when written at their start.
+    pub erase_size: usize,
+    /// Writes must be aligned to and a multiple of this size.
+    pub write_size: usize,
+}
+
+impl FlashConfig {
+    /// Reject sizes of zero, which no flash has.
+    fn validate(&self) -> Result<(), FlashError> {
+        for (name, size) in [
+            ("slot", self.slot_size),
+            ("erase", self.erase_size),
+            ("write", self.write_size),
+        ] {
+            if size == 0 {
+                return Err(FlashError::InvalidSize { name });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for FlashConfig {
+    fn default() -> Self {
+        Self {
+            slot_size: usize::MAX,
+            erase_size: 1,
+            write_size: 1,
+        }
+    }
+}
+
+/// Errors returned by a FlashSimulator for writes a real flash driver would reject.
+#[derive(Debug)]
+pub enum FlashError {
+    OutOfBounds { offset: u32, len: usize },
+    Unaligned { offset: u32, len: usize },
+    NotErased { offset: u32 },
+    NotStarted,
+    InvalidSize { name: &'static str },
+}
+
+impl core::fmt::Display for FlashError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::OutOfBounds { offset, len } => {
+                write!(
+                    f,
+                    "write of {} bytes at offset {} exceeds slot",
+                    len, offset
+                )
+            }
+            Self::Unaligned { offset, len } => write!(
+                f,
+                "write of {} bytes at offset {} does not match write granularity",
+                len, offset
+            ),
+            Self::NotErased { offset } => write!(f, "write to non-erased flash at {}", offset),
+            Self::NotStarted => write!(f, "write before update was started"),
+            Self::InvalidSize { name } => write!(f, "{} size must not be zero", name),
+        }
+    }
+}
+
+impl std::error::Error for FlashError {}
+
+/// A simulated device which enforces the constraints of a flash driver, so that protocol
+/// and chunking bugs are found before running on hardware.
+pub struct FlashSimulator {
+    config: FlashConfig,
+    version: Vec<u8>,
+    next_version: Option<Vec<u8>>,
+    next_offset: u32,
+    slot: Vec<u8>,
+}
+
+const ERASED: u8 = 0xFF;
+
+impl FlashSimulator {
+    pub fn new(version: &[u8], config: FlashConfig) -> Result<Self, FlashError> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            version: version.to_vec(),
+            next_version: None,
+            next_offset: 0,
+            slot: Vec::new(),
+        })
+    }
+
+    /// Return the current version of the device.
+    pub fn version(&self) -> &[u8] {
+        &self.version
+    }
+
+    /// Return the firmware written to the slot so far.
+    pub fn slot(&self) -> &[u8] {
+        &self.slot
+    }
+
+    fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        let start = offset as usize;
+        let end = start + data.len();
+        if end > self.config.slot_size {
+            return Err(FlashError::OutOfBounds {
+                offset,
+                len: data.len(),
+            });
+        }
+        if start % self.config.write_size != 0 || data.len() % self.config.write_size != 0 {
+            return Err(FlashError::Unaligned {
+                offset,
+                len: data.len(),
+            });
+        }
+
+        // Erase blocks that are written at their start, like a flash driver streaming firmware
+        let erase = self.config.erase_size;
+        let mut block = (start + erase - 1) / erase * erase;
+        while block < end {
+            let block_end = core::cmp::min(block + erase, self.config.slot_size);
+            if self.slot.len() < block_end {
+                self.slot.resize(block_end, ERASED);
+            }
+            self.slot[block..block_end].fill(ERASED);
+            block += erase;
+        }
+
+        if self.slot.len() < end {
+            // Never erased, contains whatever was there before
+            self.slot.resize(end, 0);
+        }
+        if let Some(i) = self.slot[start..end].iter().position(|b| *b != ERASED) {
+            return Err(FlashError::NotErased {
+                offset: (start + i) as u32,
+            });
+        }
+        self.slot[start..end].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl FirmwareDevice for FlashSimulator {
+    const MTU: usize = 256;
+    type Version = Vec<u8>;
+    type Error = FlashError;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            Ok(FirmwareStatus {
+                current_version: self.version.clone(),
+                next_offset: self.next_offset,
+                next_version: self.next_version.clone(),
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            log::debug!("FlashSimulator::start()");
+            self.next_version.replace(version.to_vec());
+            self.next_offset = 0;
+            self.slot.clear();
+            Ok(())
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            log::debug!("FlashSimulator::write({}, {})", offset, data.len());
+            if self.next_version.is_none() {
+                return Err(FlashError::NotStarted);
+            }
+            self.program(offset, data)?;
+            self.next_offset = offset + data.len() as u32;
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            log::debug!("FlashSimulator::update()");
+            self.version = version.to_vec();
+            self.next_version = None;
+            self.next_offset = 0;
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            log::debug!("FlashSimulator::synced()");
+            Ok(())
+        }
+    }
+}
+
+/// Error returned when reading from a pseudo-terminal whose other side is not open.
+#[cfg(unix)]
+const EIO: i32 = 5;
+
+/// Serves a FlashSimulator on a pseudo-terminal using the serial protocol, so that the serial
+/// transport can be tested end-to-end with `drgdfu upload serial --port <path>`.
+///
+/// Like a device waiting for commands, the device reports its status when the port is opened
+/// and again while no commands arrive. It resets after swapping firmware, so the host needs to
+/// reopen the port like with a real device.
+#[cfg(unix)]
+pub struct PtySimulator {
+    device: FlashSimulator,
+    master: tokio_serial::SerialStream,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl PtySimulator {
+    pub fn new(device: FlashSimulator) -> anyhow::Result<Self> {
+        use tokio_serial::SerialPort;
+        let (master, slave) = tokio_serial::SerialStream::pair()?;
+        let path = slave
+            .name()
+            .ok_or_else(|| anyhow::anyhow!("pseudo-terminal has no name"))?
+            .into();
+        Ok(Self {
+            device,
+            master,
+            path,
+        })
+    }
+
+    /// Path of the serial port to update the device through.
+    pub fn path(&self) -> &std::path::Path {
+        &self.path
+    }
+
+    /// Return the simulated device.
+    pub fn device(&self) -> &FlashSimulator {
+        &self.device
+    }
+
+    /// Serve the device until an error occurs.
+    pub async fn run(&mut self) -> anyhow::Result<()> {
+        use tokio::io::AsyncReadExt;
+        use tokio::time::{sleep, timeout, Duration, Instant};
+
+        let mut frame = [0; crate::FRAME_SIZE];
+        let mut received = 0;
+        let mut connected = false;
+        let mut last_activity = Instant::now();
+        loop {
+            match timeout(
+                Duration::from_millis(100),
+                self.master.read(&mut frame[received..]),
+            )
+            .await
+            {
+                // The host does not have the port open
+                Ok(Err(e)) if e.raw_os_error() == Some(EIO) => {
+                    if connected {
+                        log::info!("Host closed {}", self.path.display());
+                        connected = false;
+                    }
+                    received = 0;
+                    sleep(Duration::from_millis(100)).await;
+                }
+                Ok(Err(e)) => return Err(e.into()),
+                Ok(Ok(n)) => {
+                    last_activity = Instant::now();
+                    received += n;
+                    if received == frame.len() {
+                        received = 0;
+                        if self.handle(&frame).await? {
+                            // Like a real device, reset after swapping firmware
+                            sleep(Duration::from_millis(500)).await;
+                            connected = false;
+                        }
+                    }
+                }
+                // Nothing to read while the host has the port open
+                Err(_) => {
+                    if !connected {
+                        log::info!("Host opened {}", self.path.display());
+                        connected = true;
+                    } else if last_activity.elapsed() < Duration::from_millis(500) {
+                        continue;
+                    }
+                    self.send_status().await?;
+                    last_activity = Instant::now();
+                }
+            }
+        }
+    }
+
+    async fn send_status(&mut self) -> anyhow::Result<()> {
+        let status = self.device.status().await?;
+        let status = match &status.next_version {
+            Some(next) => Status::update(
+                &status.current_version,
+                None,
+                status.next_offset,
+                next,
+                None,
+            ),
+            None => Status::first(&status.current_version, None, None),
+        };
+        self.send(&status).await
+    }
+
+    async fn send<T: serde::Serialize>(&mut self, message: &T) -> anyhow::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        let mut frame = [0; crate::FRAME_SIZE];
+        postcard::to_slice(message, &mut frame)?;
+        self.master.write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Apply a command, returning true if the firmware was swapped.
+    async fn handle(&mut self, frame: &[u8]) -> anyhow::Result<bool> {
+        use crate::{
+            SerialRequest, SerialResponse, FEATURE_CHECKSUM, HANDSHAKE_MAGIC, PROTOCOL_VERSION,
+        };
+        match crate::decode_request(frame) {
+            Ok(SerialRequest::Hello { magic, .. }) if magic == HANDSHAKE_MAGIC => {
+                self.send(&SerialResponse::Capabilities {
+                    magic: HANDSHAKE_MAGIC,
+                    protocol: PROTOCOL_VERSION,
+                    mtu: FlashSimulator::MTU as u32,
+                    features: FEATURE_CHECKSUM,
+                })
+                .await?;
+                self.send_status().await?;
+                return Ok(false);
+            }
+            Ok(SerialRequest::Checksum { magic, len }) if magic == HANDSHAKE_MAGIC => {
+                use sha2::{Digest, Sha256};
+                let slot = self.device.slot();
+                let len = core::cmp::min(len as usize, slot.len());
+                self.send(&SerialResponse::Checksum {
+                    magic: HANDSHAKE_MAGIC,
+                    checksum: Sha256::digest(&slot[..len]).into(),
+                })
+                .await?;
+                return Ok(false);
+            }
+            _ => {}
+        }
+        let command = match crate::decode_command(frame) {
+            Ok(command) => command,
+            Err(e) => {
+                log::warn!("Ignoring invalid frame: {}", e);
+                return Ok(false);
+            }
+        };
+        match command {
+            Command::Write {
+                version,
+                offset,
+                data,
+                ..
+            } => {
+                if offset == 0 {
+                    self.device.start(&version).await?;
+                }
+                // Like a device, keep going and let the host find out from the status
+                if let Err(e) = self.device.write(offset, &data).await {
+                    log::warn!("Error writing firmware: {}", e);
+                }
+            }
+            Command::Swap {
+                version, checksum, ..
+            } => {
+                self.device.update(&version, &checksum).await?;
+                log::info!(
+                    "Updated to version {}",
+                    String::from_utf8_lossy(self.device.version())
+                );
+                return Ok(true);
+            }
+            Command::Sync { .. } => {
+                self.device.synced().await?;
+                log::info!(
+                    "In sync at version {}",
+                    String::from_utf8_lossy(self.device.version())
+                );
+            }
+            Command::Wait { .. } => {}
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,296 @@
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+
+/// UDP port of the SMP server of Zephyr devices.
+pub const SMP_UDP_PORT: u16 = 1337;
+
+/// Bytes of image sent per upload request by default, leaving room for the header and the
+/// other fields within the default 1024 byte MTU of Zephyr.
+const DEFAULT_CHUNK_SIZE: usize = 512;
+
+/// Number of times a request is sent before giving up, as UDP datagrams may be lost.
+const MAX_ATTEMPTS: u32 = 5;
+
+const OP_READ: u8 = 0;
+const OP_WRITE: u8 = 2;
+
+const GROUP_OS: u16 = 0;
+const GROUP_IMAGE: u16 = 1;
+
+const ID_OS_RESET: u8 = 5;
+const ID_IMAGE_STATE: u8 = 0;
+const ID_IMAGE_UPLOAD: u8 = 1;
+
+/// A Zephyr device running MCUboot, updated with the image management commands of mcumgr's
+/// Simple Management Protocol (SMP) over UDP.
+///
+/// The image is uploaded to the secondary slot once it has been received completely, as the
+/// first upload request carries its size. The device is then asked to test the image and
+/// reset, and the image is confirmed once the device runs it.
+pub struct SmpBoard {
+    socket: UdpSocket,
+    chunk_size: usize,
+    timeout: Duration,
+    seq: u8,
+    image: Vec<u8>,
+    updated: bool,
+}
+
+impl SmpBoard {
+    /// Connect to the SMP server at the address, such as `192.0.2.1:1337`.
+    pub async fn connect(address: &str) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(if address.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        })
+        .await?;
+        socket.connect(address).await?;
+        Ok(Self {
+            socket,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            timeout: Duration::from_secs(2),
+            seq: 0,
+            image: Vec::new(),
+            updated: false,
+        })
+    }
+
+    /// Send at most `chunk_size` bytes of image per upload request.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Wait for responses for the given time before sending a request again.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Send a request, returning the payload of the response.
+    async fn request(
+        &mut self,
+        op: u8,
+        group: u16,
+        id: u8,
+        payload: BTreeMap<Value, Value>,
+    ) -> anyhow::Result<BTreeMap<Value, Value>> {
+        let payload = serde_cbor::to_vec(&Value::Map(payload))?;
+        self.seq = self.seq.wrapping_add(1);
+        let mut frame = Vec::with_capacity(8 + payload.len());
+        frame.push(op);
+        frame.push(0);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&group.to_be_bytes());
+        frame.push(self.seq);
+        frame.push(id);
+        frame.extend_from_slice(&payload);
+
+        let mut buf = [0; 2048];
+        for attempt in 1..=MAX_ATTEMPTS {
+            self.socket.send(&frame).await?;
+            let response = timeout(self.timeout, async {
+                loop {
+                    let len = self.socket.recv(&mut buf).await?;
+                    // Skip late responses to earlier requests
+                    if len >= 8 && buf[6] == self.seq && buf[0] == op + 1 {
+                        return Ok::<_, anyhow::Error>(&buf[8..len]);
+                    }
+                }
+            })
+            .await;
+            match response {
+                Ok(response) => return decode_response(response?),
+                Err(_) => log::debug!(
+                    "No response to request {} ({} of {})",
+                    self.seq,
+                    attempt,
+                    MAX_ATTEMPTS
+                ),
+            }
+        }
+        Err(anyhow!(
+            "no response from device after {} attempts",
+            MAX_ATTEMPTS
+        ))
+    }
+
+    /// The images on the device, by slot.
+    async fn image_state(&mut self) -> anyhow::Result<Vec<BTreeMap<Value, Value>>> {
+        let response = self
+            .request(OP_READ, GROUP_IMAGE, ID_IMAGE_STATE, BTreeMap::new())
+            .await?;
+        match response.get(&text("images")) {
+            Some(Value::Array(images)) => Ok(images
+                .iter()
+                .filter_map(|image| match image {
+                    Value::Map(image) => Some(image.clone()),
+                    _ => None,
+                })
+                .collect()),
+            _ => Err(anyhow!("invalid image state response")),
+        }
+    }
+
+    async fn upload(&mut self) -> anyhow::Result<()> {
+        let image = core::mem::take(&mut self.image);
+        let mut offset = 0;
+        while offset < image.len() {
+            let end = core::cmp::min(offset + self.chunk_size, image.len());
+            let mut request = BTreeMap::new();
+            request.insert(text("off"), Value::Integer(offset as i128));
+            request.insert(text("data"), Value::Bytes(image[offset..end].to_vec()));
+            if offset == 0 {
+                request.insert(text("image"), Value::Integer(0));
+                request.insert(text("len"), Value::Integer(image.len() as i128));
+                request.insert(text("sha"), Value::Bytes(Sha256::digest(&image).to_vec()));
+            }
+            let response = self
+                .request(OP_WRITE, GROUP_IMAGE, ID_IMAGE_UPLOAD, request)
+                .await?;
+            // Continue where the device asks to, which may differ after a lost response
+            offset = match response.get(&text("off")) {
+                Some(Value::Integer(off)) => *off as usize,
+                _ => return Err(anyhow!("invalid upload response")),
+            };
+            log::debug!("Uploaded {} of {} bytes", offset, image.len());
+        }
+        Ok(())
+    }
+}
+
+fn text(s: &str) -> Value {
+    Value::Text(s.to_string())
+}
+
+/// Decode the payload of a response, failing if the device returned an error code.
+fn decode_response(payload: &[u8]) -> anyhow::Result<BTreeMap<Value, Value>> {
+    let response = match serde_cbor::from_slice(payload)? {
+        Value::Map(response) => response,
+        _ => return Err(anyhow!("invalid response")),
+    };
+    // SMP version 2 reports errors of groups as a map
+    let rc = match (response.get(&text("rc")), response.get(&text("err"))) {
+        (Some(Value::Integer(rc)), _) => *rc,
+        (_, Some(Value::Map(err))) => match err.get(&text("rc")) {
+            Some(Value::Integer(rc)) => *rc,
+            _ => 0,
+        },
+        _ => 0,
+    };
+    if rc != 0 {
+        return Err(anyhow!("device returned error code {}", rc));
+    }
+    Ok(response)
+}
+
+impl FirmwareDevice for SmpBoard {
+    const MTU: usize = 4096;
+    type Version = Vec<u8>;
+    type Error = anyhow::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            let images = self.image_state().await?;
+            let version = images
+                .iter()
+                .find(|image| image.get(&text("active")) == Some(&Value::Bool(true)))
+                .or_else(|| images.first())
+                .and_then(|image| match image.get(&text("version")) {
+                    Some(Value::Text(version)) => Some(version.as_bytes().to_vec()),
+                    _ => None,
+                })
+                .ok_or_else(|| anyhow!("device reported no running image"))?;
+            Ok(FirmwareStatus {
+                current_version: version,
+                next_offset: 0,
+                next_version: None,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, _: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            self.image.clear();
+            Ok(())
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            // The image is received in order, as the transfer is restarted on errors
+            self.image.truncate(offset as usize);
+            self.image.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, _: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            log::info!("Uploading image of {} bytes", self.image.len());
+            self.upload().await?;
+
+            // Test the uploaded image, identified by the hash MCUboot computed
+            let hash = self
+                .image_state()
+                .await?
+                .into_iter()
+                .find(|image| image.get(&text("slot")) == Some(&Value::Integer(1)))
+                .and_then(|mut image| image.remove(&text("hash")))
+                .ok_or_else(|| anyhow!("uploaded image not found on device"))?;
+            let mut request = BTreeMap::new();
+            request.insert(text("hash"), hash);
+            request.insert(text("confirm"), Value::Bool(false));
+            self.request(OP_WRITE, GROUP_IMAGE, ID_IMAGE_STATE, request)
+                .await?;
+
+            log::info!("Resetting device to swap to the new image");
+            self.request(OP_WRITE, GROUP_OS, ID_OS_RESET, BTreeMap::new())
+                .await?;
+            self.updated = true;
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            if self.updated {
+                // Confirm the running image, so that MCUboot does not revert it
+                log::debug!("Confirming image");
+                let mut request = BTreeMap::new();
+                request.insert(text("confirm"), Value::Bool(true));
+                self.request(OP_WRITE, GROUP_IMAGE, ID_IMAGE_STATE, request)
+                    .await?;
+                self.updated = false;
+            }
+            Ok(())
+        }
+    }
+}
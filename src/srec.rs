@@ -0,0 +1,165 @@
+use crate::{FirmwareError, MAX_IMAGE_SIZE};
+
+/// Returns true if the data looks like a Motorola S-record file.
+pub fn is_srec(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] == b'S' && data[1].is_ascii_digit() && data.is_ascii()
+}
+
+/// Convert a Motorola S-record file to a binary image starting at its lowest data address.
+///
+/// Gaps between data records are filled with 0xFF, the value of erased flash. Records spanning
+/// more than `MAX_IMAGE_SIZE` bytes are rejected.
+pub fn srec_to_binary(data: &[u8]) -> Result<Vec<u8>, FirmwareError> {
+    let text = core::str::from_utf8(data)
+        .map_err(|_| FirmwareError::Image("S-record file is not ASCII".to_string()))?;
+
+    let mut blocks: Vec<(u32, Vec<u8>)> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let record = line.as_bytes();
+        if record.len() < 4 || record[0] != b'S' {
+            return Err(invalid(number, "missing record start"));
+        }
+        let bytes = line
+            .get(2..)
+            .and_then(decode_hex)
+            .ok_or_else(|| invalid(number, "invalid hex"))?;
+        let count = bytes[0] as usize;
+        if bytes.len() != count + 1 {
+            return Err(invalid(number, "byte count mismatch"));
+        }
+        let sum = bytes[..count]
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if !sum != bytes[count] {
+            return Err(invalid(number, "checksum mismatch"));
+        }
+
+        let address_len = match record[1] {
+            b'1' => 2,
+            b'2' => 3,
+            b'3' => 4,
+            // Header, record counts and start addresses carry no data
+            b'0' | b'5' | b'6' | b'7' | b'8' | b'9' => continue,
+            _ => return Err(invalid(number, "unknown record type")),
+        };
+        if count < address_len + 1 {
+            return Err(invalid(number, "record too short"));
+        }
+        let address = bytes[1..=address_len]
+            .iter()
+            .fold(0u32, |a, b| (a << 8) | *b as u32);
+        blocks.push((address, bytes[address_len + 1..count].to_vec()));
+    }
+
+    let start = match blocks.iter().map(|(a, _)| *a).min() {
+        Some(start) => start,
+        None => {
+            return Err(FirmwareError::Image(
+                "S-record file contains no data".to_string(),
+            ))
+        }
+    };
+    let span = blocks
+        .iter()
+        .map(|(address, data)| (address - start) as usize + data.len())
+        .max()
+        .unwrap_or(0);
+    if span > MAX_IMAGE_SIZE {
+        return Err(FirmwareError::Image(format!(
+            "S-record file spans {} bytes, more than the maximum image size of {} bytes",
+            span, MAX_IMAGE_SIZE
+        )));
+    }
+    let mut image = Vec::with_capacity(span);
+    for (address, data) in blocks {
+        let offset = (address - start) as usize;
+        if image.len() < offset + data.len() {
+            image.resize(offset + data.len(), 0xFF);
+        }
+        image[offset..offset + data.len()].copy_from_slice(&data);
+    }
+    Ok(image)
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || s.len() < 2 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid(line: usize, reason: &str) -> FirmwareError {
+    FirmwareError::Image(format!("invalid S-record at line {}: {}", line, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a record of the type with the address and data, followed by its checksum.
+    fn record(kind: char, address: &[u8], data: &[u8]) -> String {
+        let mut bytes = vec![(address.len() + data.len() + 1) as u8];
+        bytes.extend_from_slice(address);
+        bytes.extend_from_slice(data);
+        let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        bytes.push(!sum);
+        let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        format!("S{}{}\n", kind, hex)
+    }
+
+    #[test]
+    fn convert_records() {
+        let file = [
+            record('0', &[0, 0], b"hdr"),
+            record('1', &[0x10, 0x04], &[3, 4]),
+            record('1', &[0x10, 0x00], &[1, 2]),
+            record('9', &[0x10, 0x00], &[]),
+        ]
+        .concat();
+        assert!(is_srec(file.as_bytes()));
+        assert_eq!(
+            srec_to_binary(file.as_bytes()).unwrap(),
+            vec![1, 2, 0xFF, 0xFF, 3, 4]
+        );
+    }
+
+    #[test]
+    fn reject_truncated() {
+        let file = record('1', &[0x10, 0x00], &[1, 2, 3, 4]);
+        let truncated = &file.as_bytes()[..file.len() - 5];
+        assert!(matches!(
+            srec_to_binary(truncated),
+            Err(FirmwareError::Image(e)) if e.contains("byte count mismatch")
+        ));
+    }
+
+    #[test]
+    fn reject_checksum_mismatch() {
+        let file = record('1', &[0x10, 0x00], &[1, 2, 3, 4]).replace("01020304", "01020305");
+        assert!(matches!(
+            srec_to_binary(file.as_bytes()),
+            Err(FirmwareError::Image(e)) if e.contains("checksum mismatch")
+        ));
+    }
+
+    #[test]
+    fn reject_oversized_span() {
+        let file = [
+            record('3', &[0, 0, 0, 0], &[1]),
+            record('3', &[0xFF, 0xFF, 0, 0], &[2]),
+        ]
+        .concat();
+        assert!(matches!(
+            srec_to_binary(file.as_bytes()),
+            Err(FirmwareError::Image(e)) if e.contains("maximum image size")
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::{Child, Command};
+
+/// Remote path the firmware is written to by default.
+pub const SSH_REMOTE_PATH: &str = "/tmp/drgdfu-firmware.img";
+
+/// A Linux device reachable over SSH, updated by streaming the image to a remote path and
+/// running an install command such as `rauc install {path}` or `swupdate -i {path}`.
+///
+/// The `ssh` client of the system is used, so host keys, agents and the SSH configuration of
+/// the user apply as usual. The current version is read with an optional version command;
+/// without one, the firmware is installed whenever an update is started.
+pub struct SshBoard {
+    host: String,
+    port: Option<u16>,
+    identity: Option<PathBuf>,
+    remote_path: String,
+    install_command: String,
+    version_command: Option<String>,
+    upload: Option<Child>,
+    version: Vec<u8>,
+}
+
+impl SshBoard {
+    /// Update the given host, which may include a user as in `root@device.local`, using the
+    /// install command. `{path}` in the command is replaced with the remote path of the image.
+    pub fn new(host: &str, install_command: &str) -> Self {
+        Self {
+            host: host.to_string(),
+            port: None,
+            identity: None,
+            remote_path: SSH_REMOTE_PATH.to_string(),
+            install_command: install_command.to_string(),
+            version_command: None,
+            upload: None,
+            version: Vec::new(),
+        }
+    }
+
+    /// Connect to the given port instead of the default one.
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Authenticate with the given private key.
+    pub fn with_identity(mut self, identity: &Path) -> Self {
+        self.identity = Some(identity.to_path_buf());
+        self
+    }
+
+    /// Write the image to the given path on the device.
+    pub fn with_remote_path(mut self, path: &str) -> Self {
+        self.remote_path = path.to_string();
+        self
+    }
+
+    /// Command printing the firmware version running on the device, such as
+    /// `cat /etc/sw-version`.
+    pub fn with_version_command(mut self, command: &str) -> Self {
+        self.version_command = Some(command.to_string());
+        self
+    }
+
+    fn command(&self, remote: &str) -> Command {
+        let mut command = Command::new("ssh");
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity) = &self.identity {
+            command.arg("-i").arg(identity);
+        }
+        command.arg(&self.host).arg(remote);
+        command.kill_on_drop(true);
+        command
+    }
+
+    async fn run(&self, remote: &str) -> anyhow::Result<Vec<u8>> {
+        log::debug!("Running '{}' on {}", remote, self.host);
+        let output = self
+            .command(remote)
+            .stderr(Stdio::inherit())
+            .output()
+            .await?;
+        if output.status.success() {
+            Ok(output.stdout)
+        } else {
+            Err(anyhow!(
+                "'{}' failed on {}: {}",
+                remote,
+                self.host,
+                output.status
+            ))
+        }
+    }
+}
+
+/// Quote an argument for the remote shell.
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+impl FirmwareDevice for SshBoard {
+    const MTU: usize = 65536;
+    type Version = Vec<u8>;
+    type Error = anyhow::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            if let Some(command) = &self.version_command {
+                let output = self.run(command).await?;
+                self.version = String::from_utf8_lossy(&output).trim().as_bytes().to_vec();
+            }
+            Ok(FirmwareStatus {
+                current_version: self.version.clone(),
+                next_offset: 0,
+                next_version: None,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, _: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            log::info!("Streaming image to {}:{}", self.host, self.remote_path);
+            let upload = self
+                .command(&format!("cat > {}", quote(&self.remote_path)))
+                .stdin(Stdio::piped())
+                .spawn()?;
+            self.upload.replace(upload);
+            Ok(())
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, _: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            // The image is streamed in order, as the transfer is restarted on errors
+            let stdin = self
+                .upload
+                .as_mut()
+                .and_then(|upload| upload.stdin.as_mut())
+                .ok_or_else(|| anyhow!("write before update was started"))?;
+            stdin.write_all(data).await?;
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            let mut upload = self
+                .upload
+                .take()
+                .ok_or_else(|| anyhow!("update before update was started"))?;
+            // Closing stdin ends the transfer
+            drop(upload.stdin.take());
+            let status = upload.wait().await?;
+            if !status.success() {
+                return Err(anyhow!("transferring image failed: {}", status));
+            }
+
+            let command = self
+                .install_command
+                .replace("{path}", &quote(&self.remote_path));
+            log::info!("Installing image with '{}'", command);
+            self.run(&command).await?;
+            self.version = version.to_vec();
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move { Ok(()) }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::{Failure, SerialPort};
+use anyhow::anyhow;
+use core::future::Future;
+use embedded_io::adapters::FromTokio;
+use embedded_io::asynch::{Read, Write};
+use embedded_update::{FirmwareDevice, FirmwareStatus};
+use std::path::Path;
+use tokio::time::{timeout, Duration};
+use tokio_serial::{Parity, SerialStream};
+
+const SYNC: u8 = 0x7F;
+const ACK: u8 = 0x79;
+const NACK: u8 = 0x1F;
+
+const CMD_GET: u8 = 0x00;
+const CMD_GO: u8 = 0x21;
+const CMD_WRITE_MEMORY: u8 = 0x31;
+const CMD_ERASE: u8 = 0x43;
+const CMD_EXTENDED_ERASE: u8 = 0x44;
+
+/// Maximum number of bytes written by a single WRITE MEMORY command.
+const MAX_WRITE: usize = 256;
+
+/// Start of the flash memory of most STM32 devices.
+pub const STM32_FLASH_ADDRESS: u32 = 0x0800_0000;
+
+/// Open a serial port for use with the STM32 bootloader, which requires even parity.
+pub fn open_stm32_port(port: &Path, baud_rate: u32) -> anyhow::Result<SerialPort> {
+    let p = port.to_str().ok_or_else(|| anyhow!("invalid port name"))?;
+    let builder = tokio_serial::new(p, baud_rate).parity(Parity::Even);
+    Ok(FromTokio::new(SerialStream::open(&builder)?))
+}
+
+/// A device running the STM32 system memory bootloader, updated using the UART protocol
+/// described in ST application note AN3155.
+///
+/// The bootloader has no notion of firmware versions, so the device is mass erased and
+/// written whenever an update is started, and started at the flash address afterwards.
+pub struct Stm32Board<T>
+where
+    T: Read + Write,
+{
+    transport: T,
+    address: u32,
+    version: Vec<u8>,
+    synced: bool,
+    erase_command: Option<u8>,
+}
+
+impl<T> Stm32Board<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            address: STM32_FLASH_ADDRESS,
+            version: Vec::new(),
+            synced: false,
+            erase_command: None,
+        }
+    }
+
+    /// Write the firmware to the given address instead of the start of flash.
+    pub fn with_address(mut self, address: u32) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Synchronize with the bootloader, which detects the baud rate from the sync byte.
+    async fn sync(&mut self) -> anyhow::Result<()> {
+        if !self.synced {
+            self.send(&[SYNC]).await?;
+            // A bootloader which is already synchronized treats the sync byte as an unknown command
+            match self.read_byte(Duration::from_secs(1)).await? {
+                ACK | NACK => self.synced = true,
+                b => return Err(anyhow!("unexpected response to sync: {:#04x}", b)),
+            }
+        }
+        Ok(())
+    }
+
+    async fn command(&mut self, command: u8) -> anyhow::Result<()> {
+        self.sync().await?;
+        self.send(&[command, !command]).await?;
+        self.ack(Duration::from_secs(1))
+            .await
+            .map_err(|e| e.context(format!("command {:#04x} rejected", command)))
+    }
+
+    /// Find the erase command supported by the bootloader.
+    async fn get(&mut self) -> anyhow::Result<u8> {
+        self.command(CMD_GET).await?;
+        let len = self.read_byte(Duration::from_secs(1)).await? as usize + 1;
+        let mut data = vec![0; len];
+        for b in data.iter_mut() {
+            *b = self.read_byte(Duration::from_secs(1)).await?;
+        }
+        self.ack(Duration::from_secs(1)).await?;
+        log::debug!("STM32 bootloader version {:#04x}", data[0]);
+        if data[1..].contains(&CMD_EXTENDED_ERASE) {
+            Ok(CMD_EXTENDED_ERASE)
+        } else if data[1..].contains(&CMD_ERASE) {
+            Ok(CMD_ERASE)
+        } else {
+            Err(anyhow!("bootloader does not support erasing flash"))
+        }
+    }
+
+    async fn mass_erase(&mut self) -> anyhow::Result<()> {
+        let command = match self.erase_command {
+            Some(command) => command,
+            None => {
+                let command = self.get().await?;
+                self.erase_command.replace(command);
+                command
+            }
+        };
+        self.command(command).await?;
+        if command == CMD_EXTENDED_ERASE {
+            self.send(&[0xFF, 0xFF, 0x00]).await?;
+        } else {
+            self.send(&[0xFF, 0x00]).await?;
+        }
+        // Erasing the whole flash may take a while
+        self.ack(Duration::from_secs(60)).await
+    }
+
+    async fn write_memory(&mut self, address: u32, data: &[u8]) -> anyhow::Result<()> {
+        self.command(CMD_WRITE_MEMORY).await?;
+        self.send_address(address).await?;
+
+        // The length must be a multiple of 4
+        let mut block = data.to_vec();
+        block.resize((data.len() + 3) / 4 * 4, 0xFF);
+        let mut frame = Vec::with_capacity(block.len() + 2);
+        frame.push((block.len() - 1) as u8);
+        frame.extend_from_slice(&block);
+        frame.push(checksum(&frame));
+        self.send(&frame).await?;
+        self.ack(Duration::from_secs(1)).await
+    }
+
+    async fn go(&mut self, address: u32) -> anyhow::Result<()> {
+        self.command(CMD_GO).await?;
+        self.send_address(address).await
+    }
+
+    async fn send_address(&mut self, address: u32) -> anyhow::Result<()> {
+        let mut frame = address.to_be_bytes().to_vec();
+        frame.push(checksum(&frame));
+        self.send(&frame).await?;
+        self.ack(Duration::from_secs(1)).await
+    }
+
+    async fn send(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.transport
+            .write_all(data)
+            .await
+            .map_err(|e| anyhow!("error writing to bootloader: {:?}", e))?;
+        self.transport
+            .flush()
+            .await
+            .map_err(|e| anyhow!("error writing to bootloader: {:?}", e))
+    }
+
+    async fn read_byte(&mut self, wait: Duration) -> anyhow::Result<u8> {
+        let mut b = [0];
+        timeout(wait, self.transport.read_exact(&mut b))
+            .await
+            .map_err(|_| {
+                anyhow!("timeout waiting for bootloader").context(Failure::ConnectTimeout)
+            })?
+            .map_err(|e| anyhow!("error reading from bootloader: {:?}", e))?;
+        Ok(b[0])
+    }
+
+    async fn ack(&mut self, wait: Duration) -> anyhow::Result<()> {
+        match self.read_byte(wait).await? {
+            ACK => Ok(()),
+            NACK => Err(anyhow!("bootloader responded with NACK")),
+            b => Err(anyhow!("unexpected response from bootloader: {:#04x}", b)),
+        }
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |c, b| c ^ b)
+}
+
+impl<T> FirmwareDevice for Stm32Board<T>
+where
+    T: Read + Write,
+    T::Error: core::fmt::Debug,
+{
+    const MTU: usize = MAX_WRITE;
+    type Version = Vec<u8>;
+    type Error = anyhow::Error;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            self.sync().await?;
+            Ok(FirmwareStatus {
+                current_version: self.version.clone(),
+                next_offset: 0,
+                next_version: None,
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, _: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            log::info!("Erasing flash");
+            self.mass_erase().await
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            let mut address = self.address + offset;
+            for chunk in data.chunks(MAX_WRITE) {
+                self.write_memory(address, chunk).await?;
+                address += chunk.len() as u32;
+            }
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], _: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            log::info!("Starting firmware at {:#010x}", self.address);
+            self.go(self.address).await?;
+            self.version = version.to_vec();
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move { Ok(()) }
+    }
+}
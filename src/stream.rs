@@ -0,0 +1,148 @@
+use crate::ChecksumAlgorithm;
+use core::future::Future;
+use embedded_update::{Command, Status, UpdateService};
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Block size used if the device does not report its MTU.
+const DEFAULT_BLOCK_SIZE: usize = 128;
+
+/// Images up to this size are copied into memory rather than mapped.
+const MAP_THRESHOLD: u64 = 16 * 1024 * 1024;
+
+/// The firmware, copied into memory or mapped.
+enum Contents {
+    Copied(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl Contents {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Self::Copied(data) => data,
+            Self::Mapped(map) => map,
+        }
+    }
+}
+
+/// An update service serving a binary firmware image from a file. Images larger than 16 MiB
+/// are memory-mapped, slicing the blocks requested by the device directly from the map, so
+/// that they are never copied into memory. Firmware is swapped with its SHA-256 digest as the
+/// checksum, unless another checksum algorithm is selected.
+///
+/// A mapped file must not be modified or truncated while it is served, as reading a truncated
+/// map raises SIGBUS. Changes to its size or modification time are detected before swapping,
+/// failing the update.
+pub struct FileService {
+    contents: Contents,
+    path: PathBuf,
+    /// Size and modification time of the file when it was mapped.
+    stamp: (u64, Option<SystemTime>),
+    version: Vec<u8>,
+    checksum: Vec<u8>,
+}
+
+impl FileService {
+    /// Serve the firmware in the file as the given version. The file is read once to compute
+    /// its checksum.
+    pub fn open(path: &Path, version: &[u8]) -> std::io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let metadata = file.metadata()?;
+        let contents = if metadata.len() <= MAP_THRESHOLD {
+            Contents::Copied(std::fs::read(path)?)
+        } else {
+            // SAFETY: the map is only sound while no one modifies or truncates the file, which
+            // cannot be prevented: a truncated map raises SIGBUS when read, and modified data
+            // changes behind the slices handed out. Callers are required not to modify the
+            // file, as documented, and modifications are detected before swapping.
+            Contents::Mapped(unsafe { Mmap::map(&file)? })
+        };
+        Ok(Self {
+            checksum: Sha256::digest(contents.bytes()).to_vec(),
+            contents,
+            path: path.to_path_buf(),
+            stamp: (metadata.len(), metadata.modified().ok()),
+            version: version.to_vec(),
+        })
+    }
+
+    /// Fail if the mapped file was modified since it was mapped.
+    fn check_unmodified(&self) -> std::io::Result<()> {
+        if let Contents::Mapped(_) = self.contents {
+            let metadata = std::fs::metadata(&self.path)?;
+            if (metadata.len(), metadata.modified().ok()) != self.stamp {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("{} was modified while it was served", self.path.display()),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Swap the firmware with a checksum of the algorithm, reading the file again to compute it.
+    pub fn with_checksum_algorithm(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        if !algorithm.is_default() {
+            self.checksum = algorithm.digest(self.contents.bytes());
+        }
+        self
+    }
+
+    /// The firmware served.
+    pub fn data(&self) -> &[u8] {
+        self.contents.bytes()
+    }
+
+    /// Size of the firmware in bytes.
+    pub fn size(&self) -> usize {
+        self.contents.bytes().len()
+    }
+
+    /// Checksum of the firmware.
+    pub fn checksum(&self) -> &[u8] {
+        &self.checksum
+    }
+}
+
+impl UpdateService for FileService {
+    type Error = std::io::Error;
+
+    type RequestFuture<'m> = impl Future<Output = Result<Command<'m>, Self::Error>> + 'm where Self: 'm;
+    fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Self::RequestFuture<'m> {
+        async move {
+            if self.version == status.version.as_ref() {
+                return Ok(Command::new_sync(
+                    &self.version,
+                    None,
+                    status.correlation_id,
+                ));
+            }
+
+            let offset = match &status.update {
+                Some(update) if update.version.as_ref() == self.version => update.offset,
+                _ => 0,
+            };
+            let data = self.contents.bytes();
+            if offset as usize >= data.len() {
+                self.check_unmodified()?;
+                Ok(Command::new_swap(
+                    &self.version,
+                    &self.checksum,
+                    status.correlation_id,
+                ))
+            } else {
+                let mtu = status.mtu.map(|m| m as usize).unwrap_or(DEFAULT_BLOCK_SIZE);
+                let start = offset as usize;
+                let len = core::cmp::min(mtu, data.len() - start);
+                Ok(Command::new_write(
+                    &self.version,
+                    offset,
+                    &data[start..start + len],
+                    status.correlation_id,
+                ))
+            }
+        }
+    }
+}
@@ -0,0 +1,475 @@
+//! Fakes for writing protocol regression tests against the update protocol without hardware
+//! or Drogue Cloud.
+//!
+//! A [`FakeDevice`] records every call made by the updater and a [`FakeUpdateService`] serves
+//! firmware from memory while recording the status reports it receives. [`run_update`] drives
+//! a full update between the two, after which the [`Recording`] can be checked:
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use drgdfu::testing::*;
+//!
+//! let firmware = vec![1; 1000];
+//! let mut device = FakeDevice::new(b"0.9");
+//! let recording = device.recording();
+//! run_update(FakeUpdateService::new(b"1.0", &firmware), &mut device).await?;
+//! recording.assert_received(&firmware);
+//! recording.assert_updated_to(b"1.0");
+//! # Ok(())
+//! # }
+//! ```
+use crate::{run_updater, RetryPolicy};
+use core::future::Future;
+use embedded_update::*;
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// A call made to a FakeDevice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Status,
+    Start { version: Vec<u8> },
+    Write { offset: u32, data: Vec<u8> },
+    Update { version: Vec<u8>, checksum: Vec<u8> },
+    Synced,
+}
+
+/// The calls made to a FakeDevice, shared with the device so that it can be inspected after
+/// the device has been handed to the updater.
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    events: Arc<Mutex<Vec<DeviceEvent>>>,
+}
+
+impl Recording {
+    fn push(&self, event: DeviceEvent) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// Return the calls made so far, in order.
+    pub fn events(&self) -> Vec<DeviceEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// Return the offset and length of each write, in order.
+    pub fn writes(&self) -> Vec<(u32, usize)> {
+        self.events()
+            .iter()
+            .filter_map(|e| match e {
+                DeviceEvent::Write { offset, data } => Some((*offset, data.len())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Return the firmware written since the last update was started, with each write applied
+    /// at its offset.
+    pub fn received(&self) -> Vec<u8> {
+        let mut firmware = Vec::new();
+        for event in self.events() {
+            match event {
+                DeviceEvent::Start { .. } => firmware.clear(),
+                DeviceEvent::Write { offset, data } => {
+                    let start = offset as usize;
+                    if firmware.len() < start + data.len() {
+                        firmware.resize(start + data.len(), 0);
+                    }
+                    firmware[start..start + data.len()].copy_from_slice(&data);
+                }
+                _ => {}
+            }
+        }
+        firmware
+    }
+
+    /// Return the versions the device was asked to swap to, in order.
+    pub fn updates(&self) -> Vec<Vec<u8>> {
+        self.events()
+            .into_iter()
+            .filter_map(|e| match e {
+                DeviceEvent::Update { version, .. } => Some(version),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Panic unless the received firmware equals `expected`, naming the first differing offset.
+    pub fn assert_received(&self, expected: &[u8]) {
+        let actual = self.received();
+        if let Some(offset) = actual.iter().zip(expected).position(|(a, e)| a != e) {
+            panic!(
+                "firmware mismatch at offset {}: expected {:#04x} but received {:#04x}",
+                offset, expected[offset], actual[offset]
+            );
+        }
+        assert_eq!(
+            actual.len(),
+            expected.len(),
+            "received {} bytes of firmware but expected {}",
+            actual.len(),
+            expected.len()
+        );
+    }
+
+    /// Panic unless the device was swapped exactly once, to the given version.
+    pub fn assert_updated_to(&self, version: &[u8]) {
+        assert_eq!(
+            self.updates(),
+            vec![version.to_vec()],
+            "device was not updated exactly once to {}",
+            String::from_utf8_lossy(version)
+        );
+    }
+
+    /// Panic if the device was swapped.
+    pub fn assert_not_updated(&self) {
+        let updates = self.updates();
+        assert!(
+            updates.is_empty(),
+            "device was updated {} times",
+            updates.len()
+        );
+    }
+}
+
+/// Error injected into a FakeDevice.
+#[derive(Debug)]
+pub struct InjectedError {
+    pub offset: u32,
+}
+
+impl core::fmt::Display for InjectedError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "injected write error at offset {}", self.offset)
+    }
+}
+
+impl std::error::Error for InjectedError {}
+
+/// An in-process device accepting any firmware, recording every call made to it.
+pub struct FakeDevice {
+    version: Vec<u8>,
+    next_version: Option<Vec<u8>>,
+    next_offset: u32,
+    failures: Vec<u32>,
+    recording: Recording,
+}
+
+impl FakeDevice {
+    pub fn new(version: &[u8]) -> Self {
+        Self {
+            version: version.to_vec(),
+            next_version: None,
+            next_offset: 0,
+            failures: Vec::new(),
+            recording: Recording::default(),
+        }
+    }
+
+    /// Fail the first write at the given offset, to test how errors are recovered from.
+    pub fn with_failure_at(mut self, offset: u32) -> Self {
+        self.failures.push(offset);
+        self
+    }
+
+    /// Return the recording of the calls made to this device.
+    pub fn recording(&self) -> Recording {
+        self.recording.clone()
+    }
+
+    /// Return the current version of the device.
+    pub fn version(&self) -> &[u8] {
+        &self.version
+    }
+}
+
+impl FirmwareDevice for FakeDevice {
+    const MTU: usize = 256;
+    type Version = Vec<u8>;
+    type Error = InjectedError;
+
+    type StatusFuture<'m> = impl Future<Output = Result<FirmwareStatus<Self::Version>, Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn status(&mut self) -> Self::StatusFuture<'_> {
+        async move {
+            self.recording.push(DeviceEvent::Status);
+            Ok(FirmwareStatus {
+                current_version: self.version.clone(),
+                next_offset: self.next_offset,
+                next_version: self.next_version.clone(),
+            })
+        }
+    }
+
+    type StartFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn start<'m>(&'m mut self, version: &'m [u8]) -> Self::StartFuture<'m> {
+        async move {
+            self.recording.push(DeviceEvent::Start {
+                version: version.to_vec(),
+            });
+            self.next_version.replace(version.to_vec());
+            self.next_offset = 0;
+            Ok(())
+        }
+    }
+
+    type WriteFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn write<'m>(&'m mut self, offset: u32, data: &'m [u8]) -> Self::WriteFuture<'m> {
+        async move {
+            if let Some(i) = self.failures.iter().position(|o| *o == offset) {
+                self.failures.remove(i);
+                return Err(InjectedError { offset });
+            }
+            self.recording.push(DeviceEvent::Write {
+                offset,
+                data: data.to_vec(),
+            });
+            self.next_offset = offset + data.len() as u32;
+            Ok(())
+        }
+    }
+
+    type UpdateFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn update<'m>(&'m mut self, version: &'m [u8], checksum: &'m [u8]) -> Self::UpdateFuture<'m> {
+        async move {
+            self.recording.push(DeviceEvent::Update {
+                version: version.to_vec(),
+                checksum: checksum.to_vec(),
+            });
+            self.version = version.to_vec();
+            self.next_version = None;
+            self.next_offset = 0;
+            Ok(())
+        }
+    }
+
+    type SyncedFuture<'m> = impl Future<Output = Result<(), Self::Error>> + 'm
+    where
+        Self: 'm;
+
+    fn synced(&mut self) -> Self::SyncedFuture<'_> {
+        async move {
+            self.recording.push(DeviceEvent::Synced);
+            Ok(())
+        }
+    }
+}
+
+/// A status report received by a FakeUpdateService.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedStatus {
+    pub version: Vec<u8>,
+    pub mtu: Option<u32>,
+    /// Version being written and the next offset, if an update is in progress.
+    pub update: Option<(Vec<u8>, u32)>,
+}
+
+/// An in-process update service serving firmware from memory, recording the status reports it
+/// receives. Firmware is swapped with its SHA-256 digest as the checksum.
+pub struct FakeUpdateService {
+    version: Vec<u8>,
+    firmware: Vec<u8>,
+    checksum: Vec<u8>,
+    mtu: Option<usize>,
+    waits: usize,
+    statuses: Arc<Mutex<Vec<ReceivedStatus>>>,
+}
+
+impl FakeUpdateService {
+    pub fn new(version: &[u8], firmware: &[u8]) -> Self {
+        Self {
+            version: version.to_vec(),
+            firmware: firmware.to_vec(),
+            checksum: Sha256::digest(firmware).to_vec(),
+            mtu: None,
+            waits: 0,
+            statuses: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Send blocks of at most `mtu` bytes, even if the device supports larger blocks.
+    pub fn with_mtu(mut self, mtu: usize) -> Self {
+        self.mtu.replace(mtu);
+        self
+    }
+
+    /// Tell the device to wait for the first `waits` status reports.
+    pub fn with_waits(mut self, waits: usize) -> Self {
+        self.waits = waits;
+        self
+    }
+
+    /// Return a handle to the status reports received so far, which stays valid after the
+    /// service has been handed to the updater.
+    pub fn statuses(&self) -> Arc<Mutex<Vec<ReceivedStatus>>> {
+        self.statuses.clone()
+    }
+
+    fn block(&self, status: &Status<'_>, offset: u32) -> (u32, usize) {
+        let mtu = status.mtu.map(|m| m as usize).unwrap_or(128);
+        let mtu = self.mtu.map(|m| m.min(mtu)).unwrap_or(mtu);
+        let len = core::cmp::min(mtu, self.firmware.len() - offset as usize);
+        (offset, len)
+    }
+}
+
+impl UpdateService for FakeUpdateService {
+    type Error = Infallible;
+
+    type RequestFuture<'m> = impl Future<Output = Result<Command<'m>, Self::Error>> + 'm where Self: 'm;
+    fn request<'m>(&'m mut self, status: &'m Status<'m>) -> Self::RequestFuture<'m> {
+        async move {
+            self.statuses.lock().unwrap().push(ReceivedStatus {
+                version: status.version.to_vec(),
+                mtu: status.mtu,
+                update: status
+                    .update
+                    .as_ref()
+                    .map(|u| (u.version.to_vec(), u.offset)),
+            });
+
+            if self.waits > 0 {
+                self.waits -= 1;
+                return Ok(Command::new_wait(Some(0), status.correlation_id));
+            }
+
+            if self.version == status.version.as_ref() {
+                return Ok(Command::new_sync(
+                    &self.version,
+                    None,
+                    status.correlation_id,
+                ));
+            }
+
+            let offset = match &status.update {
+                Some(update) if update.version.as_ref() == self.version => update.offset,
+                _ => 0,
+            };
+            if offset as usize >= self.firmware.len() {
+                Ok(Command::new_swap(
+                    &self.version,
+                    &self.checksum,
+                    status.correlation_id,
+                ))
+            } else {
+                let (offset, len) = self.block(status, offset);
+                let start = offset as usize;
+                Ok(Command::new_write(
+                    &self.version,
+                    offset,
+                    &self.firmware[start..start + len],
+                    status.correlation_id,
+                ))
+            }
+        }
+    }
+}
+
+/// Drive a full update of the device from the service, until the device reports that it is in
+/// sync. The device is expected to run the new version after swapping firmware, like a
+/// FakeDevice does. Failed attempts are retried up to three times.
+pub async fn run_update<S, F>(service: S, device: &mut F) -> anyhow::Result<()>
+where
+    S: UpdateService,
+    S::Error: core::fmt::Debug,
+    F: FirmwareDevice,
+    F::Error: core::fmt::Debug,
+{
+    let mut updater = FirmwareUpdater::new(
+        service,
+        UpdaterConfig {
+            timeout_ms: 1_000,
+            backoff_ms: 0,
+        },
+    );
+    let retry = RetryPolicy::fixed(Duration::from_millis(0)).with_max_attempts(3);
+    run_updater(&mut updater, device, &retry).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firmware() -> Vec<u8> {
+        (0..1000).map(|i| i as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn update_round_trip() {
+        let firmware = firmware();
+        let mut device = FakeDevice::new(b"0.9");
+        let recording = device.recording();
+        let service = FakeUpdateService::new(b"1.0", &firmware).with_mtu(100);
+        let statuses = service.statuses();
+        run_update(service, &mut device).await.unwrap();
+
+        recording.assert_received(&firmware);
+        recording.assert_updated_to(b"1.0");
+        assert!(recording.writes().iter().all(|(_, len)| *len <= 100));
+        assert_eq!(recording.events().last(), Some(&DeviceEvent::Synced));
+        assert_eq!(device.version(), b"1.0");
+        assert_eq!(statuses.lock().unwrap()[0].version, b"0.9");
+    }
+
+    #[tokio::test]
+    async fn recover_from_write_failure() {
+        let firmware = firmware();
+        let mut device = FakeDevice::new(b"0.9").with_failure_at(512);
+        let recording = device.recording();
+        run_update(FakeUpdateService::new(b"1.0", &firmware), &mut device)
+            .await
+            .unwrap();
+
+        // The transfer continues where it failed instead of starting over
+        recording.assert_received(&firmware);
+        recording.assert_updated_to(b"1.0");
+        assert_eq!(
+            recording.writes().iter().filter(|(o, _)| *o == 0).count(),
+            1
+        );
+        assert!(recording.writes().iter().any(|(o, _)| *o == 512));
+    }
+
+    #[tokio::test]
+    async fn wait_before_update() {
+        let firmware = firmware();
+        let mut device = FakeDevice::new(b"0.9");
+        let recording = device.recording();
+        let service = FakeUpdateService::new(b"1.0", &firmware).with_waits(2);
+        let statuses = service.statuses();
+        run_update(service, &mut device).await.unwrap();
+
+        recording.assert_received(&firmware);
+        recording.assert_updated_to(b"1.0");
+        let statuses = statuses.lock().unwrap();
+        assert!(statuses.len() > 2);
+        assert!(statuses[..2].iter().all(|s| s.update.is_none()));
+    }
+
+    #[tokio::test]
+    async fn already_synced() {
+        let firmware = firmware();
+        let mut device = FakeDevice::new(b"1.0");
+        let recording = device.recording();
+        run_update(FakeUpdateService::new(b"1.0", &firmware), &mut device)
+            .await
+            .unwrap();
+
+        recording.assert_not_updated();
+        assert!(recording.writes().is_empty());
+        assert_eq!(recording.events().last(), Some(&DeviceEvent::Synced));
+    }
+}
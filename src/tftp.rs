@@ -0,0 +1,306 @@
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::{timeout, Duration};
+
+/// Well known port of TFTP servers.
+pub const TFTP_PORT: u16 = 69;
+
+const OP_RRQ: u16 = 1;
+const OP_WRQ: u16 = 2;
+const OP_DATA: u16 = 3;
+const OP_ACK: u16 = 4;
+const OP_ERROR: u16 = 5;
+const OP_OACK: u16 = 6;
+
+const ERROR_NOT_FOUND: u16 = 1;
+const ERROR_ACCESS: u16 = 2;
+const ERROR_ILLEGAL: u16 = 4;
+
+/// Block size of RFC 1350, used unless the client asks for another with the `blksize` option.
+const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Largest block size accepted, fitting into an Ethernet frame.
+const MAX_BLOCK_SIZE: usize = 1468;
+
+/// Number of times a packet is sent before giving up on a client.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// A MAC address of a device, such as `00:11:22:aa:bb:cc`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MacAddress([u8; 6]);
+
+impl core::str::FromStr for MacAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let octets: Vec<&str> = s.split(|c| c == ':' || c == '-').collect();
+        let mut mac = [0; 6];
+        if octets.len() != mac.len() {
+            return Err(anyhow!("invalid MAC address '{}'", s));
+        }
+        for (octet, hex) in mac.iter_mut().zip(octets) {
+            *octet =
+                u8::from_str_radix(hex, 16).map_err(|_| anyhow!("invalid MAC address '{}'", s))?;
+        }
+        Ok(Self(mac))
+    }
+}
+
+impl core::fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let m = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            m[0], m[1], m[2], m[3], m[4], m[5]
+        )
+    }
+}
+
+/// A read-only TFTP server, serving firmware images to bootloaders which pull them over the
+/// network, such as those configured through BOOTP or DHCP.
+///
+/// Devices with an image of their own are identified by the MAC address in the requested
+/// file name, like the `01-00-11-22-aa-bb-cc` names of PXELINUX, or else by the ARP table of
+/// the host on Linux. Other devices are served the default image, whatever the file name.
+/// Files are read when requested, so that images can be replaced while serving.
+///
+/// The `blksize` and `tsize` options of RFC 2348 and RFC 2349 are supported.
+#[derive(Debug, Clone, Default)]
+pub struct TftpServer {
+    firmware: Option<PathBuf>,
+    devices: HashMap<MacAddress, PathBuf>,
+    timeout: Option<Duration>,
+}
+
+impl TftpServer {
+    /// Serve the image in the file to devices without an image of their own.
+    pub fn new(firmware: Option<PathBuf>) -> Self {
+        Self {
+            firmware,
+            devices: HashMap::new(),
+            timeout: None,
+        }
+    }
+
+    /// Serve the image in the file to the device with the MAC address.
+    pub fn with_device(mut self, mac: MacAddress, firmware: PathBuf) -> Self {
+        self.devices.insert(mac, firmware);
+        self
+    }
+
+    /// Wait for acknowledgements for the given time before sending a block again.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout.replace(timeout);
+        self
+    }
+
+    /// Serve requests on the address until an error occurs, each transfer being handled by
+    /// its own task.
+    pub async fn run(self, listen: SocketAddr) -> anyhow::Result<()> {
+        let socket = UdpSocket::bind(listen).await?;
+        log::info!("Serving TFTP on {}", listen);
+        let server = Arc::new(self);
+        let mut buf = [0; 1024];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await?;
+            let request = buf[..len].to_vec();
+            let server = server.clone();
+            let local = SocketAddr::new(socket.local_addr()?.ip(), 0);
+            tokio::spawn(async move {
+                if let Err(e) = server.handle(local, peer, &request).await {
+                    log::warn!("Transfer to {} failed: {:?}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Handle a request from a new transfer id, replying from a port of its own.
+    async fn handle(
+        &self,
+        local: SocketAddr,
+        peer: SocketAddr,
+        request: &[u8],
+    ) -> anyhow::Result<()> {
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(peer).await?;
+
+        if request.len() < 2 {
+            return Err(anyhow!("truncated request"));
+        }
+        match u16::from_be_bytes([request[0], request[1]]) {
+            OP_RRQ => {}
+            OP_WRQ => {
+                send_error(&socket, ERROR_ACCESS, "server is read-only").await?;
+                return Ok(());
+            }
+            _ => {
+                send_error(&socket, ERROR_ILLEGAL, "illegal operation").await?;
+                return Ok(());
+            }
+        }
+
+        // Filename, mode and option pairs, each terminated by a zero
+        let mut fields = request[2..]
+            .split(|b| *b == 0)
+            .map(|f| String::from_utf8_lossy(f).to_string());
+        let filename = fields.next().unwrap_or_default();
+        let mode = fields.next().unwrap_or_default();
+        let mut options = HashMap::new();
+        while let (Some(name), Some(value)) = (fields.next(), fields.next()) {
+            options.insert(name.to_lowercase(), value);
+        }
+        log::info!("{} requested '{}' ({})", peer, filename, mode);
+        if !mode.eq_ignore_ascii_case("octet") {
+            send_error(&socket, ERROR_ILLEGAL, "only octet mode is supported").await?;
+            return Ok(());
+        }
+
+        let path = match self.select(&filename, peer.ip()) {
+            Some(path) => path,
+            None => {
+                send_error(&socket, ERROR_NOT_FOUND, "file not found").await?;
+                return Ok(());
+            }
+        };
+        let image = match tokio::fs::read(path).await {
+            Ok(image) => image,
+            Err(e) => {
+                send_error(&socket, ERROR_NOT_FOUND, "file not found").await?;
+                return Err(anyhow!("unable to read {}: {}", path.display(), e));
+            }
+        };
+
+        let mut block_size = DEFAULT_BLOCK_SIZE;
+        let mut oack = Vec::new();
+        if let Some(size) = options.get("blksize").and_then(|s| s.parse::<usize>().ok()) {
+            block_size = size.clamp(8, MAX_BLOCK_SIZE);
+            append_option(&mut oack, "blksize", &block_size.to_string());
+        }
+        if options.contains_key("tsize") {
+            append_option(&mut oack, "tsize", &image.len().to_string());
+        }
+        if !oack.is_empty() {
+            let mut packet = OP_OACK.to_be_bytes().to_vec();
+            packet.extend_from_slice(&oack);
+            self.transfer(&socket, &packet, 0).await?;
+        }
+
+        log::info!(
+            "Sending {} ({} bytes) to {}",
+            path.display(),
+            image.len(),
+            peer
+        );
+        // A final block shorter than the block size ends the transfer, which is empty if
+        // the image is a multiple of the block size
+        let blocks = image.len() / block_size + 1;
+        for i in 0..blocks {
+            let start = i * block_size;
+            let end = core::cmp::min(start + block_size, image.len());
+            let block = (i + 1) as u16;
+            let mut packet = OP_DATA.to_be_bytes().to_vec();
+            packet.extend_from_slice(&block.to_be_bytes());
+            packet.extend_from_slice(&image[start..end]);
+            self.transfer(&socket, &packet, block).await?;
+        }
+        log::info!("Sent {} to {}", path.display(), peer);
+        Ok(())
+    }
+
+    /// Send the packet until the client acknowledges the block.
+    async fn transfer(&self, socket: &UdpSocket, packet: &[u8], block: u16) -> anyhow::Result<()> {
+        let wait = self.timeout.unwrap_or(Duration::from_secs(1));
+        let mut buf = [0; 512];
+        for _ in 0..MAX_ATTEMPTS {
+            socket.send(packet).await?;
+            let ack = timeout(wait, async {
+                loop {
+                    let len = socket.recv(&mut buf).await?;
+                    if len < 4 {
+                        continue;
+                    }
+                    let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+                    let number = u16::from_be_bytes([buf[2], buf[3]]);
+                    match opcode {
+                        // Duplicate acknowledgements of earlier blocks are ignored
+                        OP_ACK if number == block => return Ok(()),
+                        OP_ERROR => {
+                            let message = String::from_utf8_lossy(&buf[4..len]);
+                            return Err(anyhow!(
+                                "client aborted transfer: {}",
+                                message.trim_end_matches('\0')
+                            ));
+                        }
+                        _ => {}
+                    }
+                }
+            })
+            .await;
+            if let Ok(ack) = ack {
+                return ack;
+            }
+        }
+        Err(anyhow!(
+            "block {} not acknowledged after {} attempts",
+            block,
+            MAX_ATTEMPTS
+        ))
+    }
+
+    /// The image for a request, by the MAC address of the device if it has one of its own.
+    fn select(&self, filename: &str, ip: IpAddr) -> Option<&PathBuf> {
+        let device = mac_in_filename(filename)
+            .and_then(|mac| self.devices.get(&mac))
+            .or_else(|| arp_lookup(ip).and_then(|mac| self.devices.get(&mac)));
+        device.or(self.firmware.as_ref())
+    }
+}
+
+/// Find a MAC address in a file name, such as `01-00-11-22-aa-bb-cc` or `00:11:22:aa:bb:cc.bin`.
+fn mac_in_filename(filename: &str) -> Option<MacAddress> {
+    let name = filename.rsplit('/').next()?;
+    let name = name.split('.').next()?;
+    // PXELINUX prefixes the ARP hardware type
+    let name = name.strip_prefix("01-").unwrap_or(name);
+    name.parse().ok()
+}
+
+/// The MAC address of a host in the ARP table.
+#[cfg(target_os = "linux")]
+fn arp_lookup(ip: IpAddr) -> Option<MacAddress> {
+    let table = std::fs::read_to_string("/proc/net/arp").ok()?;
+    let ip = ip.to_string();
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [address, _, _, mac, ..] if *address == ip => mac.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn arp_lookup(_: IpAddr) -> Option<MacAddress> {
+    None
+}
+
+fn append_option(packet: &mut Vec<u8>, name: &str, value: &str) {
+    packet.extend_from_slice(name.as_bytes());
+    packet.push(0);
+    packet.extend_from_slice(value.as_bytes());
+    packet.push(0);
+}
+
+async fn send_error(socket: &UdpSocket, code: u16, message: &str) -> anyhow::Result<()> {
+    let mut packet = OP_ERROR.to_be_bytes().to_vec();
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    socket.send(&packet).await?;
+    Ok(())
+}
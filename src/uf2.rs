@@ -0,0 +1,87 @@
+const UF2_MAGIC_START0: u32 = 0x0A32_4655;
+const UF2_MAGIC_START1: u32 = 0x9E5D_5157;
+const UF2_MAGIC_END: u32 = 0x0AB1_6F30;
+const UF2_FLAG_FAMILY_ID: u32 = 0x0000_2000;
+
+const UF2_BLOCK_SIZE: usize = 512;
+const UF2_DATA_SIZE: usize = 476;
+/// Payload per block, as required by most bootloaders including the RP2040 one.
+const UF2_PAYLOAD_SIZE: usize = 256;
+
+/// UF2 family id of the RP2040.
+pub const UF2_FAMILY_RP2040: u32 = 0xE48B_FF56;
+/// Start of the flash of the RP2040, where images are written by default.
+pub const RP2040_FLASH_ADDRESS: u32 = 0x1000_0000;
+
+/// Creates images for bootloaders using the USB Flashing Format, which are updated by copying
+/// the image to the mass storage device they expose.
+pub struct Uf2Image {
+    family: u32,
+    base_address: u32,
+}
+
+impl Uf2Image {
+    /// Create images for the given family id, such as `UF2_FAMILY_RP2040`. Images are written
+    /// to the start of flash for known families, and to address 0 otherwise.
+    pub fn new(family: u32) -> Self {
+        let base_address = match family {
+            UF2_FAMILY_RP2040 => RP2040_FLASH_ADDRESS,
+            _ => 0,
+        };
+        Self {
+            family,
+            base_address,
+        }
+    }
+
+    /// Address of the first byte of the firmware on the device.
+    pub fn with_base_address(mut self, base_address: u32) -> Self {
+        self.base_address = base_address;
+        self
+    }
+
+    /// Split the firmware into UF2 blocks.
+    pub fn create(&self, firmware: &[u8]) -> Vec<u8> {
+        let blocks = (firmware.len() + UF2_PAYLOAD_SIZE - 1) / UF2_PAYLOAD_SIZE;
+        let mut image = Vec::with_capacity(blocks * UF2_BLOCK_SIZE);
+        for (i, chunk) in firmware.chunks(UF2_PAYLOAD_SIZE).enumerate() {
+            let address = self.base_address + (i * UF2_PAYLOAD_SIZE) as u32;
+            for word in [
+                UF2_MAGIC_START0,
+                UF2_MAGIC_START1,
+                UF2_FLAG_FAMILY_ID,
+                address,
+                UF2_PAYLOAD_SIZE as u32,
+                i as u32,
+                blocks as u32,
+                self.family,
+            ] {
+                image.extend_from_slice(&word.to_le_bytes());
+            }
+            let mut data = [0; UF2_DATA_SIZE];
+            data[..chunk.len()].copy_from_slice(chunk);
+            image.extend_from_slice(&data);
+            image.extend_from_slice(&UF2_MAGIC_END.to_le_bytes());
+        }
+        image
+    }
+}
+
+/// Returns true if the data starts with a UF2 block.
+pub fn is_uf2(data: &[u8]) -> bool {
+    read_word(data, 0) == Some(UF2_MAGIC_START0) && read_word(data, 4) == Some(UF2_MAGIC_START1)
+}
+
+/// The number of blocks of a UF2 image, and its family id if the first block has one.
+pub fn uf2_blocks(data: &[u8]) -> (usize, Option<u32>) {
+    let family = match read_word(data, 8) {
+        Some(flags) if flags & UF2_FLAG_FAMILY_ID != 0 => read_word(data, 28),
+        _ => None,
+    };
+    (data.len() / UF2_BLOCK_SIZE, family)
+}
+
+fn read_word(data: &[u8], offset: usize) -> Option<u32> {
+    let word = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+}
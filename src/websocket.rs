@@ -0,0 +1,129 @@
+use crate::RetryPolicy;
+use futures::StreamExt;
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// Channel of the events announcing a DFU command for a device.
+pub const DFU_CHANNEL: &str = "dfu";
+
+/// Waits for DFU commands on a Drogue Cloud event stream, such as the WebSocket integration
+/// of an application, so that a sync daemon can update devices as soon as new firmware is
+/// rolled out instead of polling.
+///
+/// Events are cloud events in JSON format. An event is a DFU command if its subject is the
+/// `dfu` channel, and if a device is given, its `device` attribute matches it.
+pub struct CommandStream {
+    url: String,
+    token: Option<String>,
+    device: Option<String>,
+    retry: RetryPolicy,
+    socket: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+}
+
+impl CommandStream {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            token: None,
+            device: None,
+            retry: RetryPolicy::exponential(Duration::from_secs(1), Duration::from_secs(60)),
+            socket: None,
+        }
+    }
+
+    /// Authenticate with the given access token.
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.token.replace(token.to_string());
+        self
+    }
+
+    /// Only react to commands for the given device.
+    pub fn with_device(mut self, device: &str) -> Self {
+        self.device.replace(device.to_string());
+        self
+    }
+
+    /// Reconnect according to the policy when the connection is lost.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    async fn connect(&mut self) -> anyhow::Result<()> {
+        let mut request = self.url.as_str().into_client_request()?;
+        if let Some(token) = &self.token {
+            request
+                .headers_mut()
+                .insert("Authorization", format!("Bearer {}", token).parse()?);
+        }
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        log::info!("Connected to command stream {}", self.url);
+        self.socket.replace(socket);
+        Ok(())
+    }
+
+    /// Wait for the next DFU command, reconnecting while the connection is lost. Fails once
+    /// the retry policy gives up.
+    pub async fn next(&mut self) -> anyhow::Result<()> {
+        let mut failures = 0;
+        loop {
+            if self.socket.is_none() {
+                if let Err(e) = self.connect().await {
+                    failures += 1;
+                    if !self.retry.retry(failures) {
+                        return Err(e.context("error connecting to command stream"));
+                    }
+                    log::warn!("Error connecting to command stream, retrying: {:?}", e);
+                    self.retry.wait(failures).await;
+                    continue;
+                }
+            }
+
+            let socket = self.socket.as_mut().unwrap();
+            let payload = match socket.next().await {
+                Some(Ok(Message::Text(text))) => text.into_bytes(),
+                Some(Ok(Message::Binary(data))) => data,
+                // Pings are answered by the socket
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => continue,
+                Some(Ok(Message::Close(_))) | None => {
+                    log::warn!("Command stream closed, reconnecting");
+                    self.socket = None;
+                    failures += 1;
+                    self.retry.wait(failures).await;
+                    continue;
+                }
+                Some(Err(e)) => {
+                    log::warn!("Error reading command stream, reconnecting: {:?}", e);
+                    self.socket = None;
+                    failures += 1;
+                    self.retry.wait(failures).await;
+                    continue;
+                }
+            };
+            failures = 0;
+            if self.is_command(&payload) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn is_command(&self, payload: &[u8]) -> bool {
+        let event: serde_json::Value = match serde_json::from_slice(payload) {
+            Ok(event) => event,
+            Err(e) => {
+                log::debug!("Ignoring event which is not JSON: {:?}", e);
+                return false;
+            }
+        };
+        if event["subject"].as_str() != Some(DFU_CHANNEL) {
+            return false;
+        }
+        match &self.device {
+            Some(device) => event["device"].as_str() == Some(device.as_str()),
+            None => true,
+        }
+    }
+}